@@ -0,0 +1,111 @@
+extern crate wetransfer;
+
+use std::env;
+use std::io;
+use std::process;
+
+use wetransfer::secret::Secret;
+use wetransfer::sync::Client;
+use wetransfer::sync::builder::FileSource;
+use wetransfer::sync::policy::UploadPolicy;
+use wetransfer::sync::spool::SpoolOptions;
+
+const USAGE: &str = "Usage: wetransfer [-m MESSAGE] [-n NAME] FILE...
+
+Uploads FILEs as a new transfer and prints its URL. A FILE of `-` reads
+the standard input, uploaded as NAME (`stdin` by default).
+Key files, `.env` files and files holding credentials are refused.
+The app token is read from the APP_TOKEN environment variable.";
+
+#[derive(Debug, PartialEq)]
+struct Arguments {
+    message: String,
+    stdin_name: String,
+    files: Vec<String>,
+}
+
+fn parse(args: &[String]) -> Result<Arguments, String> {
+    let mut arguments = Arguments { message: String::new(), stdin_name: String::from("stdin"), files: Vec::new() };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-m" | "--message" => arguments.message = args.next().ok_or("missing MESSAGE")?.clone(),
+            "-n" | "--name" => arguments.stdin_name = args.next().ok_or("missing NAME")?.clone(),
+            "-h" | "--help" => return Err(String::new()),
+            _ => arguments.files.push(arg.clone()),
+        }
+    }
+    if arguments.files.is_empty() {
+        return Err(String::from("missing FILE"));
+    }
+    if arguments.files.iter().filter(|file| *file == "-").count() > 1 {
+        return Err(String::from("the standard input can only be read once"));
+    }
+    Ok(arguments)
+}
+
+fn run(arguments: Arguments) -> Result<String, String> {
+    let app_token = Secret::from_env("APP_TOKEN").map_err(|error| error.message)?;
+    let mut client = Client::new(app_token).map_err(|error| error.message)?;
+    client.set_upload_policy(UploadPolicy::recommended().on_finding(|finding| eprintln!("wetransfer: {}", finding)));
+    let mut builder = client.transfers.builder().message(arguments.message);
+    for file in arguments.files {
+        builder = if file == "-" {
+            let stdin = io::stdin();
+            let source = FileSource::from_reader(arguments.stdin_name.as_str(), stdin.lock(), &SpoolOptions::default())
+                .map_err(|error| error.message)?;
+            builder.source(source)
+        } else {
+            builder.file(file)
+        };
+    }
+    let transfer = builder.upload().map_err(|error| error.message)?;
+    Ok(transfer.url.unwrap_or(transfer.id))
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let arguments = match parse(&args) {
+        Ok(arguments) => arguments,
+        Err(error) => {
+            if !error.is_empty() {
+                eprintln!("wetransfer: {}", error);
+            }
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+    match run(arguments) {
+        Ok(url) => println!("{}", url),
+        Err(error) => {
+            eprintln!("wetransfer: {}", error);
+            process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn it_parses_arguments() {
+        let arguments = parse(&args(&["-m", "Backup", "-n", "backup.tar", "-", "notes.txt"])).unwrap();
+        assert_eq!(arguments, Arguments {
+            message: String::from("Backup"),
+            stdin_name: String::from("backup.tar"),
+            files: args(&["-", "notes.txt"]),
+        });
+    }
+
+    #[test]
+    fn it_rejects_invalid_arguments() {
+        assert_eq!(parse(&args(&["-m", "Backup"])), Err(String::from("missing FILE")));
+        assert_eq!(parse(&args(&["notes.txt", "-m"])), Err(String::from("missing MESSAGE")));
+        assert_eq!(parse(&args(&["-", "-"])), Err(String::from("the standard input can only be read once")));
+    }
+}
@@ -0,0 +1,195 @@
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use serde_json;
+use sha2::{Digest, Sha256};
+
+use responses::*;
+use fsutil::io_error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Decides whether a transfer can be reused when the new message differs.
+pub enum ReusePolicy {
+    /// Only reuse transfers sent with the very same message.
+    SameMessage,
+    /// Reuse any transfer holding the same contents.
+    AnyMessage,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+/// represents a transfer known to hold a given set of contents.
+pub struct DedupEntry {
+    /// SHA-256 of the sorted SHA-256 of every file.
+    pub key: String,
+    pub file_hashes: Vec<String>,
+    pub transfer: Transfer,
+}
+
+/// An opt-in cache, stored as a JSON file, remembering which transfer already
+/// holds identical contents so they are not uploaded again while it is alive.
+#[derive(Debug)]
+pub struct DedupCache {
+    path: PathBuf,
+    policy: ReusePolicy,
+    entries: Mutex<Vec<DedupEntry>>,
+}
+
+impl DedupCache {
+    /// Opens (or starts) the cache stored at `path`, dropping expired transfers.
+    pub fn open<P: AsRef<Path>>(path: P, policy: ReusePolicy) -> Result<DedupCache, WeTransferError> {
+        let path = path.as_ref().to_path_buf();
+        let entries: Vec<DedupEntry> = if path.exists() {
+            let contents = fs::read_to_string(&path).map_err(|error| io_error(&path, error))?;
+            serde_json::from_str(&contents).map_err(|error| WeTransferError {
+                status: 0,
+                message: format!("Corrupted dedup cache {}: {}", path.display(), error),
+                ..Default::default()
+            })?
+        } else {
+            Vec::new()
+        };
+        let now = Utc::now();
+        let entries = entries.into_iter().filter(|entry| is_alive(&entry.transfer, now)).collect();
+        Ok(DedupCache { path, policy, entries: Mutex::new(entries) })
+    }
+
+    pub fn policy(&self) -> ReusePolicy {
+        self.policy
+    }
+
+    /// Returns a transfer, not expired yet, holding the same contents as `paths`
+    /// and allowed by the policy to be reused for `message`.
+    pub fn lookup(&self, message: &str, paths: &[&str]) -> Result<Option<Transfer>, WeTransferError> {
+        let key = combine(&sorted_hashes(paths)?);
+        let now = Utc::now();
+        let entries = self.entries.lock().unwrap();
+        Ok(entries.iter()
+            .filter(|entry| entry.key == key && is_alive(&entry.transfer, now))
+            .find(|entry| self.policy == ReusePolicy::AnyMessage || entry.transfer.message == message)
+            .map(|entry| entry.transfer.clone()))
+    }
+
+    /// Remembers that `transfer` holds the contents of `paths`.
+    pub fn store(&self, paths: &[&str], transfer: &Transfer) -> Result<(), WeTransferError> {
+        let file_hashes = sorted_hashes(paths)?;
+        let key = combine(&file_hashes);
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|entry| entry.transfer.id != transfer.id);
+        entries.push(DedupEntry { key, file_hashes, transfer: transfer.clone() });
+        self.save(&entries)
+    }
+
+    /// Forgets a transfer, e.g. once it was deleted.
+    pub fn forget(&self, transfer_id: &str) -> Result<(), WeTransferError> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|entry| entry.transfer.id != transfer_id);
+        self.save(&entries)
+    }
+
+    fn save(&self, entries: &[DedupEntry]) -> Result<(), WeTransferError> {
+        let temp_path = self.path.with_extension("tmp");
+        fs::write(&temp_path, serde_json::to_string(entries).unwrap()).map_err(|error| io_error(&temp_path, error))?;
+        fs::rename(&temp_path, &self.path).map_err(|error| io_error(&self.path, error))
+    }
+}
+
+/// Hex-encoded SHA-256 of a file's contents.
+pub fn sha256_file<P: AsRef<Path>>(path: P) -> Result<String, WeTransferError> {
+    let path = path.as_ref();
+    let mut file = File::open(path).map_err(|error| io_error(path, error))?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer).map_err(|error| io_error(path, error))?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hex(&hasher.finalize()))
+}
+
+/// Hex-encodes bytes, lowercase.
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Sorted, so the key does not depend on the order of the files.
+fn sorted_hashes(paths: &[&str]) -> Result<Vec<String>, WeTransferError> {
+    let mut hashes = paths.iter().map(sha256_file).collect::<Result<Vec<String>, WeTransferError>>()?;
+    hashes.sort();
+    Ok(hashes)
+}
+
+fn combine(hashes: &[String]) -> String {
+    let mut hasher = Sha256::new();
+    for hash in hashes {
+        hasher.update(hash.as_bytes());
+        hasher.update(b"\n");
+    }
+    hex(&hasher.finalize())
+}
+
+// Transfers without a readable expiry date are never reused.
+fn is_alive(transfer: &Transfer, now: DateTime<Utc>) -> bool {
+    DateTime::parse_from_rfc3339(&transfer.expires_at)
+        .map(|expires_at| expires_at.with_timezone(&Utc) > now)
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+    use std::env;
+
+    fn cache(name: &str, policy: ReusePolicy) -> DedupCache {
+        let path = env::temp_dir().join(format!("wetransfer-dedup-{}.json", name));
+        let _ = fs::remove_file(&path);
+        DedupCache::open(path, policy).unwrap()
+    }
+
+    fn transfer(id: &str, message: &str, expires_at: DateTime<Utc>) -> Transfer {
+        Transfer {
+            id: id.to_string(),
+            message: message.to_string(),
+            url: Some(String::from("https://we.tl/t-12344657")),
+            expires_at: expires_at.to_rfc3339(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn it_hashes_files() {
+        let path = env::temp_dir().join("wetransfer-dedup-hash.txt");
+        fs::write(&path, "abc").unwrap();
+        assert_eq!(sha256_file(&path).unwrap(), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    }
+
+    #[test]
+    fn it_reuses_transfers_with_the_same_contents() {
+        let cache = cache("reuse", ReusePolicy::SameMessage);
+        let alive = transfer("t-alive", "Renders", Utc::now() + Duration::days(3));
+        cache.store(&["Cargo.toml", "README.md"], &alive).unwrap();
+
+        assert_eq!(cache.lookup("Renders", &["README.md", "Cargo.toml"]).unwrap(), Some(alive.clone()));
+        assert_eq!(cache.lookup("Other message", &["README.md", "Cargo.toml"]).unwrap(), None);
+        assert_eq!(cache.lookup("Renders", &["Cargo.toml"]).unwrap(), None);
+
+        let reopened = DedupCache::open(&cache.path, ReusePolicy::AnyMessage).unwrap();
+        assert_eq!(reopened.lookup("Other message", &["Cargo.toml", "README.md"]).unwrap(), Some(alive));
+        reopened.forget("t-alive").unwrap();
+        assert_eq!(reopened.lookup("Renders", &["Cargo.toml", "README.md"]).unwrap(), None);
+    }
+
+    #[test]
+    fn it_ignores_expired_transfers() {
+        let cache = cache("expired", ReusePolicy::AnyMessage);
+        cache.store(&["Cargo.toml"], &transfer("t-expired", "Renders", Utc::now() - Duration::days(1))).unwrap();
+        assert_eq!(cache.lookup("Renders", &["Cargo.toml"]).unwrap(), None);
+        assert!(DedupCache::open(&cache.path, ReusePolicy::AnyMessage).unwrap().entries.lock().unwrap().is_empty());
+    }
+}
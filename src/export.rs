@@ -0,0 +1,285 @@
+use serde_json;
+
+use responses::Board;
+
+/// Version of the JSON produced by `to_json`, bumped on incompatible changes.
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Markdown,
+    Html,
+    Json,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+/// represents a board as exported to JSON.
+pub struct BoardExport {
+    pub schema: u32,
+    pub id: String,
+    pub name: String,
+    pub description: Option<String>,
+    pub url: String,
+    pub links: Vec<ExportedLink>,
+    pub files: Vec<ExportedFile>,
+    /// sum of the sizes of every file, in bytes.
+    pub total_size: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExportedLink {
+    pub id: String,
+    /// the link's `Meta.title`, or its url when it has none.
+    pub title: String,
+    pub url: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExportedFile {
+    pub id: String,
+    pub name: String,
+    pub size: u64,
+    /// `size` in human-readable units, e.g. `1.5 MB`.
+    pub human_size: String,
+}
+
+impl BoardExport {
+    /// Collects the links and files of a board, each group sorted by title or name.
+    pub fn new(board: &Board) -> BoardExport {
+        let mut links: Vec<ExportedLink> = board.items.iter()
+            .filter(|item| item["type"] == "link")
+            .map(|item| {
+                let url = item["url"].as_str().unwrap_or_default().to_string();
+                let title = item["meta"]["title"].as_str().filter(|title| !title.is_empty()).map(String::from);
+                ExportedLink { id: text(&item["id"]), title: title.unwrap_or_else(|| url.clone()), url }
+            })
+            .collect();
+        links.sort_by_key(|link| (link.title.to_lowercase(), link.url.clone()));
+        let mut files: Vec<ExportedFile> = board.items.iter()
+            .filter(|item| item["type"] == "file")
+            .map(|item| {
+                let size = item["size"].as_u64().unwrap_or_default();
+                ExportedFile { id: text(&item["id"]), name: text(&item["name"]), size, human_size: human_size(size) }
+            })
+            .collect();
+        files.sort_by_key(|file| (file.name.to_lowercase(), file.id.clone()));
+        BoardExport {
+            schema: SCHEMA_VERSION,
+            id: board.id.clone(),
+            name: board.name.clone(),
+            description: board.description.clone().filter(|description| !description.is_empty()),
+            url: board.url.clone(),
+            total_size: files.iter().map(|file| file.size).sum(),
+            links,
+            files,
+        }
+    }
+}
+
+/// Renders a board in the given format.
+pub fn export(board: &Board, format: ExportFormat) -> String {
+    match format {
+        ExportFormat::Markdown => to_markdown(board),
+        ExportFormat::Html => to_html(board),
+        ExportFormat::Json => to_json(board),
+    }
+}
+
+pub fn to_markdown(board: &Board) -> String {
+    let export = BoardExport::new(board);
+    let mut output = format!("# {}\n\n", markdown_escape(&export.name));
+    if let Some(ref description) = export.description {
+        output.push_str(&format!("{}\n\n", markdown_escape(description)));
+    }
+    if is_web_url(&export.url) {
+        output.push_str(&format!("<{}>\n", markdown_destination(&export.url)));
+    }
+    if !export.links.is_empty() {
+        output.push_str(&format!("\n## Links ({})\n\n", export.links.len()));
+        for link in &export.links {
+            output.push_str(&format!("- {}\n", markdown_link(&link.title, &link.url)));
+        }
+    }
+    if !export.files.is_empty() {
+        output.push_str(&format!("\n## Files ({}, {})\n\n", export.files.len(), human_size(export.total_size)));
+        for file in &export.files {
+            output.push_str(&format!("- {} ({})\n", markdown_escape(&file.name), file.human_size));
+        }
+    }
+    output
+}
+
+/// A standalone page, without external stylesheets nor scripts. Only `http`
+/// and `https` urls become anchors; other urls are shown as plain text.
+pub fn to_html(board: &Board) -> String {
+    let export = BoardExport::new(board);
+    let mut body = format!("<h1>{}</h1>\n", html_escape(&export.name));
+    if let Some(ref description) = export.description {
+        body.push_str(&format!("<p>{}</p>\n", html_escape(description)));
+    }
+    body.push_str(&format!("<p>{}</p>\n", html_link(&export.url, &export.url)));
+    if !export.links.is_empty() {
+        body.push_str(&format!("<h2>Links ({})</h2>\n<ul>\n", export.links.len()));
+        for link in &export.links {
+            body.push_str(&format!("<li>{}</li>\n", html_link(&link.title, &link.url)));
+        }
+        body.push_str("</ul>\n");
+    }
+    if !export.files.is_empty() {
+        body.push_str(&format!("<h2>Files ({}, {})</h2>\n<table>\n<tr><th>Name</th><th>Size</th></tr>\n",
+                               export.files.len(), human_size(export.total_size)));
+        for file in &export.files {
+            body.push_str(&format!("<tr><td>{}</td><td>{}</td></tr>\n", html_escape(&file.name), file.human_size));
+        }
+        body.push_str("</table>\n");
+    }
+    format!("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n<style>\
+             body {{ font-family: sans-serif; max-width: 48em; margin: 2em auto; }} \
+             td, th {{ padding: 0.2em 1em 0.2em 0; text-align: left; }}</style>\n</head>\n<body>\n{}</body>\n</html>\n",
+            html_escape(&export.name), body)
+}
+
+/// Pretty-printed `BoardExport`.
+pub fn to_json(board: &Board) -> String {
+    serde_json::to_string_pretty(&BoardExport::new(board)).unwrap()
+}
+
+/// Formats a size with binary multiples, e.g. `1536` as `1.5 KB`.
+pub fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["KB", "MB", "GB", "TB", "PB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// A Markdown link to `url` titled `title`, or plain text when `url` is not an
+/// `http` or `https` url.
+pub(crate) fn markdown_link(title: &str, url: &str) -> String {
+    if is_web_url(url) {
+        format!("[{}]({})", markdown_escape(title), markdown_destination(url))
+    } else if title == url {
+        markdown_escape(url)
+    } else {
+        format!("{} ({})", markdown_escape(title), markdown_escape(url))
+    }
+}
+
+fn html_link(title: &str, url: &str) -> String {
+    if is_web_url(url) {
+        format!("<a href=\"{}\">{}</a>", html_escape(url), html_escape(title))
+    } else if title == url {
+        html_escape(url)
+    } else {
+        format!("{} ({})", html_escape(title), html_escape(url))
+    }
+}
+
+fn is_web_url(url: &str) -> bool {
+    let url = url.trim_start().to_ascii_lowercase();
+    url.starts_with("http://") || url.starts_with("https://")
+}
+
+// Percent-encodes what would end a link destination early.
+fn markdown_destination(url: &str) -> String {
+    url.chars().fold(String::new(), |mut escaped, c| {
+        match c {
+            ' ' => escaped.push_str("%20"),
+            '(' => escaped.push_str("%28"),
+            ')' => escaped.push_str("%29"),
+            '<' => escaped.push_str("%3C"),
+            '>' => escaped.push_str("%3E"),
+            c if c.is_whitespace() || c.is_control() => escaped.push_str(&format!("%{:02X}", c as u32)),
+            c => escaped.push(c),
+        }
+        escaped
+    })
+}
+
+fn text(value: &serde_json::Value) -> String {
+    value.as_str().unwrap_or_default().to_string()
+}
+
+fn markdown_escape(text: &str) -> String {
+    text.chars().fold(String::new(), |mut escaped, c| {
+        if "\\`*_[]<>#|".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+        escaped
+    })
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&#39;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn board() -> Board {
+        serde_json::from_str(r#"{"id": "board-id", "name": "Client <X>", "description": "Final deliverables", "state": "downloadable",
+            "url": "https://we.tl/b-random-hash", "items": [
+            {"id": "f2", "type": "file", "name": "video.mov", "size": 1572864},
+            {"id": "l2", "type": "link", "url": "https://wetransfer.com/", "meta": {"title": "WeTransfer"}},
+            {"id": "f1", "type": "file", "name": "brief.pdf", "size": 2048},
+            {"id": "l1", "type": "link", "url": "https://example.com/", "meta": {"title": ""}}]}"#).unwrap()
+    }
+
+    #[test]
+    fn it_formats_sizes() {
+        assert_eq!(human_size(0), "0 B");
+        assert_eq!(human_size(1023), "1023 B");
+        assert_eq!(human_size(1536), "1.5 KB");
+        assert_eq!(human_size(5 * 1024 * 1024 * 1024), "5.0 GB");
+    }
+
+    #[test]
+    fn it_exports_to_markdown() {
+        assert_eq!(to_markdown(&board()), "# Client \\<X\\>\n\nFinal deliverables\n\n<https://we.tl/b-random-hash>\n\
+            \n## Links (2)\n\n- [https://example.com/](https://example.com/)\n- [WeTransfer](https://wetransfer.com/)\n\
+            \n## Files (2, 1.5 MB)\n\n- brief.pdf (2.0 KB)\n- video.mov (1.5 MB)\n");
+    }
+
+    #[test]
+    fn it_exports_to_html() {
+        let html = export(&board(), ExportFormat::Html);
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.contains("<h1>Client &lt;X&gt;</h1>"));
+        assert!(html.contains("<tr><td>brief.pdf</td><td>2.0 KB</td></tr>\n<tr><td>video.mov</td><td>1.5 MB</td></tr>"));
+    }
+
+    #[test]
+    fn it_only_links_to_web_urls() {
+        let board: Board = serde_json::from_str(r#"{"id": "b", "name": "n", "description": "<img src=x> [Pay](https://evil.example)", "state": "downloadable", "url": "https://we.tl/b", "items": [
+            {"id": "l1", "type": "link", "url": "javascript:alert(1)", "meta": {"title": "Click"}},
+            {"id": "l2", "type": "link", "url": "https://example.com/a b)(c", "meta": {"title": "Spaced"}}]}"#).unwrap();
+        let html = to_html(&board);
+        assert!(html.contains("<li>Click (javascript:alert(1))</li>"));
+        assert!(!html.contains("href=\"javascript"));
+        assert!(html.contains("<li><a href=\"https://example.com/a b)(c\">Spaced</a></li>"));
+
+        let markdown = to_markdown(&board);
+        assert!(markdown.contains("\\<img src=x\\> \\[Pay\\](https://evil.example)\n"));
+        assert!(markdown.contains("- Click (javascript:alert(1))\n"));
+        assert!(markdown.contains("- [Spaced](https://example.com/a%20b%29%28c)\n"));
+    }
+
+    #[test]
+    fn it_exports_to_json() {
+        let export: BoardExport = serde_json::from_str(&to_json(&board())).unwrap();
+        assert_eq!(export.schema, SCHEMA_VERSION);
+        assert_eq!(export.links[1], ExportedLink {
+            id: String::from("l2"), title: String::from("WeTransfer"), url: String::from("https://wetransfer.com/"),
+        });
+        assert_eq!(export.files[0].name, "brief.pdf");
+        assert_eq!(export.total_size, 1574912);
+    }
+}
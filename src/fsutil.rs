@@ -0,0 +1,125 @@
+use std::collections::hash_map::RandomState;
+use std::env;
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[cfg(unix)]
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+
+use responses::WeTransferError;
+
+// How many names are tried before giving up on creating a temp file or directory.
+const MAX_ATTEMPTS: u32 = 16;
+
+static CREATED: AtomicUsize = AtomicUsize::new(0);
+
+/// A directory with an unpredictable name in the temp directory, only
+/// accessible to the current user. It is removed with its contents when dropped.
+#[derive(Debug)]
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    /// Creates `wetransfer-<label>-<random>` in the temp directory.
+    pub fn new(label: &str) -> Result<TempDir, WeTransferError> {
+        let mut attempts = 0;
+        loop {
+            let path = env::temp_dir().join(format!("wetransfer-{}-{}", label, random_name()));
+            let mut builder = DirBuilder::new();
+            #[cfg(unix)]
+            builder.mode(0o700);
+            match builder.create(&path) {
+                Ok(()) => return Ok(TempDir { path }),
+                Err(ref error) if error.kind() == io::ErrorKind::AlreadyExists && attempts < MAX_ATTEMPTS => attempts += 1,
+                Err(error) => return Err(io_error(&path, error)),
+            }
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Creates `wetransfer-<random>.<extension>` in the temp directory, only readable
+/// by the current user. An existing file or link is never opened instead.
+pub fn create_temp_file(extension: &str) -> Result<(PathBuf, File), WeTransferError> {
+    let mut attempts = 0;
+    loop {
+        let path = env::temp_dir().join(format!("wetransfer-{}.{}", random_name(), extension));
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        match options.open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(ref error) if error.kind() == io::ErrorKind::AlreadyExists && attempts < MAX_ATTEMPTS => attempts += 1,
+            Err(error) => return Err(io_error(&path, error)),
+        }
+    }
+}
+
+/// 16 hex digits, different for every call.
+pub fn random_name() -> String {
+    // `RandomState` is seeded by the operating system.
+    let mut hasher = RandomState::new().build_hasher();
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    hasher.write_u64(now.as_secs());
+    hasher.write_u32(now.subsec_nanos());
+    hasher.write_u32(process::id());
+    hasher.write_usize(CREATED.fetch_add(1, Ordering::SeqCst));
+    format!("{:016x}", hasher.finish())
+}
+
+pub fn io_error(path: &Path, error: io::Error) -> WeTransferError {
+    WeTransferError {
+        status: 0,
+        message: format!("Error while accessing {}: {}", path.display(), error),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_creates_private_temp_directories() {
+        let first = TempDir::new("test").unwrap();
+        let second = TempDir::new("test").unwrap();
+        assert_ne!(first.path(), second.path());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(first.path()).unwrap().permissions().mode() & 0o777, 0o700);
+        }
+        fs::write(first.path().join("copy.txt"), "copy").unwrap();
+        let path = first.path().to_path_buf();
+        drop(first);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn it_creates_private_temp_files() {
+        let (path, _) = create_temp_file("spool").unwrap();
+        assert!(path.to_string_lossy().ends_with(".spool"));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,420 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use chrono::{DateTime, Duration, Utc};
+use serde_json;
+
+use responses::*;
+use fsutil::io_error;
+use sync::transfer::TransferService;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    Transfer,
+    Board,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+/// represents a transfer or a board as recorded in the local history.
+pub struct HistoryEntry {
+    pub kind: EntryKind,
+    pub id: String,
+    /// the transfer message, or the board name.
+    pub message: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    pub state: String,
+    #[serde(default)]
+    pub url: Option<String>,
+    /// only known for transfers.
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    #[serde(default)]
+    pub files: Vec<HistoryFile>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryFile {
+    pub name: String,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+/// Filters for `History::query`. Unset fields match everything.
+pub struct HistoryQuery {
+    pub kind: Option<EntryKind>,
+    pub state: Option<String>,
+    pub created_after: Option<DateTime<Utc>>,
+    pub created_before: Option<DateTime<Utc>>,
+}
+
+impl HistoryQuery {
+    pub fn new() -> HistoryQuery {
+        HistoryQuery::default()
+    }
+
+    pub fn kind(mut self, kind: EntryKind) -> HistoryQuery {
+        self.kind = Some(kind);
+        self
+    }
+
+    pub fn state<S: Into<String>>(mut self, state: S) -> HistoryQuery {
+        self.state = Some(state.into());
+        self
+    }
+
+    pub fn created_after(mut self, date: DateTime<Utc>) -> HistoryQuery {
+        self.created_after = Some(date);
+        self
+    }
+
+    pub fn created_before(mut self, date: DateTime<Utc>) -> HistoryQuery {
+        self.created_before = Some(date);
+        self
+    }
+
+    fn matches(&self, entry: &HistoryEntry) -> bool {
+        self.kind.is_none_or(|kind| kind == entry.kind)
+            && self.state.as_ref().is_none_or(|state| *state == entry.state)
+            && self.created_after.is_none_or(|date| entry.created_at >= date)
+            && self.created_before.is_none_or(|date| entry.created_at < date)
+    }
+}
+
+/// What `History::refresh` did.
+#[derive(Debug, Clone, Default)]
+pub struct RefreshReport {
+    /// Number of entries whose state changed.
+    pub changed: usize,
+    /// Transfers the API no longer knows, now marked as expired.
+    pub expired: Vec<String>,
+    /// Transfers which could not be refreshed, and why.
+    pub errors: Vec<(String, WeTransferError)>,
+}
+
+/// A local record of the transfers and boards created by this crate, stored
+/// as a JSON-lines file. Every change appends a line; the last line of an id wins.
+#[derive(Debug)]
+pub struct History {
+    path: PathBuf,
+    entries: Mutex<Vec<HistoryEntry>>,
+}
+
+impl History {
+    /// Opens (or starts) the history stored at `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<History, WeTransferError> {
+        let path = path.as_ref().to_path_buf();
+        let mut entries: Vec<HistoryEntry> = Vec::new();
+        if path.exists() {
+            let file = File::open(&path).map_err(|error| io_error(&path, error))?;
+            let mut positions: HashMap<String, usize> = HashMap::new();
+            for (number, line) in BufReader::new(file).lines().enumerate() {
+                let line = line.map_err(|error| io_error(&path, error))?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let entry: HistoryEntry = serde_json::from_str(&line).map_err(|error| WeTransferError {
+                    status: 0,
+                    message: format!("Corrupted history {} at line {}: {}", path.display(), number + 1, error),
+                    ..Default::default()
+                })?;
+                match positions.get(&entry.id) {
+                    Some(&position) => entries[position] = entry,
+                    None => {
+                        positions.insert(entry.id.clone(), entries.len());
+                        entries.push(entry);
+                    }
+                }
+            }
+        }
+        Ok(History { path, entries: Mutex::new(entries) })
+    }
+
+    /// Every entry, oldest first.
+    pub fn entries(&self) -> Vec<HistoryEntry> {
+        self.entries.lock().unwrap().clone()
+    }
+
+    pub fn get(&self, id: &str) -> Option<HistoryEntry> {
+        self.entries.lock().unwrap().iter().find(|entry| entry.id == id).cloned()
+    }
+
+    pub fn query(&self, query: &HistoryQuery) -> Vec<HistoryEntry> {
+        self.entries.lock().unwrap().iter().filter(|entry| query.matches(entry)).cloned().collect()
+    }
+
+    /// Entries with a file whose name contains `name`, ignoring case.
+    pub fn find_by_file_name(&self, name: &str) -> Vec<HistoryEntry> {
+        let name = name.to_lowercase();
+        self.entries.lock().unwrap().iter()
+            .filter(|entry| entry.files.iter().any(|file| file.name.to_lowercase().contains(&name)))
+            .cloned()
+            .collect()
+    }
+
+    /// Transfers not expired yet, but expiring within the next `days` days.
+    pub fn expiring_within(&self, days: i64) -> Vec<HistoryEntry> {
+        self.expiring_between(Utc::now(), Duration::days(days))
+    }
+
+    fn expiring_between(&self, now: DateTime<Utc>, window: Duration) -> Vec<HistoryEntry> {
+        self.entries.lock().unwrap().iter()
+            .filter(|entry| entry.expires_at.is_some_and(|date| date > now && date <= now + window))
+            .cloned()
+            .collect()
+    }
+
+    /// Records a transfer, or updates it when already known.
+    pub fn record_transfer(&self, transfer: &Transfer) -> Result<(), WeTransferError> {
+        self.upsert(transfer_entry(transfer), false)
+    }
+
+    /// Records a board, or updates it when already known.
+    pub fn record_board(&self, board: &Board) -> Result<(), WeTransferError> {
+        self.upsert(board_entry(board), false)
+    }
+
+    /// Updates a transfer only when it is already part of the history.
+    pub fn update_transfer(&self, transfer: &Transfer) -> Result<(), WeTransferError> {
+        self.upsert(transfer_entry(transfer), true)
+    }
+
+    /// Updates a board only when it is already part of the history.
+    pub fn update_board(&self, board: &Board) -> Result<(), WeTransferError> {
+        self.upsert(board_entry(board), true)
+    }
+
+    /// Appends files uploaded to a known board.
+    pub fn add_board_files(&self, board_id: &str, files: &[HistoryFile]) -> Result<(), WeTransferError> {
+        let updated = match self.get(board_id) {
+            Some(mut entry) => {
+                for file in files {
+                    if !entry.files.contains(file) {
+                        entry.files.push(file.clone());
+                    }
+                }
+                entry
+            },
+            None => return Ok(()),
+        };
+        self.upsert(updated, true)
+    }
+
+    /// Fetches every stored transfer through `transfers` and records its current state.
+    /// Transfers answered with `404 Not Found` are marked as expired; other failures
+    /// are reported without stopping the refresh of the remaining entries.
+    pub fn refresh(&self, transfers: &TransferService) -> RefreshReport {
+        let ids: Vec<String> = self.query(&HistoryQuery::new().kind(EntryKind::Transfer))
+            .into_iter()
+            .map(|entry| entry.id)
+            .collect();
+        let mut report = RefreshReport::default();
+        for id in ids {
+            let before = self.get(&id);
+            let updated = match transfers.find(id.as_str()) {
+                Ok(transfer) => self.update_transfer(&transfer),
+                Err(ref error) if error.status == 404 => {
+                    report.expired.push(id.clone());
+                    match before.clone() {
+                        Some(entry) => self.upsert(HistoryEntry { state: String::from("expired"), ..entry }, true),
+                        None => Ok(()),
+                    }
+                },
+                Err(error) => Err(error),
+            };
+            match updated {
+                Ok(()) if self.get(&id) != before => report.changed += 1,
+                Ok(()) => {},
+                Err(error) => report.errors.push((id, error)),
+            }
+        }
+        report
+    }
+
+    /// Rewrites the file with a single line per entry.
+    pub fn compact(&self) -> Result<(), WeTransferError> {
+        let entries = self.entries.lock().unwrap();
+        let temp_path = self.path.with_extension("tmp");
+        {
+            let mut file = File::create(&temp_path).map_err(|error| io_error(&temp_path, error))?;
+            for entry in entries.iter() {
+                writeln!(file, "{}", serde_json::to_string(entry).unwrap()).map_err(|error| io_error(&temp_path, error))?;
+            }
+        }
+        fs::rename(&temp_path, &self.path).map_err(|error| io_error(&self.path, error))
+    }
+
+    // Keeps `created_at` of known entries, and only writes actual changes.
+    fn upsert(&self, mut entry: HistoryEntry, only_if_known: bool) -> Result<(), WeTransferError> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.iter().position(|known| known.id == entry.id) {
+            Some(position) => {
+                let known = &entries[position];
+                entry.created_at = known.created_at;
+                if entry.expires_at.is_none() {
+                    entry.expires_at = known.expires_at;
+                }
+                if entry.files.is_empty() {
+                    entry.files = known.files.clone();
+                }
+                if entry == *known {
+                    return Ok(());
+                }
+                self.append(&entry)?;
+                entries[position] = entry;
+            },
+            None if only_if_known => {},
+            None => {
+                self.append(&entry)?;
+                entries.push(entry);
+            }
+        }
+        Ok(())
+    }
+
+    fn append(&self, entry: &HistoryEntry) -> Result<(), WeTransferError> {
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)
+            .map_err(|error| io_error(&self.path, error))?;
+        writeln!(file, "{}", serde_json::to_string(entry).unwrap()).map_err(|error| io_error(&self.path, error))
+    }
+}
+
+fn transfer_entry(transfer: &Transfer) -> HistoryEntry {
+    HistoryEntry {
+        kind: EntryKind::Transfer,
+        id: transfer.id.clone(),
+        message: transfer.message.clone(),
+        description: None,
+        state: transfer.state.clone(),
+        url: transfer.url.clone(),
+        expires_at: DateTime::parse_from_rfc3339(&transfer.expires_at).ok().map(|date| date.with_timezone(&Utc)),
+        created_at: Utc::now(),
+        files: transfer.files.iter().map(|file| HistoryFile { name: file.name.clone(), size: file.size as u64 }).collect(),
+    }
+}
+
+fn board_entry(board: &Board) -> HistoryEntry {
+    HistoryEntry {
+        kind: EntryKind::Board,
+        id: board.id.clone(),
+        message: board.name.clone(),
+        description: board.description.clone(),
+        state: board.state.clone(),
+        url: Some(board.url.clone()),
+        expires_at: None,
+        created_at: Utc::now(),
+        files: board.items.iter()
+            .filter_map(|item| serde_json::from_value::<FileBoard>(item.clone()).ok())
+            .filter(|file| file.kind == "file")
+            .map(|file| HistoryFile { name: file.name, size: file.size })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::mock;
+    use std::env;
+
+    fn history(name: &str) -> History {
+        let path = env::temp_dir().join(format!("wetransfer-history-{}.jsonl", name));
+        let _ = fs::remove_file(&path);
+        History::open(path).unwrap()
+    }
+
+    fn transfer(id: &str, state: &str, expires_at: &str) -> Transfer {
+        Transfer {
+            id: id.to_string(),
+            message: String::from("Renders"),
+            state: state.to_string(),
+            url: Some(String::from("https://we.tl/t-12344657")),
+            expires_at: expires_at.to_string(),
+            files: vec![::responses::File { name: String::from("final-cut.mov"), size: 2048, ..Default::default() }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn it_persists_and_reloads_entries() {
+        let history = history("reload");
+        history.record_transfer(&transfer("t-1", "processing", "2018-01-01T00:00:00Z")).unwrap();
+        history.record_transfer(&transfer("t-1", "downloadable", "2018-01-01T00:00:00Z")).unwrap();
+        history.record_board(&Board { id: String::from("b-1"), name: String::from("Moodboard"), ..Default::default() }).unwrap();
+        history.update_transfer(&transfer("unknown", "downloadable", "")).unwrap();
+
+        let reloaded = History::open(&history.path).unwrap();
+        let entries = reloaded.entries();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].state, "downloadable");
+        assert_eq!(entries[0].files[0], HistoryFile { name: String::from("final-cut.mov"), size: 2048 });
+        assert_eq!(entries[1].kind, EntryKind::Board);
+
+        reloaded.compact().unwrap();
+        assert_eq!(fs::read_to_string(&history.path).unwrap().lines().count(), 2);
+    }
+
+    #[test]
+    fn it_queries_entries() {
+        let history = history("query");
+        let now = Utc::now();
+        let soon = (now + Duration::days(2)).to_rfc3339();
+        let later = (now + Duration::days(6)).to_rfc3339();
+        history.record_transfer(&transfer("t-soon", "downloadable", &soon)).unwrap();
+        history.record_transfer(&transfer("t-later", "processing", &later)).unwrap();
+        history.record_transfer(&transfer("t-gone", "expired", "2018-01-01T00:00:00Z")).unwrap();
+
+        assert_eq!(history.expiring_within(3).len(), 1);
+        assert_eq!(history.expiring_within(7).len(), 2);
+        assert_eq!(history.query(&HistoryQuery::new().state("processing"))[0].id, "t-later");
+        assert_eq!(history.query(&HistoryQuery::new().created_before(now - Duration::days(1))).len(), 0);
+        assert_eq!(history.query(&HistoryQuery::new().kind(EntryKind::Board)).len(), 0);
+        assert_eq!(history.find_by_file_name("FINAL").len(), 3);
+        assert!(history.find_by_file_name("missing").is_empty());
+    }
+
+    #[test]
+    fn it_refreshes_transfer_states() {
+        let body = fs::read_to_string(Path::new("src/support/finalize_response.json")).expect("Fixtures:");
+        let _m = mock("GET", "/041bae61-adb4-4ba2-80eb-48719396f0e3")
+          .with_status(200)
+          .with_body(body)
+          .create();
+
+        let history = history("refresh");
+        history.record_transfer(&transfer("041bae61-adb4-4ba2-80eb-48719396f0e3", "uploading", "")).unwrap();
+        let service = TransferService::new("jwt-token".into(), "1234".into());
+        assert_eq!(history.refresh(&service).changed, 1);
+        let entry = history.get("041bae61-adb4-4ba2-80eb-48719396f0e3").unwrap();
+        assert_eq!(entry.state, "processing");
+        assert_eq!(entry.expires_at, Some("2018-01-01T00:00:00Z".parse().unwrap()));
+        assert_eq!(history.refresh(&service).changed, 0);
+    }
+
+    #[test]
+    fn it_refreshes_every_entry_despite_failures() {
+        let body = fs::read_to_string(Path::new("src/support/finalize_response.json")).expect("Fixtures:");
+        let _gone = mock("GET", "/refresh-gone").with_status(404).with_body("{\"message\": \"Not found\"}").create();
+        let _broken = mock("GET", "/refresh-broken").with_status(500).with_body("{\"message\": \"Oops\"}").create();
+        let _found = mock("GET", "/041bae61-adb4-4ba2-80eb-48719396f0e3").with_status(200).with_body(body).create();
+
+        let history = history("refresh-failures");
+        history.record_transfer(&transfer("refresh-gone", "downloadable", "")).unwrap();
+        history.record_transfer(&transfer("refresh-broken", "downloadable", "")).unwrap();
+        history.record_transfer(&transfer("041bae61-adb4-4ba2-80eb-48719396f0e3", "uploading", "")).unwrap();
+        let report = history.refresh(&TransferService::new("jwt-token".into(), "1234".into()));
+
+        assert_eq!(report.changed, 2);
+        assert_eq!(report.expired, vec![String::from("refresh-gone")]);
+        assert_eq!(history.get("refresh-gone").unwrap().state, "expired");
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(report.errors[0].0, "refresh-broken");
+        assert_eq!(report.errors[0].1.status, 500);
+        assert_eq!(history.get("041bae61-adb4-4ba2-80eb-48719396f0e3").unwrap().state, "processing");
+    }
+}
@@ -0,0 +1,334 @@
+use std::collections::HashSet;
+
+use reqwest::Url;
+
+use requests::AddLink;
+use responses::WeTransferError;
+
+/// Where the url and the title are found in each CSV record.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CsvColumns {
+    /// Column holding the url: a header name, or a 0-based index when there is no header.
+    pub url: CsvColumn,
+    /// Column holding the title. Links without one are titled after their url.
+    pub title: Option<CsvColumn>,
+    /// Whether the first record names the columns.
+    pub has_header: bool,
+    pub delimiter: char,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CsvColumn {
+    Name(String),
+    Index(usize),
+}
+
+impl Default for CsvColumns {
+    fn default() -> CsvColumns {
+        CsvColumns {
+            url: CsvColumn::Name(String::from("url")),
+            title: Some(CsvColumn::Name(String::from("title"))),
+            has_header: true,
+            delimiter: ',',
+        }
+    }
+}
+
+/// Reads the `<A HREF="...">` entries of a Netscape bookmark file, as exported by browsers.
+pub fn from_bookmarks_html(html: &str) -> Vec<AddLink> {
+    let lower = html.to_ascii_lowercase();
+    let mut links = Vec::new();
+    let mut position = 0;
+    while let Some(start) = lower[position..].find("<a ").map(|offset| position + offset) {
+        let tag_end = match lower[start..].find('>') {
+            Some(offset) => start + offset,
+            None => break,
+        };
+        let text_end = lower[tag_end..].find("</a>").map(|offset| tag_end + offset).unwrap_or(html.len());
+        if let Some(url) = attribute(&html[start..tag_end], "href") {
+            links.push(link(&url, &decode_entities(html[tag_end + 1..text_end].trim())));
+        }
+        position = text_end;
+    }
+    links
+}
+
+/// Reads the `<outline>` entries of an OPML file which point to a page or a feed.
+pub fn from_opml(opml: &str) -> Vec<AddLink> {
+    let lower = opml.to_ascii_lowercase();
+    let mut links = Vec::new();
+    let mut position = 0;
+    while let Some(start) = lower[position..].find("<outline").map(|offset| position + offset) {
+        let end = lower[start..].find('>').map(|offset| start + offset).unwrap_or(opml.len());
+        let tag = &opml[start..end];
+        let url = attribute(tag, "htmlurl").or_else(|| attribute(tag, "url")).or_else(|| attribute(tag, "xmlurl"));
+        if let Some(url) = url {
+            let title = attribute(tag, "title").or_else(|| attribute(tag, "text")).unwrap_or_default();
+            links.push(link(&url, &title));
+        }
+        position = end;
+    }
+    links
+}
+
+/// Reads `[title](url)` links and `<url>` autolinks to `http` and `https` urls.
+/// Images (`![alt](src)`) and relative links are left out.
+pub fn from_markdown(markdown: &str) -> Vec<AddLink> {
+    let mut links = Vec::new();
+    let mut rest = markdown;
+    while let Some(start) = rest.find(['[', '<']) {
+        let image = rest[..start].ends_with('!');
+        let after = &rest[start + 1..];
+        let found = if rest[start..].starts_with('[') {
+            after.find(']').filter(|&title_end| after[title_end + 1..].starts_with('(')).and_then(|title_end| {
+                after[title_end + 2..].find(')').map(|url_end| {
+                    let url = after[title_end + 2..title_end + 2 + url_end].split_whitespace().next().unwrap_or_default();
+                    let found = Some(url).filter(|url| !image && is_web_url(url)).map(|url| link(url, &after[..title_end]));
+                    (found, title_end + 3 + url_end)
+                })
+            })
+        } else {
+            after.find('>')
+                .filter(|&end| is_web_url(&after[..end]))
+                .map(|end| (Some(link(&after[..end], "")), end + 1))
+        };
+        match found {
+            Some((found, length)) => {
+                links.extend(found);
+                rest = &after[length..];
+            },
+            None => rest = after,
+        }
+    }
+    links
+}
+
+fn is_web_url(url: &str) -> bool {
+    let url = url.to_ascii_lowercase();
+    url.starts_with("http://") || url.starts_with("https://")
+}
+
+/// Reads links from CSV, quoted fields included.
+pub fn from_csv(csv: &str, columns: &CsvColumns) -> Result<Vec<AddLink>, WeTransferError> {
+    let mut records = parse_csv(csv, columns.delimiter).into_iter();
+    let header = if columns.has_header { records.next().unwrap_or_default() } else { Vec::new() };
+    let index_of = |column: &CsvColumn| match *column {
+        CsvColumn::Index(index) => Ok(index),
+        CsvColumn::Name(ref name) => header.iter().position(|field| field.trim().eq_ignore_ascii_case(name)).ok_or_else(|| WeTransferError {
+            status: 0,
+            message: format!("No \"{}\" column in the CSV header", name),
+            ..Default::default()
+        }),
+    };
+    let url_index = index_of(&columns.url)?;
+    let title_index = match columns.title {
+        Some(ref column) => Some(index_of(column)?),
+        None => None,
+    };
+    Ok(records
+        .filter_map(|record| {
+            let url = record.get(url_index).map(|url| url.trim()).filter(|url| !url.is_empty())?;
+            let title = title_index.and_then(|index| record.get(index)).map(|title| title.as_str()).unwrap_or_default();
+            Some(link(url, title))
+        })
+        .collect())
+}
+
+/// Makes equivalent urls compare equal: lowercase scheme and host, no default port,
+/// no fragment nor `utm_*` tracking parameters. Urls without a scheme are taken as
+/// `https`. Returns `None` for anything but `http` and `https` urls.
+pub fn normalize_url(url: &str) -> Option<String> {
+    let url = url.trim();
+    let mut parsed = match Url::parse(url) {
+        Ok(parsed) => parsed,
+        Err(_) if !url.contains("://") => Url::parse(&format!("https://{}", url)).ok()?,
+        Err(_) => return None,
+    };
+    if (parsed.scheme() != "http" && parsed.scheme() != "https") || parsed.host_str().is_none() {
+        return None;
+    }
+    parsed.set_fragment(None);
+    let query: Vec<(String, String)> = parsed.query_pairs()
+        .filter(|(key, _)| !key.starts_with("utm_"))
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+        .collect();
+    if query.is_empty() {
+        parsed.set_query(None);
+    } else {
+        parsed.query_pairs_mut().clear().extend_pairs(query);
+    }
+    Some(parsed.to_string())
+}
+
+/// Normalizes the urls of `links`, dropping invalid ones and those already in
+/// `existing` or earlier in `links`.
+pub fn dedupe(links: Vec<AddLink>, existing: &[String]) -> Vec<AddLink> {
+    let mut seen: HashSet<String> = existing.iter().filter_map(|url| normalize_url(url)).collect();
+    links.into_iter()
+        .filter_map(|link| {
+            let url = normalize_url(&link.url)?;
+            if !seen.insert(url.clone()) {
+                return None;
+            }
+            let title = if link.title == link.url { url.clone() } else { link.title };
+            Some(AddLink { url, title })
+        })
+        .collect()
+}
+
+fn link(url: &str, title: &str) -> AddLink {
+    let url = url.trim().to_string();
+    let title = title.trim();
+    AddLink { title: if title.is_empty() { url.clone() } else { title.to_string() }, url }
+}
+
+// Reads `name="value"` (or single-quoted) from a tag, ignoring the case of the name.
+pub(crate) fn attribute(tag: &str, name: &str) -> Option<String> {
+    let lower = tag.to_ascii_lowercase();
+    let mut position = 0;
+    while let Some(offset) = lower[position..].find(name) {
+        let start = position + offset;
+        position = start + name.len();
+        let preceded = lower[..start].ends_with(|c: char| c.is_whitespace());
+        let rest = lower[position..].trim_start();
+        if !preceded || !rest.starts_with('=') {
+            continue;
+        }
+        let value_start = tag.len() - rest[1..].trim_start().len();
+        let quote = tag[value_start..].chars().next()?;
+        let value = if quote == '"' || quote == '\'' {
+            tag[value_start + 1..].split(quote).next()?
+        } else {
+            tag[value_start..].split(|c: char| c.is_whitespace() || c == '>').next()?
+        };
+        return Some(decode_entities(value));
+    }
+    None
+}
+
+pub(crate) fn decode_entities(text: &str) -> String {
+    text.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"")
+        .replace("&#39;", "'").replace("&#x27;", "'").replace("&apos;", "'").replace("&amp;", "&")
+}
+
+fn parse_csv(csv: &str, delimiter: char) -> Vec<Vec<String>> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = csv.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            },
+            '"' if quoted => quoted = false,
+            '"' if field.is_empty() => quoted = true,
+            _ if quoted => field.push(c),
+            _ if c == delimiter => record.push(field.split_off(0)),
+            '\r' => {},
+            '\n' => {
+                record.push(field.split_off(0));
+                records.push(record.split_off(0));
+            },
+            _ => field.push(c),
+        }
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+    records.retain(|record| record.iter().any(|field| !field.trim().is_empty()));
+    records
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairs(links: &[AddLink]) -> Vec<(&str, &str)> {
+        links.iter().map(|link| (link.url.as_str(), link.title.as_str())).collect()
+    }
+
+    #[test]
+    fn it_imports_bookmarks() {
+        let html = r#"<!DOCTYPE NETSCAPE-Bookmark-file-1>
+            <DL><p>
+                <DT><H3>Moodboard</H3>
+                <DL><p>
+                    <DT><A HREF="https://wetransfer.com/" ADD_DATE="1548512000">WeTransfer</A>
+                    <DT><A ADD_DATE="1548512000" HREF='https://example.com/?a=1&amp;b=2'>Tom &amp; Jerry</A>
+                </DL><p>
+            </DL><p>"#;
+        assert_eq!(pairs(&from_bookmarks_html(html)), vec![
+            ("https://wetransfer.com/", "WeTransfer"),
+            ("https://example.com/?a=1&b=2", "Tom & Jerry"),
+        ]);
+    }
+
+    #[test]
+    fn it_imports_opml() {
+        let opml = r#"<opml version="2.0"><body>
+            <outline text="Design">
+                <outline text="Dribbble" type="rss" xmlUrl="https://dribbble.com/shots/popular.rss" htmlUrl="https://dribbble.com/"/>
+                <outline title="Notes" type="link" url="https://example.com/notes"/>
+            </outline></body></opml>"#;
+        assert_eq!(pairs(&from_opml(opml)), vec![
+            ("https://dribbble.com/", "Dribbble"),
+            ("https://example.com/notes", "Notes"),
+        ]);
+    }
+
+    #[test]
+    fn it_imports_markdown() {
+        let markdown = "See [the brief](https://example.com/brief \"Brief\") and <https://wetransfer.com>.\n- [ ] todo, [not a link] (nor this)\n\
+                        ![logo](logo.png) ![remote](https://example.com/logo.png) [notes](notes.md) [mail](mailto:jane@example.com)";
+        assert_eq!(pairs(&from_markdown(markdown)), vec![
+            ("https://example.com/brief", "the brief"),
+            ("https://wetransfer.com", "https://wetransfer.com"),
+        ]);
+    }
+
+    #[test]
+    fn it_imports_csv() {
+        let csv = "Name;Link\n\"Brief; v2\";https://example.com/brief\nNo link;\n\"Say \"\"hi\"\"\";https://example.com/hi\n";
+        let columns = CsvColumns {
+            url: CsvColumn::Name(String::from("link")),
+            title: Some(CsvColumn::Name(String::from("name"))),
+            has_header: true,
+            delimiter: ';',
+        };
+        assert_eq!(pairs(&from_csv(csv, &columns).unwrap()), vec![
+            ("https://example.com/brief", "Brief; v2"),
+            ("https://example.com/hi", "Say \"hi\""),
+        ]);
+
+        let columns = CsvColumns { url: CsvColumn::Index(0), title: None, has_header: false, delimiter: ',' };
+        assert_eq!(pairs(&from_csv("https://a.com\r\nhttps://b.com", &columns).unwrap()), vec![
+            ("https://a.com", "https://a.com"),
+            ("https://b.com", "https://b.com"),
+        ]);
+        assert!(from_csv("a,b\n", &CsvColumns::default()).is_err());
+    }
+
+    #[test]
+    fn it_normalizes_urls() {
+        assert_eq!(normalize_url("HTTPS://WeTransfer.com:443/About#team"), Some(String::from("https://wetransfer.com/About")));
+        assert_eq!(normalize_url("example.com/page?utm_source=x&id=3"), Some(String::from("https://example.com/page?id=3")));
+        assert_eq!(normalize_url("mailto:me@example.com"), None);
+        assert_eq!(normalize_url("ftp://example.com"), None);
+    }
+
+    #[test]
+    fn it_dedupes_links() {
+        let links = vec![
+            link("https://wetransfer.com/#top", ""),
+            link("https://example.com/", "Example"),
+            link("https://EXAMPLE.com", "Example again"),
+            link("not a url at all", ""),
+        ];
+        let deduped = dedupe(links, &[String::from("https://wetransfer.com/")]);
+        assert_eq!(pairs(&deduped), vec![("https://example.com/", "Example")]);
+    }
+}
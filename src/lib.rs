@@ -5,16 +5,16 @@
 //! featuring  their two offered _products_:
 // !
 //! - **Transfers**,  a file sharing service which can transport up to 2GB. 
-//! The service generates a publicly accessible link, from which the download 
-//! can be started. Those files expire after seven days.
+//!   The service generates a publicly accessible link, from which the download
+//!   can be started. Those files expire after seven days.
 //!
 //! - **Boards**, a service to store creative ideas into boards,
-//! where both files and links can be pinned. These boards will expire
-//! after three months of inactivity.
+//!   where both files and links can be pinned. These boards will expire
+//!   after three months of inactivity.
 //! 
 //! ## Usage
 //! 
-//! ```rust
+//! ```rust,no_run
 //! extern crate wetransfer;
 //! 
 //! use wetransfer::requests::*;
@@ -58,12 +58,39 @@ extern crate serde_json;
 #[macro_use]
 extern crate serde_derive;
 extern crate reqwest;
+extern crate zeroize;
+extern crate httpdate;
+extern crate chrono;
+extern crate sha2;
+extern crate md5;
+extern crate native_tls;
+extern crate base64;
+extern crate hmac;
+extern crate zip;
+extern crate image;
+extern crate flate2;
+extern crate zstd;
+extern crate serde_yaml;
+extern crate toml;
 #[cfg(test)]
 extern crate mockito;
 
 /// Namespace for synchronous operations.
 pub mod sync;
+mod fsutil;
 /// Namespace that declares struct types to model the `requests` payloads.
 pub mod requests;
 /// Namespace that declares struct types to model the `responses` from the APIs.
 pub mod responses;
+/// Namespace for credential handling (app tokens and JWTs).
+pub mod secret;
+/// Namespace for the local record of created transfers and boards.
+pub mod history;
+/// Namespace for the content-hash cache which avoids re-uploading identical files.
+pub mod dedup;
+/// Namespace for rendering boards into Markdown, HTML and JSON documents.
+pub mod export;
+/// Namespace for reading links from bookmarks, CSV, OPML and Markdown files.
+pub mod import;
+/// Namespace for boards described in YAML or TOML files and applied to live boards.
+pub mod spec;
@@ -37,7 +37,7 @@ pub struct CreateBoardRequest {
     pub description: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 /// represents the body schema required to add a link to a board.
 pub struct AddLink {
     #[serde(default)]
@@ -1,25 +1,50 @@
 extern crate serde_json;
 
-#[derive(Default, Debug, Serialize, Deserialize)]
+use std::time::Duration;
+use secret::Secret;
+use sync::spool::TempCopies;
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
 /// represents an error. Used as the root error type of the crate.
 pub struct WeTransferError {
     #[serde(default)]
     pub status: u16,
     #[serde(default)]
     pub message: String,
+    /// present when the API rejected the request for exceeding its quota.
+    #[serde(skip)]
+    pub rate_limit: Option<RateLimit>,
+    /// present when the upload was stopped through a `CancellationToken`.
+    #[serde(skip)]
+    pub cancelled: Option<Box<UploadProgress>>,
+    /// the links added by `import_links` before it failed, which stay on the board.
+    #[serde(skip)]
+    pub imported: Box<[Link]>,
+}
 
+#[derive(Default, Debug, Clone, PartialEq)]
+/// represents the rate-limit information sent along a `429 Too Many Requests`.
+pub struct RateLimit {
+    /// parsed from `Retry-After`, either in seconds or as an HTTP date.
+    pub retry_after: Option<Duration>,
+    /// parsed from `X-RateLimit-Limit`.
+    pub limit: Option<u64>,
+    /// parsed from `X-RateLimit-Remaining`.
+    pub remaining: Option<u64>,
+    /// parsed from `X-RateLimit-Reset`.
+    pub reset: Option<u64>,
 }
 
-#[derive(Default, Debug, Serialize, Deserialize)]
+#[derive(Default, Debug, Deserialize)]
 /// represents the response from a login attempt.
 pub struct Login {
     pub success: bool,
     #[serde(default)]
-    pub token: String,
+    pub token: Secret,
 }
 
-#[derive(Default, Debug, Serialize, Deserialize)]
-/// represents a WeTransfer file transfer. 
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+/// represents a WeTransfer file transfer.
 pub struct Transfer {
     #[serde(default)]
     pub success: bool,
@@ -35,9 +60,12 @@ pub struct Transfer {
     pub expires_at: String,
     #[serde(default)]
     pub files: Vec<File>,
+    /// checksums of what this client uploaded, when the transfer was created by it.
+    #[serde(skip)]
+    pub integrity: Option<IntegrityReport>,
 }
 
-#[derive(Default, Debug, Serialize, Deserialize)]
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 /// represents a file within a Transfer.
 pub struct File {
     #[serde(default)]
@@ -50,9 +78,15 @@ pub struct File {
     pub name: String,
     #[serde(default)]
     pub id: String,
+    /// where the file can be fetched from, when the API shares it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub download_url: Option<String>,
+    /// the upload state of the file, e.g. `uploaded`, when the API shares it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state: Option<String>,
 }
 
-#[derive(Default, Debug, Serialize, Deserialize)]
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Multipart {
     #[serde(default)]
     pub part_numbers: u64,
@@ -132,3 +166,78 @@ pub struct CompleteFileBoardUploadResponse {
 
 
 
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+/// represents how far the upload of a transfer or of board files went.
+/// Enough to resume it later.
+pub struct UploadProgress {
+    /// the transfer or board id.
+    pub upload_id: String,
+    pub files: Vec<FileProgress>,
+    /// copies the files are read from, kept until the upload is over.
+    #[serde(skip)]
+    pub copies: TempCopies,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+/// represents the upload state of a single file.
+pub struct FileProgress {
+    pub id: String,
+    /// only present for board files.
+    #[serde(default)]
+    pub multipart_id: Option<String>,
+    pub name: String,
+    /// local path the contents are read from.
+    pub path: String,
+    /// path of the original file, when `path` is a copy made for the upload,
+    /// e.g. without metadata. The copy is made again if it is gone on resume.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    #[serde(default)]
+    pub size: u64,
+    pub part_numbers: u64,
+    pub chunk_size: u64,
+    /// 1-based numbers of the parts already stored in S3.
+    pub completed_parts: Vec<u64>,
+    /// whether the file was marked as complete in the API.
+    pub completed: bool,
+    /// checksums of the parts uploaded so far.
+    #[serde(default)]
+    pub checksums: Vec<PartChecksum>,
+    /// hex-encoded SHA-256 of the whole file, once every part is uploaded.
+    #[serde(default)]
+    pub sha256: Option<String>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+/// represents the checksums of an uploaded part.
+pub struct PartChecksum {
+    pub part: u64,
+    pub size: u64,
+    /// hex-encoded, as S3 reports it in the `ETag` of single PUT uploads.
+    pub md5: String,
+    pub sha256: String,
+    /// the `ETag` header returned by S3, without quotes.
+    pub etag: Option<String>,
+    /// whether the `ETag` could be compared with, and matched, `md5`.
+    pub verified: bool,
+    /// how many uploads it took to get a matching `ETag`.
+    pub attempts: u32,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+/// represents the integrity checks of every file of an upload.
+pub struct IntegrityReport {
+    pub files: Vec<FileIntegrity>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileIntegrity {
+    pub name: String,
+    pub path: String,
+    pub size: u64,
+    pub sha256: Option<String>,
+    pub parts: Vec<PartChecksum>,
+    /// true when every part was checked against its `ETag`.
+    pub verified: bool,
+}
@@ -0,0 +1,157 @@
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use serde::de::{Deserialize, Deserializer};
+use zeroize::Zeroize;
+
+use responses::WeTransferError;
+
+/// A credential (app token, JWT) that never shows up in `Debug` output
+/// and whose memory is wiped when dropped.
+#[derive(Clone, Default, PartialEq)]
+pub struct Secret {
+    inner: String,
+}
+
+impl Secret {
+    /// Constructor.
+    pub fn new<S: Into<String>>(value: S) -> Secret {
+        Secret { inner: value.into() }
+    }
+
+    /// Reads the secret from a file, ignoring surrounding whitespace.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Secret, WeTransferError> {
+        match fs::read_to_string(path.as_ref()) {
+            Ok(mut contents) => {
+                let secret = Secret::new(contents.trim());
+                contents.zeroize();
+                Ok(secret)
+            },
+            Err(error) => Err(WeTransferError {
+                status: 0,
+                message: format!("Error while reading secret from {}: {}", path.as_ref().display(), error),
+                ..Default::default()
+            })
+        }
+    }
+
+    /// Reads the secret from an environment variable.
+    pub fn from_env(name: &str) -> Result<Secret, WeTransferError> {
+        match env::var(name) {
+            Ok(value) => Ok(Secret::new(value)),
+            Err(error) => Err(WeTransferError {
+                status: 0,
+                message: format!("Error while reading secret from ${}: {}", name, error),
+                ..Default::default()
+            })
+        }
+    }
+
+    /// Runs a command (e.g. a password manager) and uses its trimmed stdout as the secret.
+    pub fn from_command(program: &str, args: &[&str]) -> Result<Secret, WeTransferError> {
+        match Command::new(program).args(args).output() {
+            Ok(mut output) => {
+                if !output.status.success() {
+                    output.stdout.zeroize();
+                    return Err(WeTransferError {
+                        status: 0,
+                        message: format!("Secret command `{}` exited with {}", program, output.status),
+                        ..Default::default()
+                    });
+                }
+                match String::from_utf8(output.stdout) {
+                    Ok(mut stdout) => {
+                        let secret = Secret::new(stdout.trim());
+                        stdout.zeroize();
+                        Ok(secret)
+                    },
+                    Err(error) => {
+                        error.into_bytes().zeroize();
+                        Err(WeTransferError {
+                            status: 0,
+                            message: format!("Secret command `{}` did not print valid UTF-8", program),
+                            ..Default::default()
+                        })
+                    }
+                }
+            },
+            Err(error) => Err(WeTransferError {
+                status: 0,
+                message: format!("Error while running secret command `{}`: {}", program, error),
+                ..Default::default()
+            })
+        }
+    }
+
+    /// Gives access to the cleartext value. Keep the borrow as short as possible.
+    pub fn expose(&self) -> &str {
+        self.inner.as_str()
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        self.inner.zeroize();
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Secret([REDACTED])")
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Secret {
+        Secret::new(value)
+    }
+}
+
+impl<'a> From<&'a str> for Secret {
+    fn from(value: &'a str) -> Secret {
+        Secret::new(value)
+    }
+}
+
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Secret, D::Error> {
+        String::deserialize(deserializer).map(Secret::new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_redacts_debug_output() {
+        let secret = Secret::new("super-secret-token");
+        let output = format!("{:?}", secret);
+        assert!(!output.contains("super-secret-token"));
+        assert_eq!(output, "Secret([REDACTED])");
+        assert_eq!(secret.expose(), "super-secret-token");
+    }
+
+    #[test]
+    fn it_loads_secrets_from_env_vars() {
+        env::set_var("WETRANSFER_SECRET_TEST", "from-env");
+        let secret = Secret::from_env("WETRANSFER_SECRET_TEST").unwrap();
+        assert_eq!(secret.expose(), "from-env");
+        assert!(Secret::from_env("WETRANSFER_SECRET_MISSING").is_err());
+    }
+
+    #[test]
+    fn it_loads_secrets_from_files_and_commands() {
+        let path = env::temp_dir().join("wetransfer-secret-test");
+        fs::write(&path, "from-file\n").unwrap();
+        assert_eq!(Secret::from_file(&path).unwrap().expose(), "from-file");
+        fs::remove_file(&path).unwrap();
+
+        let secret = Secret::from_command("echo", &["from-command"]).unwrap();
+        assert_eq!(secret.expose(), "from-command");
+        assert!(Secret::from_command("false", &[]).is_err());
+    }
+}
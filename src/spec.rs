@@ -0,0 +1,310 @@
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde_json;
+use serde_yaml;
+use toml;
+
+use export::human_size;
+use import::normalize_url;
+use requests::AddLink;
+use responses::*;
+use sync::board::BoardService;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+/// represents the desired contents of a board, as kept in a YAML or TOML file.
+pub struct BoardSpec {
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub links: Vec<SpecLink>,
+    /// paths of local files, relative to the spec file when loaded with `load`.
+    #[serde(default)]
+    pub files: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SpecLink {
+    pub url: String,
+    /// defaults to the url.
+    #[serde(default)]
+    pub title: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+/// represents what `apply` remembers between runs.
+pub struct SpecState {
+    pub board_id: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A local file the board lacks.
+pub struct PlannedFile {
+    pub path: String,
+    /// the name and size the file is uploaded with, e.g. once cleaned by the metadata stripper.
+    pub name: String,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+/// What `apply` would change. Its `Display` output reads like a diff.
+pub struct Plan {
+    /// `None` when the board has to be created.
+    pub board_id: Option<String>,
+    pub name: String,
+    pub links: Vec<AddLink>,
+    pub files: Vec<PlannedFile>,
+    pub unchanged_links: usize,
+    pub unchanged_files: usize,
+}
+
+impl Plan {
+    pub fn is_empty(&self) -> bool {
+        self.board_id.is_some() && self.links.is_empty() && self.files.is_empty()
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.board_id {
+            Some(ref id) => writeln!(f, "  board \"{}\" ({})", self.name, id)?,
+            None => writeln!(f, "+ board \"{}\"", self.name)?,
+        }
+        for link in &self.links {
+            writeln!(f, "+ link {} ({})", link.url, link.title)?;
+        }
+        for file in &self.files {
+            writeln!(f, "+ file {} ({})", file.name, human_size(file.size))?;
+        }
+        writeln!(f, "= {} links and {} files unchanged", self.unchanged_links, self.unchanged_files)
+    }
+}
+
+impl BoardSpec {
+    pub fn from_yaml(yaml: &str) -> Result<BoardSpec, WeTransferError> {
+        serde_yaml::from_str(yaml).map_err(|error| invalid(error.to_string()))
+    }
+
+    pub fn from_toml(toml: &str) -> Result<BoardSpec, WeTransferError> {
+        toml::from_str(toml).map_err(|error| invalid(error.to_string()))
+    }
+
+    /// Reads a `.yaml`, `.yml` or `.toml` file. File paths are made relative to its directory.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<BoardSpec, WeTransferError> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path).map_err(|error| invalid(format!("{}: {}", path.display(), error)))?;
+        let mut spec = match path.extension().and_then(|extension| extension.to_str()) {
+            Some("yaml") | Some("yml") => BoardSpec::from_yaml(&contents)?,
+            Some("toml") => BoardSpec::from_toml(&contents)?,
+            _ => return Err(invalid(format!("{}: expected a .yaml, .yml or .toml file", path.display()))),
+        };
+        let directory = path.parent().unwrap_or_else(|| Path::new(""));
+        spec.files = spec.files.iter().map(|file| directory.join(file).to_string_lossy().into_owned()).collect();
+        Ok(spec)
+    }
+
+    /// Compares the spec with the board recorded in `state`, if any. A board
+    /// which no longer exists is planned to be created again.
+    pub fn plan(&self, service: &BoardService, state: Option<&SpecState>) -> Result<Plan, WeTransferError> {
+        let board = match state {
+            Some(state) => match service.find(state.board_id.as_str()) {
+                Ok(board) => Some(board),
+                Err(ref error) if error.status == 404 => None,
+                Err(error) => return Err(error),
+            },
+            None => None,
+        };
+        let existing_urls: Vec<String> = board.iter()
+            .flat_map(|board| board.items.iter())
+            .filter(|item| item["type"] == "link")
+            .filter_map(|item| item["url"].as_str().and_then(normalize_url))
+            .collect();
+        let existing_files: Vec<(String, u64)> = board.iter()
+            .flat_map(|board| board.items.iter())
+            .filter(|item| item["type"] == "file")
+            .map(|item| (item["name"].as_str().unwrap_or_default().to_string(), item["size"].as_u64().unwrap_or_default()))
+            .collect();
+
+        let mut plan = Plan { board_id: board.map(|board| board.id), name: self.name.clone(), ..Default::default() };
+        for link in &self.links {
+            let url = normalize_url(&link.url).ok_or_else(|| invalid(format!("{} is not a valid url", link.url)))?;
+            if existing_urls.contains(&url) {
+                plan.unchanged_links += 1;
+            } else if !plan.links.iter().any(|planned| planned.url == url) {
+                let title = link.title.clone().unwrap_or_else(|| url.clone());
+                plan.links.push(AddLink { url, title });
+            }
+        }
+        for path in &self.files {
+            fs::metadata(path).map_err(|error| invalid(format!("{}: {}", path, error)))?;
+        }
+        // Files are compared under the name and size they are uploaded with, e.g. once cleaned.
+        let (uploaded, _copies) = service.transform(&self.files)?;
+        for (path, uploaded) in self.files.iter().zip(&uploaded) {
+            let size = fs::metadata(uploaded).map_err(|error| invalid(format!("{}: {}", uploaded, error)))?.len();
+            let name = Path::new(uploaded).file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+            if existing_files.contains(&(name.clone(), size)) {
+                plan.unchanged_files += 1;
+            } else {
+                plan.files.push(PlannedFile { path: path.clone(), name, size });
+            }
+        }
+        Ok(plan)
+    }
+
+    /// Carries out `plan`, creating the board when needed, and records its id in `state_path`.
+    pub fn apply<P: AsRef<Path>>(&self, service: &BoardService, plan: &Plan, state_path: P) -> Result<SpecState, WeTransferError> {
+        let board_id = match plan.board_id {
+            Some(ref id) => id.clone(),
+            None => service.create(self.name.clone(), self.description.clone())?.id,
+        };
+        let state = SpecState { board_id };
+        // Saved before adding anything, so a failure below does not create a second board next time.
+        state.save(state_path)?;
+        if !plan.links.is_empty() {
+            service.add_links(state.board_id.as_str(), &plan.links)?;
+        }
+        if !plan.files.is_empty() {
+            let paths: Vec<String> = plan.files.iter().map(|file| file.path.clone()).collect();
+            service.add_files(state.board_id.clone(), &paths)?;
+        }
+        Ok(state)
+    }
+}
+
+impl SpecState {
+    /// Returns `None` when the spec was never applied.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Option<SpecState>, WeTransferError> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(None);
+        }
+        let contents = fs::read_to_string(path).map_err(|error| invalid(format!("{}: {}", path.display(), error)))?;
+        serde_json::from_str(&contents).map(Some).map_err(|error| invalid(format!("{}: {}", path.display(), error)))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), WeTransferError> {
+        let path = path.as_ref();
+        fs::write(path, serde_json::to_string_pretty(self).unwrap()).map_err(|error| invalid(format!("{}: {}", path.display(), error)))
+    }
+
+    /// Where the state of a spec file is kept by default: next to it, as `<spec>.state.json`.
+    pub fn path_for<P: AsRef<Path>>(spec_path: P) -> PathBuf {
+        let spec_path = spec_path.as_ref();
+        let mut name = spec_path.file_name().map(|name| name.to_os_string()).unwrap_or_default();
+        name.push(".state.json");
+        spec_path.with_file_name(name)
+    }
+}
+
+fn invalid(message: String) -> WeTransferError {
+    WeTransferError {
+        status: 0,
+        message: format!("Invalid board spec: {}", message),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::mock;
+    use std::env;
+
+    const YAML: &str = "
+name: Client X
+description: Spring campaign
+links:
+  - url: https://wetransfer.com/
+    title: WeTransfer
+  - url: https://example.com/#top
+files:
+  - Cargo.toml
+";
+
+    #[test]
+    fn it_reads_yaml_and_toml_specs() {
+        let toml = r#"
+name = "Client X"
+description = "Spring campaign"
+files = ["Cargo.toml"]
+
+[[links]]
+url = "https://wetransfer.com/"
+title = "WeTransfer"
+
+[[links]]
+url = "https://example.com/#top"
+"#;
+        let spec = BoardSpec::from_yaml(YAML).unwrap();
+        assert_eq!(spec, BoardSpec::from_toml(toml).unwrap());
+        assert_eq!(spec.links[1].title, None);
+        assert!(BoardSpec::from_yaml("links: []").unwrap_err().message.starts_with("Invalid board spec: missing field `name`"));
+
+        let path = env::temp_dir().join("wetransfer-spec").join("board.yml");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, YAML).unwrap();
+        let loaded = BoardSpec::load(&path).unwrap();
+        assert_eq!(loaded.files, vec![path.with_file_name("Cargo.toml").to_string_lossy().into_owned()]);
+        assert_eq!(SpecState::path_for(&path), path.with_file_name("board.yml.state.json"));
+    }
+
+    #[test]
+    fn it_plans_only_missing_items() {
+        let size = fs::metadata("Cargo.toml").unwrap().len();
+        let board = format!(r#"{{"id": "spec-board", "name": "Client X", "state": "downloadable", "url": "https://we.tl/b-spec", "items": [
+            {{"id": "link-1", "type": "link", "url": "https://WeTransfer.com", "meta": {{"title": "WeTransfer"}}}},
+            {{"id": "file-1", "type": "file", "name": "Cargo.toml", "size": {}}}]}}"#, size);
+        let _find = mock("GET", "/spec-board").with_status(200).with_body(board.as_str()).create();
+
+        let service = BoardService::new("jwt-token".into(), "1234".into());
+        let state = SpecState { board_id: String::from("spec-board") };
+        let plan = BoardSpec::from_yaml(YAML).unwrap().plan(&service, Some(&state)).unwrap();
+        assert_eq!(plan.board_id, Some(String::from("spec-board")));
+        assert_eq!(plan.links, vec![AddLink { url: String::from("https://example.com/"), title: String::from("https://example.com/") }]);
+        assert!(plan.files.is_empty());
+        assert_eq!(plan.to_string(), "  board \"Client X\" (spec-board)\n+ link https://example.com/ (https://example.com/)\n= 1 links and 1 files unchanged\n");
+    }
+
+    #[test]
+    fn it_plans_files_under_their_cleaned_size() {
+        let directory = env::temp_dir().join("wetransfer-spec-cleaned");
+        fs::create_dir_all(&directory).unwrap();
+        let photo = directory.join("photo.jpg");
+        // A JPEG whose EXIF segment takes 15 of its 25 bytes.
+        fs::write(&photo, [&[0xFF, 0xD8, 0xFF, 0xE1, 0x00, 0x0D][..], b"Exif\0\0GPS\0\0", &[0xFF, 0xDA, 0x00, 0x02, 0x12, 0x34, 0xFF, 0xD9]].concat()).unwrap();
+        let board = r#"{"id": "cleaned-spec-board", "name": "Client X", "state": "downloadable", "url": "https://we.tl/b-spec", "items": [
+            {"id": "file-1", "type": "file", "name": "photo.jpg", "size": 10}]}"#;
+        let _find = mock("GET", "/cleaned-spec-board").with_status(200).with_body(board).create();
+
+        let mut service = BoardService::new("jwt-token".into(), "1234".into());
+        service.set_metadata_stripper(::std::sync::Arc::new(::sync::privacy::MetadataStripper::new()));
+        let spec = BoardSpec { links: Vec::new(), files: vec![photo.to_string_lossy().into_owned()], ..BoardSpec::from_yaml(YAML).unwrap() };
+        let state = SpecState { board_id: String::from("cleaned-spec-board") };
+        let plan = spec.plan(&service, Some(&state)).unwrap();
+        assert!(plan.is_empty());
+        assert_eq!(plan.unchanged_files, 1);
+    }
+
+    #[test]
+    fn it_creates_boards_on_first_apply() {
+        let body = fs::read_to_string(Path::new("src/support/create_board.json")).expect("Fixtures:");
+        let _create = mock("POST", "/").with_status(201).with_body(body).create();
+        let links = fs::read_to_string(Path::new("src/support/add_links.json")).expect("Fixtures:");
+        let add_links = mock("POST", "/swnoauod92ugkkhbj20190126151445/links").with_status(200).with_body(links).create();
+
+        let service = BoardService::new("jwt-token".into(), "1234".into());
+        let spec = BoardSpec { files: Vec::new(), ..BoardSpec::from_yaml(YAML).unwrap() };
+        let plan = spec.plan(&service, None).unwrap();
+        assert!(plan.to_string().starts_with("+ board \"Client X\"\n+ link https://wetransfer.com/ (WeTransfer)\n"));
+
+        let state_path = env::temp_dir().join("wetransfer-spec-state.json");
+        let _ = fs::remove_file(&state_path);
+        let state = spec.apply(&service, &plan, &state_path).unwrap();
+        assert_eq!(state.board_id, "swnoauod92ugkkhbj20190126151445");
+        assert_eq!(SpecState::load(&state_path).unwrap(), Some(state));
+        add_links.assert();
+    }
+}
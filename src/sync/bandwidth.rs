@@ -0,0 +1,199 @@
+use std::cmp;
+use std::io::{self, Read};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::{Local, NaiveTime};
+
+// Size of the slices handed to the HTTP client, so throttling stays smooth.
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// Caps the upload bandwidth of every S3 part upload sharing it.
+/// Both the limit and the schedule can be changed while uploads are running.
+#[derive(Debug)]
+pub struct BandwidthLimiter {
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    bytes_per_second: Option<u64>,
+    schedule: Option<BandwidthSchedule>,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl Default for BandwidthLimiter {
+    fn default() -> BandwidthLimiter {
+        BandwidthLimiter::new(None)
+    }
+}
+
+impl BandwidthLimiter {
+    /// Constructor. `None` means full speed.
+    pub fn new(bytes_per_second: Option<u64>) -> BandwidthLimiter {
+        BandwidthLimiter {
+            state: Mutex::new(State {
+                bytes_per_second,
+                schedule: None,
+                tokens: 0.0,
+                last_refill: Instant::now(),
+            })
+        }
+    }
+
+    /// Changes the limit used outside of the schedule's time ranges.
+    pub fn set_limit(&self, bytes_per_second: Option<u64>) {
+        self.state.lock().unwrap().bytes_per_second = bytes_per_second;
+    }
+
+    /// Installs (or removes) a time-of-day schedule, evaluated in local time.
+    pub fn set_schedule(&self, schedule: Option<BandwidthSchedule>) {
+        self.state.lock().unwrap().schedule = schedule;
+    }
+
+    /// The limit applying right now.
+    pub fn current_limit(&self) -> Option<u64> {
+        let state = self.state.lock().unwrap();
+        state.limit_at(Local::now().time())
+    }
+
+    /// Accounts for `bytes` about to be sent, sleeping as long as needed to honour the limit.
+    pub fn consume(&self, bytes: usize) {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let now = Instant::now();
+            let limit = match state.limit_at(Local::now().time()) {
+                Some(limit) if limit > 0 => limit as f64,
+                _ => {
+                    state.tokens = 0.0;
+                    state.last_refill = now;
+                    return;
+                }
+            };
+            let elapsed = now.duration_since(state.last_refill);
+            let elapsed_secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+            // At most one second worth of bytes can be saved up.
+            state.tokens = (state.tokens + elapsed_secs * limit).min(limit);
+            state.last_refill = now;
+            state.tokens -= bytes as f64;
+            if state.tokens >= 0.0 {
+                None
+            } else {
+                Some(Duration::from_millis((-state.tokens / limit * 1000.0).ceil() as u64))
+            }
+        };
+        if let Some(duration) = wait {
+            thread::sleep(duration);
+        }
+    }
+}
+
+impl State {
+    fn limit_at(&self, time: NaiveTime) -> Option<u64> {
+        match self.schedule.as_ref().and_then(|schedule| schedule.rule_at(time)) {
+            Some(rule) => rule.bytes_per_second,
+            None => self.bytes_per_second,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+/// A list of time ranges with their own limit, e.g. capped during work hours.
+pub struct BandwidthSchedule {
+    rules: Vec<ScheduleRule>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A time range of the day. Ranges where `from` is after `until` span midnight.
+pub struct ScheduleRule {
+    pub from: NaiveTime,
+    pub until: NaiveTime,
+    pub bytes_per_second: Option<u64>,
+}
+
+impl BandwidthSchedule {
+    /// Constructor.
+    pub fn new() -> BandwidthSchedule {
+        BandwidthSchedule::default()
+    }
+
+    /// Adds a range. When ranges overlap, the first one added wins.
+    pub fn rule(mut self, from: NaiveTime, until: NaiveTime, bytes_per_second: Option<u64>) -> BandwidthSchedule {
+        self.rules.push(ScheduleRule { from, until, bytes_per_second });
+        self
+    }
+
+    fn rule_at(&self, time: NaiveTime) -> Option<&ScheduleRule> {
+        self.rules.iter().find(|rule| {
+            if rule.from <= rule.until {
+                rule.from <= time && time < rule.until
+            } else {
+                time >= rule.from || time < rule.until
+            }
+        })
+    }
+}
+
+/// A `Read` adapter which hands out small slices and throttles them through a `BandwidthLimiter`.
+pub struct ThrottledReader<R> {
+    inner: R,
+    limiter: Arc<BandwidthLimiter>,
+}
+
+impl<R: Read> ThrottledReader<R> {
+    /// Constructor.
+    pub fn new(inner: R, limiter: Arc<BandwidthLimiter>) -> ThrottledReader<R> {
+        ThrottledReader { inner, limiter }
+    }
+}
+
+impl<R: Read> Read for ThrottledReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = cmp::min(buf.len(), CHUNK_SIZE);
+        let read = self.inner.read(&mut buf[..len])?;
+        self.limiter.consume(read);
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn it_throttles_reads() {
+        let limiter = Arc::new(BandwidthLimiter::new(Some(100 * 1024)));
+        let mut reader = ThrottledReader::new(Cursor::new(vec![0; 150 * 1024]), limiter.clone());
+        let start = Instant::now();
+        let mut sink = Vec::new();
+        reader.read_to_end(&mut sink).unwrap();
+        assert_eq!(sink.len(), 150 * 1024);
+        assert!(start.elapsed() >= Duration::from_millis(1400));
+
+        limiter.set_limit(None);
+        let start = Instant::now();
+        let mut reader = ThrottledReader::new(Cursor::new(vec![0; 1024 * 1024]), limiter);
+        reader.read_to_end(&mut sink).unwrap();
+        assert!(start.elapsed() < Duration::from_millis(500));
+    }
+
+    #[test]
+    fn it_picks_limits_from_the_schedule() {
+        let schedule = BandwidthSchedule::new()
+            .rule(NaiveTime::from_hms_opt(9, 0, 0).unwrap(), NaiveTime::from_hms_opt(18, 0, 0).unwrap(), Some(1024))
+            .rule(NaiveTime::from_hms_opt(22, 0, 0).unwrap(), NaiveTime::from_hms_opt(6, 0, 0).unwrap(), None);
+        let state = State {
+            bytes_per_second: Some(4096),
+            schedule: Some(schedule),
+            tokens: 0.0,
+            last_refill: Instant::now(),
+        };
+        assert_eq!(state.limit_at(NaiveTime::from_hms_opt(10, 30, 0).unwrap()), Some(1024));
+        assert_eq!(state.limit_at(NaiveTime::from_hms_opt(23, 0, 0).unwrap()), None);
+        assert_eq!(state.limit_at(NaiveTime::from_hms_opt(3, 0, 0).unwrap()), None);
+        assert_eq!(state.limit_at(NaiveTime::from_hms_opt(20, 0, 0).unwrap()), Some(4096));
+    }
+}
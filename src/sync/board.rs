@@ -1,86 +1,387 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use sync::requester::RequestService;
+use secret::Secret;
+use sync::rate_limit::{RateLimiter, RateLimitPolicy};
+use sync::bandwidth::BandwidthLimiter;
+use sync::cancel::CancellationToken;
+use sync::upload::{self, UploadOptions, UploadTarget};
+use sync::builder::BoardFilesBuilder;
+use sync::download::{DownloadItem, Downloader};
+use sync::enrich::LinkEnricher;
+use sync::webhook::{WebhookDispatcher, WebhookEvent};
+use sync::policy::UploadPolicy;
+use sync::privacy::MetadataStripper;
+use sync::preset::{ImagePreset, SkippedImage, Transcoded};
+use sync::spool::TempCopies;
+use std::collections::HashMap;
+use import;
+use history::{History, HistoryFile};
+use std::sync::Arc;
 use responses::*;
+use fsutil::io_error;
 use requests::*;
 use std::fs;
-use std::fs::File;
-use std::error::Error;
-use std::io::prelude::*;
+use std::fmt;
+use serde_json;
+
+type SkipReporter = Box<dyn Fn(&SkippedImage) + Send + Sync>;
 
-#[derive(Debug)]
 /// A service to perform operations in Boards. 
 pub struct BoardService {
-    requester: RequestService
+    requester: RequestService,
+    history: Option<Arc<History>>,
+    enricher: Option<Arc<LinkEnricher>>,
+    webhooks: Option<Arc<WebhookDispatcher>>,
+    policy: Option<Arc<UploadPolicy>>,
+    stripper: Option<Arc<MetadataStripper>>,
+    presets: HashMap<String, ImagePreset>,
+    image_preset: Option<String>,
+    on_skipped_image: Option<SkipReporter>,
 }
 
+impl fmt::Debug for BoardService {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BoardService")
+          .field("requester", &self.requester)
+          .field("history", &self.history)
+          .field("enricher", &self.enricher)
+          .field("webhooks", &self.webhooks)
+          .field("policy", &self.policy)
+          .field("stripper", &self.stripper)
+          .field("presets", &self.presets)
+          .field("image_preset", &self.image_preset)
+          .finish()
+    }
+}
+
+/// What `BoardService::mirror` did.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct MirrorReport {
+    /// Files fetched by this run.
+    pub downloaded: Vec<PathBuf>,
+    /// Files already mirrored by a previous run.
+    pub skipped: Vec<PathBuf>,
+    /// Number of links written to `.wetransfer/links.md`.
+    pub links: usize,
+}
+
+// Where `mirror` keeps its own files, apart from the board's.
+const MIRROR_METADATA: &str = ".wetransfer";
+
 #[cfg(not(test))]
 const BOARDS_URL: &str = "https://dev.wetransfer.com/v2/boards";
 #[cfg(test)]
 const BOARDS_URL: &str = mockito::SERVER_URL;
 
 impl BoardService {
-    pub fn new(jwt: String, app_token: String) -> BoardService {
+    pub fn new(jwt: Secret, app_token: Secret) -> BoardService {
         BoardService {
             requester: RequestService::new(jwt, app_token, BOARDS_URL.to_owned()),
+            history: None,
+            enricher: None,
+            webhooks: None,
+            policy: None,
+            stripper: None,
+            presets: vec![ImagePreset::web_preview()].into_iter().map(|preset| (preset.name.clone(), preset)).collect(),
+            image_preset: None,
+            on_skipped_image: None,
         }
     }
 
+    pub fn set_rate_limiter(&mut self, limiter: Arc<RateLimiter>) {
+        self.requester.set_rate_limiter(limiter);
+    }
+
+    pub fn set_rate_limit_policy(&mut self, policy: RateLimitPolicy) {
+        self.requester.set_rate_limit_policy(policy);
+    }
+
+    pub fn set_bandwidth_limiter(&mut self, limiter: Arc<BandwidthLimiter>) {
+        self.requester.set_bandwidth_limiter(limiter);
+    }
+
     pub fn create<S: Into<String>+ToString>(&self, name: S, description: Option<S>) -> Result<Board, WeTransferError> {
-        let normalized_desc = match description {
-            Some(contents) => Some(contents.to_string()),
-            None => None,
-        };
+        let normalized_desc = description.map(|contents| contents.to_string());
         let payload = CreateBoardRequest { name: name.to_string(), description: normalized_desc };
-        self.requester.post::<CreateBoardRequest, Board>("/", payload)
+        let board = self.requester.post::<CreateBoardRequest, Board>("/", payload)?;
+        if let Some(ref history) = self.history {
+            // The history is best effort: a failing write must not hide the API response.
+            let _ = history.record_board(&board);
+        }
+        self.dispatch(WebhookEvent::BoardCreated(board.clone()));
+        Ok(board)
     }
     
+    /// Records every created board in `history`, along with the files added to it.
+    pub fn set_history(&mut self, history: Arc<History>) {
+        self.history = Some(history);
+    }
+
+    /// Titles the links passed to `add_links` which have none, or only their url as title.
+    pub fn set_link_enricher(&mut self, enricher: Arc<LinkEnricher>) {
+        self.enricher = Some(enricher);
+    }
+
+    /// Sends board events (created, files added, upload failed) to `webhooks`.
+    pub fn set_webhooks(&mut self, webhooks: Arc<WebhookDispatcher>) {
+        self.webhooks = Some(webhooks);
+    }
+
+    /// Screens the files added to boards with `policy` before adding them.
+    pub fn set_upload_policy(&mut self, policy: Arc<UploadPolicy>) {
+        self.policy = Some(policy);
+    }
+
+    /// Uploads copies of the files without their metadata, once the upload policy let them through.
+    pub fn set_metadata_stripper(&mut self, stripper: Arc<MetadataStripper>) {
+        self.stripper = Some(stripper);
+    }
+
+    /// Makes `preset` available to `set_image_preset` under its name, replacing
+    /// any preset of the same name. `web-preview` is registered from the start.
+    pub fn register_preset(&mut self, preset: ImagePreset) {
+        self.presets.insert(preset.name.clone(), preset);
+    }
+
+    /// Uploads the images transcoded with the preset named `name`, once the upload
+    /// policy let them through, and before the metadata stripper. `None` uploads
+    /// images as they are.
+    pub fn set_image_preset(&mut self, name: Option<&str>) -> Result<(), WeTransferError> {
+        if let Some(name) = name {
+            if !self.presets.contains_key(name) {
+                return Err(WeTransferError { status: 0, message: format!("Unknown image preset: {}", name), ..Default::default() });
+            }
+        }
+        self.image_preset = name.map(String::from);
+        Ok(())
+    }
+
+    /// Receives the images the image preset could not transcode, which are uploaded as they are.
+    pub fn on_skipped_image<F: Fn(&SkippedImage) + Send + Sync + 'static>(&mut self, reporter: F) {
+        self.on_skipped_image = Some(Box::new(reporter));
+    }
+
+    pub fn find<S: Into<String>>(&self, board_id: S) -> Result<Board, WeTransferError> {
+        let path = format!("/{}", board_id.into());
+        let board = self.requester.get::<Board>(&path)?;
+        if let Some(ref history) = self.history {
+            let _ = history.update_board(&board);
+        }
+        Ok(board)
+    }
+
+    /// Copies a board into `directory`: every file, plus a `links.md` index of its
+    /// links and a `board.json` snapshot of the board in `directory/.wetransfer`.
+    /// Files listed with the same id and size in the previous snapshot, and still on
+    /// disk, are not downloaded again. A board file named `.wetransfer` is refused, as
+    /// are files which would be saved under the same name.
+    pub fn mirror<P: AsRef<Path>>(&self, board_id: &str, directory: P) -> Result<MirrorReport, WeTransferError> {
+        let directory = directory.as_ref();
+        let board = self.find(board_id)?;
+        let downloader = Downloader::new();
+        let items = DownloadItem::from_board(&board)?;
+        // Checked before looking for files already mirrored, which two files saved under one name would confuse.
+        downloader.check_names(&items)?;
+        if items.iter().any(|item| downloader.local_name(item) == MIRROR_METADATA) {
+            return Err(WeTransferError {
+                status: 0,
+                message: format!("Board file {} collides with the mirror metadata directory", MIRROR_METADATA),
+                ..Default::default()
+            });
+        }
+        let metadata = directory.join(MIRROR_METADATA);
+        let snapshot_path = metadata.join("board.json");
+        let previous: Option<Board> = fs::read_to_string(&snapshot_path).ok()
+            .and_then(|contents| serde_json::from_str(&contents).ok());
+        let mirrored = previous.as_ref().map(file_ids).unwrap_or_default();
+
+        let mut report = MirrorReport::default();
+        let mut pending = Vec::new();
+        // `from_board` lists file items in the same order as `file_ids`.
+        for (item, id) in items.into_iter().zip(file_ids(&board)) {
+            if downloader.is_downloaded(&item, directory) && mirrored.contains(&id) {
+                report.skipped.push(directory.join(downloader.local_name(&item)));
+            } else {
+                pending.push(item);
+            }
+        }
+        report.downloaded = downloader.download_all(&pending, directory)?;
+
+        let links: Vec<String> = board.items.iter()
+            .filter(|item| item["type"] == "link")
+            .map(|item| {
+                let url = item["url"].as_str().unwrap_or_default();
+                let title = item["meta"]["title"].as_str().filter(|title| !title.is_empty()).unwrap_or(url);
+                format!("- {}\n", ::export::markdown_link(title, url))
+            })
+            .collect();
+        report.links = links.len();
+        let index = format!("# {}\n\n{}", board.name, links.concat());
+        fs::create_dir_all(&metadata).map_err(|error| io_error(&metadata, error))?;
+        write(&metadata.join("links.md"), index.as_bytes())?;
+        // Written last, so an interrupted run is never mistaken for a complete one.
+        write(&snapshot_path, serde_json::to_string_pretty(&board).unwrap().as_bytes())?;
+        Ok(report)
+    }
+
     pub fn add_links<S: Into<String>+ToString>(&self, board_id: S, links: &[AddLink]) -> Result<Vec<Link>, WeTransferError> {
         let path = format!("/{}/links", board_id.to_string());
-        self.requester.post::<&[AddLink], Vec<Link>>(&path, links)
+        match self.enricher {
+            Some(ref enricher) => self.requester.post::<&[AddLink], Vec<Link>>(&path, &enricher.enrich(links)),
+            None => self.requester.post::<&[AddLink], Vec<Link>>(&path, links),
+        }
+    }
+
+    /// Starts describing files to add to a board, for when `add_files` is not flexible enough.
+    pub fn files_builder<S: ToString>(&self, board_id: S) -> BoardFilesBuilder<'_> {
+        BoardFilesBuilder::new(self, board_id)
+    }
+
+    /// Adds imported links to a board, `batch_size` at a time. Urls are normalized,
+    /// and links already on the board or repeated in `links` are left out. Returns
+    /// the links added. When a batch fails, the batches sent before it stay on the
+    /// board, and the error lists their links in `imported`.
+    pub fn import_links(&self, board_id: &str, links: Vec<AddLink>, batch_size: usize) -> Result<Vec<Link>, WeTransferError> {
+        let board = self.find(board_id)?;
+        let existing: Vec<String> = board.items.iter()
+            .filter(|item| item["type"] == "link")
+            .filter_map(|item| item["url"].as_str().map(String::from))
+            .collect();
+        let links = import::dedupe(links, &existing);
+        let mut added = Vec::new();
+        for batch in links.chunks(batch_size.max(1)) {
+            match self.add_links(board_id, batch) {
+                Ok(links) => added.extend(links),
+                Err(error) => return Err(WeTransferError { imported: added.into_boxed_slice(), ..error }),
+            }
+        }
+        Ok(added)
+    }
+
+    /// Uploads files to a board, returning the checksums of what was uploaded.
+    pub fn add_files<S: Into<String>+ToString>(&self, board_id: S, paths: &[S]) -> Result<IntegrityReport, WeTransferError> {
+        self.add_files_with_cancellation(board_id, paths, &CancellationToken::new())
+    }
+
+    /// Same as `add_files`, but stops as soon as `cancel` is cancelled. The returned
+    /// error then carries an `UploadProgress` which can be handed to `resume_file_uploads`.
+    pub fn add_files_with_cancellation<S: Into<String>+ToString>(&self, board_id: S, paths: &[S], cancel: &CancellationToken) -> Result<IntegrityReport, WeTransferError> {
+        let progress = self.start_upload(&board_id.to_string(), paths)?;
+        self.resume_file_uploads(progress, cancel)
+    }
+
+    /// Uploads whatever is left of files previously added to a board.
+    pub fn resume_file_uploads(&self, mut progress: UploadProgress, cancel: &CancellationToken) -> Result<IntegrityReport, WeTransferError> {
+        self.resume_file_uploads_with(&mut progress, cancel, &UploadOptions::default(), |_| {})
+    }
+
+    /// Same as `resume_file_uploads`, following `options` and calling `on_part` after every uploaded part.
+    pub fn resume_file_uploads_with<F: FnMut(&UploadProgress) + Send>(&self, progress: &mut UploadProgress, cancel: &CancellationToken, options: &UploadOptions, on_part: F) -> Result<IntegrityReport, WeTransferError> {
+        let report = match upload::upload_files_with(self, progress, cancel, options, on_part) {
+            Ok(report) => report,
+            Err(error) => {
+                if error.cancelled.is_none() {
+                    self.dispatch(WebhookEvent::UploadFailed { kind: String::from("board"), id: progress.upload_id.clone(), error: error.clone() });
+                }
+                return Err(error);
+            },
+        };
+        self.dispatch(WebhookEvent::board_files_added(progress));
+        if let Some(ref history) = self.history {
+            let files: Vec<HistoryFile> = progress.files.iter()
+                .map(|file| HistoryFile { name: file.name.clone(), size: file.size })
+                .collect();
+            let _ = history.add_board_files(&progress.upload_id, &files);
+        }
+        Ok(report)
+    }
+
+    /// Adds the files at `paths` to a board. Files dropped by the upload policy are left out.
+    /// The files are declared as they are, to be uploaded as they are: only `start_upload` cleans them.
+    pub fn start_file_uploads<S: Into<String>+ToString>(&self, board_id: &S, paths: &[S]) -> Result<Vec<FileBoard>, WeTransferError> {
+        let paths = self.screen(paths)?;
+        self.request_files(&board_id.to_string(), &paths)
+    }
+
+    /// Same as `start_file_uploads`, returning what is needed to upload the files
+    /// left by the policy, from their cleaned copies where needed.
+    pub fn start_upload<S: ToString>(&self, board_id: &str, paths: &[S]) -> Result<UploadProgress, WeTransferError> {
+        let (originals, paths, copies) = self.prepare(paths)?;
+        let files = self.request_files(board_id, &paths)?;
+        Ok(UploadProgress::from_board_files(board_id, &files, &paths).with_copies(&originals, copies))
+    }
+
+    // Runs the upload policy, the image preset, then the metadata stripper. Returns the files left
+    // by the policy, the paths to upload them from, and the copies.
+    fn prepare<S: ToString>(&self, paths: &[S]) -> Result<(Vec<String>, Vec<String>, TempCopies), WeTransferError> {
+        let originals = self.screen(paths)?;
+        let (paths, copies) = self.transform(&originals)?;
+        Ok((originals, paths, copies))
     }
 
-    pub fn add_files<S: Into<String>+ToString>(&self, board_id: S, paths: &[S]) -> Result<(), WeTransferError> {
-        let result = self.start_file_uploads(&board_id, paths);
-        match result {
-            Ok(list_of_files) => self.fulfill_file_uploads(&board_id, paths, &list_of_files),
-            Err(wetransfer_error) => Err(wetransfer_error)
+    // Returns the files left by the upload policy.
+    fn screen<S: ToString>(&self, paths: &[S]) -> Result<Vec<String>, WeTransferError> {
+        let paths: Vec<String> = paths.iter().map(|path| path.to_string()).collect();
+        match self.policy {
+            Some(ref policy) => policy.screen(&paths),
+            None => Ok(paths),
         }
     }
 
-    fn start_file_uploads<S: Into<String>+ToString>(&self, board_id: &S, paths: &[S]) -> Result<Vec<FileBoard>, WeTransferError> {
-        let files = paths.iter().map(|path_str: &S| {
-            // Compiler suggested to put this expression under
-            // a let variable.
-            let path_str_ref = path_str.to_string();
-            let path = Path::new(&path_str_ref);
-            self.extract_file_info(&path)
+    pub(crate) fn upload_policy(&self) -> Option<&UploadPolicy> {
+        self.policy.as_deref()
+    }
+
+    // Returns the paths to upload in place of `paths`: transcoded, then cleaned copies where needed.
+    pub(crate) fn transform(&self, paths: &[String]) -> Result<(Vec<String>, TempCopies), WeTransferError> {
+        let (paths, mut copies) = match self.image_preset.as_ref().and_then(|name| self.presets.get(name)) {
+            Some(preset) => {
+                let Transcoded { paths, skipped, directory } = preset.apply(paths)?;
+                if let Some(ref reporter) = self.on_skipped_image {
+                    for image in &skipped {
+                        reporter(image);
+                    }
+                }
+                (paths, TempCopies::new(vec![directory]))
+            },
+            None => (paths.to_vec(), TempCopies::default()),
+        };
+        match self.stripper {
+            Some(ref stripper) => {
+                let (paths, cleaned) = stripper.clean_all(&paths)?;
+                copies.merge(cleaned);
+                Ok((paths, copies))
+            },
+            None => Ok((paths, copies)),
+        }
+    }
+
+    fn request_files(&self, board_id: &str, paths: &[String]) -> Result<Vec<FileBoard>, WeTransferError> {
+        let files = paths.iter().map(|path_str: &String| {
+            let path = Path::new(path_str);
+            self.extract_file_info(path)
         }).collect();
 
         match files {
-            Ok(file_requests) => {
-                let path = format!("/{}/files", board_id.to_string());
-                self.requester.post::<Vec<FileRequest>, Vec<FileBoard>>(&path, file_requests)
-            },
+            Ok(file_requests) => self.add_file_requests(board_id, file_requests),
             Err(transfer_error) => Err(transfer_error)
         }
     }
 
-    fn fulfill_file_uploads<S: Into<String>+ToString>(&self, board_id: &S, paths: &[S], list_of_files: &[FileBoard]) -> Result<(), WeTransferError> {
-        for (index, file) in list_of_files.iter().enumerate() {
-            let path_str = paths[index].to_string();
-            let path = Path::new(path_str.as_str());
-            let mut file_io = File::open(path).unwrap();
-            let mut buffer = std::vec::from_elem(0, file.multipart.chunk_size as usize);
-            for part in 1..=file.multipart.part_numbers {
-                file_io.read_exact(&mut buffer).unwrap();
-                let s3_url = self.upload_url_for(board_id.to_string(), file.id.to_owned(), part, file.multipart.id.to_string())?.url;
-                self.requester.file_upload(s3_url, &buffer).unwrap();
-            }
-            self.mark_as_complete(board_id.to_string(), file.id.to_owned())?;
+    /// Adds files described by hand to a board, e.g. by `BoardFilesBuilder::build_request`.
+    pub fn add_file_requests(&self, board_id: &str, file_requests: Vec<FileRequest>) -> Result<Vec<FileBoard>, WeTransferError> {
+        let path = format!("/{}/files", board_id);
+        self.requester.post::<Vec<FileRequest>, Vec<FileBoard>>(&path, file_requests)
+    }
+
+    // Webhooks are best effort and delivered in the background: a slow or failing
+    // delivery must neither delay nor hide the API response.
+    fn dispatch(&self, event: WebhookEvent) {
+        if let Some(ref webhooks) = self.webhooks {
+            webhooks.queue(event);
         }
-        Ok(())
     }
-    
+
     fn upload_url_for<S: Into<String>+ToString>(&self, board_id: S, file_id: S, part: u64, multipart_id: S) -> Result<GetUploadUrlResponse, WeTransferError> {
         let path = format!("/{}/files/{}/upload-url/{}/{}", board_id.to_string(), file_id.to_string(), part, multipart_id.to_string());
         self.requester.get::<GetUploadUrlResponse>(&path)
@@ -102,16 +403,52 @@ impl BoardService {
             }),
             Err(error) => Err(WeTransferError {
                 status: 0,
-                message: error.description().to_string(),
+                message: error.to_string(),
+                ..Default::default()
             })
         }
     }
 }
 
+// The id and size of every file item of a board.
+fn file_ids(board: &Board) -> Vec<(String, u64)> {
+    board.items.iter()
+        .filter(|item| item["type"] == "file")
+        .map(|item| (item["id"].as_str().unwrap_or_default().to_string(), item["size"].as_u64().unwrap_or_default()))
+        .collect()
+}
+
+fn write(path: &Path, contents: &[u8]) -> Result<(), WeTransferError> {
+    fs::write(path, contents).map_err(|error| WeTransferError {
+        status: 0,
+        message: format!("Error while writing {}: {}", path.display(), error),
+        ..Default::default()
+    })
+}
+
+impl UploadTarget for BoardService {
+    fn upload_url_for_part(&self, progress: &UploadProgress, file: &FileProgress, part: u64) -> Result<String, WeTransferError> {
+        let multipart_id = file.multipart_id.clone().unwrap_or_default();
+        self.upload_url_for(progress.upload_id.clone(), file.id.clone(), part, multipart_id).map(|response| response.url)
+    }
+
+    fn complete_file(&self, progress: &UploadProgress, file: &FileProgress) -> Result<(), WeTransferError> {
+        self.mark_as_complete(progress.upload_id.as_str(), file.id.as_str()).map(|_| ())
+    }
+
+    fn requester(&self) -> &RequestService {
+        &self.requester
+    }
+
+    fn restore_copies(&self, progress: &mut UploadProgress) -> Result<(), WeTransferError> {
+        upload::restore_copies(progress, |paths| self.transform(paths))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use mockito::mock;
+    use mockito::{mock, Matcher};
     use std::fs;
 
     #[test]
@@ -134,6 +471,112 @@ mod tests {
         assert_eq!(response.state, "downloadable");
     }
 
+    #[test]
+    fn it_finds_boards_by_id() {
+        let body = fs::read_to_string(Path::new("src/support/create_board.json")).expect("Fixtures:");
+        let _m = mock("GET", "/swnoauod92ugkkhbj20190126151445")
+          .with_status(200)
+          .match_header("Authorization", "Bearer jwt-token")
+          .match_header("x-api-key", "1234")
+          .with_body(body)
+          .create();
+
+        let instance = BoardService::new("jwt-token".into(), "1234".into());
+        let board = instance.find("swnoauod92ugkkhbj20190126151445").unwrap();
+        assert_eq!(board.name, "xd");
+        assert_eq!(board.url, "https://we.tl/b-random-hash");
+    }
+
+    #[test]
+    fn it_mirrors_boards_incrementally() {
+        let body = format!(r#"{{"id": "mirrored-board", "name": "Client X", "state": "downloadable", "url": "https://we.tl/b-mirror", "items": [
+            {{"id": "link-1", "type": "link", "url": "https://wetransfer.com/", "meta": {{"title": "WeTransfer"}}}},
+            {{"id": "file-1", "type": "file", "name": "brief.txt", "size": 5, "download_url": "{}/mirror/brief.txt"}}]}}"#,
+            mockito::SERVER_URL);
+        let _find = mock("GET", "/mirrored-board").with_status(200).with_body(body.as_str()).create();
+        let download = mock("GET", "/mirror/brief.txt").with_status(200).with_body("brief").expect(1).create();
+
+        let directory = ::std::env::temp_dir().join("wetransfer-mirror");
+        let _ = fs::remove_dir_all(&directory);
+        let service = BoardService::new("jwt-token".into(), "1234".into());
+        let report = service.mirror("mirrored-board", &directory).unwrap();
+        assert_eq!(report.downloaded, vec![directory.join("brief.txt")]);
+        assert_eq!(report.links, 1);
+        assert_eq!(fs::read_to_string(directory.join("brief.txt")).unwrap(), "brief");
+        assert_eq!(fs::read_to_string(directory.join(".wetransfer/links.md")).unwrap(), "# Client X\n\n- [WeTransfer](https://wetransfer.com/)\n");
+        let snapshot: Board = serde_json::from_str(&fs::read_to_string(directory.join(".wetransfer/board.json")).unwrap()).unwrap();
+        assert_eq!(snapshot.id, "mirrored-board");
+
+        let report = service.mirror("mirrored-board", &directory).unwrap();
+        assert!(report.downloaded.is_empty());
+        assert_eq!(report.skipped, vec![directory.join("brief.txt")]);
+        download.assert();
+    }
+
+    #[test]
+    fn it_keeps_board_files_apart_from_mirror_metadata() {
+        let body = format!(r#"{{"id": "colliding-board", "name": "Client Y", "state": "downloadable", "url": "https://we.tl/b-collide", "items": [
+            {{"id": "file-1", "type": "file", "name": "board.json", "size": 2, "download_url": "{0}/collide/board.json"}},
+            {{"id": "file-2", "type": "file", "name": ".wetransfer", "size": 2, "download_url": "{0}/collide/meta"}}]}}"#,
+            mockito::SERVER_URL);
+        let _find = mock("GET", "/colliding-board").with_status(200).with_body(body.as_str()).create();
+
+        let directory = ::std::env::temp_dir().join("wetransfer-mirror-collision");
+        let _ = fs::remove_dir_all(&directory);
+        let service = BoardService::new("jwt-token".into(), "1234".into());
+        let error = service.mirror("colliding-board", &directory).unwrap_err();
+        assert_eq!(error.message, "Board file .wetransfer collides with the mirror metadata directory");
+        assert!(!directory.exists());
+
+        let body = format!(r#"{{"id": "compressed-board", "name": "Client Z", "state": "downloadable", "url": "https://we.tl/b-compressed", "items": [
+            {{"id": "file-1", "type": "file", "name": "export.csv", "size": 2, "download_url": "{0}/collide/export.csv"}},
+            {{"id": "file-2", "type": "file", "name": "export.csv.wtz.gz", "size": 2, "download_url": "{0}/collide/export.csv.wtz.gz"}}]}}"#,
+            mockito::SERVER_URL);
+        let _find = mock("GET", "/compressed-board").with_status(200).with_body(body.as_str()).create();
+        let error = service.mirror("compressed-board", &directory).unwrap_err();
+        assert_eq!(error.message, "Several files would be saved as export.csv");
+        assert!(!directory.exists());
+    }
+
+    #[test]
+    fn it_imports_links_in_batches() {
+        let board = r#"{"id": "import-board", "name": "Moodboard", "state": "downloadable", "url": "https://we.tl/b-import", "items": [
+            {"id": "link-1", "type": "link", "url": "https://wetransfer.com/", "meta": {"title": "WeTransfer"}}]}"#;
+        let _find = mock("GET", "/import-board").with_status(200).with_body(board).create();
+        let added = r#"[{"id": "new", "url": "https://example.com/", "meta": {"title": "Example"}, "type": "link"}]"#;
+        let batches = mock("POST", "/import-board/links").with_status(200).with_body(added).expect(2).create();
+
+        let links = ::import::from_markdown("[WeTransfer](https://wetransfer.com/#about) [A](https://a.com) [B](https://b.com) [C](https://c.com) [A](https://A.com/)");
+        let service = BoardService::new("jwt-token".into(), "1234".into());
+        let result = service.import_links("import-board", links, 2).unwrap();
+        assert_eq!(result.len(), 2);
+        batches.assert();
+    }
+
+    #[test]
+    fn it_keeps_links_imported_before_a_failing_batch() {
+        let board = r#"{"id": "partial-import", "name": "Moodboard", "state": "downloadable", "url": "https://we.tl/b-partial", "items": []}"#;
+        let _find = mock("GET", "/partial-import").with_status(200).with_body(board).create();
+        let added = r#"[{"id": "a", "url": "https://a.com/", "meta": {"title": "A"}, "type": "link"}]"#;
+        let _first = mock("POST", "/partial-import/links")
+          .match_body(Matcher::Regex("a\\.com".to_string()))
+          .with_status(200)
+          .with_body(added)
+          .create();
+        let _second = mock("POST", "/partial-import/links")
+          .match_body(Matcher::Regex("b\\.com".to_string()))
+          .with_status(500)
+          .with_body("{\"message\": \"Boom\"}")
+          .create();
+
+        let links = ::import::from_markdown("[A](https://a.com) [B](https://b.com)");
+        let service = BoardService::new("jwt-token".into(), "1234".into());
+        let error = service.import_links("partial-import", links, 1).unwrap_err();
+        assert_eq!(error.status, 500);
+        assert_eq!(error.imported.len(), 1);
+        assert_eq!(error.imported[0].id, "a");
+    }
+
     #[test]
     fn it_add_links() {
         let body = fs::read_to_string(Path::new("src/support/add_links.json")).expect("Fixtures:");
@@ -145,8 +588,7 @@ mod tests {
           .create();
 
         let instance = BoardService::new("jwt-token".into(), "1234".into());
-        let mut links: Vec<AddLink> = Vec::new();
-        links.push(AddLink { url: String::from("https://wetransfer.com"), title: String::from("WeTransfer")});
+        let links = vec![AddLink { url: String::from("https://wetransfer.com"), title: String::from("WeTransfer")}];
         let result = instance.add_links("id-board", &links);
         assert!(result.is_ok());
         let response = result.unwrap();
@@ -156,6 +598,32 @@ mod tests {
         assert_eq!(response[0].meta.title, String::from("WeTransfer"));
     }
 
+    #[test]
+    fn it_declares_transcoded_images() {
+        let directory = std::env::temp_dir().join("wetransfer-board-preset");
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("scan.png");
+        ::image::RgbImage::new(30, 10).save(&path).unwrap();
+        let broken = directory.join("broken.png");
+        fs::write(&broken, "not an image").unwrap();
+        let board_id = "5c7e8b39-1a2f-4d8e-9b3a-preset";
+        let _m = mock("POST", format!("/{}/files", board_id).as_str())
+          .with_status(400)
+          .match_body(Matcher::Regex("\"name\":\"scan.jpg\".*\"name\":\"broken.png\"".to_string()))
+          .with_body("{\"message\": \"Rejected.\", \"success\": false}")
+          .create();
+
+        let skipped = Arc::new(::std::sync::Mutex::new(Vec::new()));
+        let reported = skipped.clone();
+        let mut service = BoardService::new("jwt-token".into(), "1234".into());
+        service.on_skipped_image(move |image| reported.lock().unwrap().push(image.path.clone()));
+        assert_eq!(service.set_image_preset(Some("thumbnail")).unwrap_err().message, "Unknown image preset: thumbnail");
+        service.set_image_preset(Some("web-preview")).unwrap();
+        let error = service.add_files(board_id, &[path.to_str().unwrap(), broken.to_str().unwrap()]).unwrap_err();
+        assert_eq!(error.status, 400);
+        assert_eq!(*skipped.lock().unwrap(), vec![broken.to_string_lossy().into_owned()]);
+    }
+
     #[test]
     fn it_requests_s3_urls_for_uploading_parts() {
         let board_id = "041bae61-adb4-4ba2-80eb-48719396f0e3";
@@ -0,0 +1,65 @@
+use std::io::{self, Read};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// A cheap, cloneable flag used to stop an upload from another thread.
+/// It is checked between parts and while a part is being sent.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    /// Constructor.
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    /// Asks every upload holding a clone of this token to stop.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+/// A `Read` adapter which fails as soon as its token is cancelled,
+/// aborting the HTTP request it feeds.
+pub struct CancellableReader<R> {
+    inner: R,
+    token: CancellationToken,
+}
+
+impl<R: Read> CancellableReader<R> {
+    /// Constructor.
+    pub fn new(inner: R, token: CancellationToken) -> CancellableReader<R> {
+        CancellableReader { inner, token }
+    }
+}
+
+impl<R: Read> Read for CancellableReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.token.is_cancelled() {
+            return Err(io::Error::other("upload cancelled"));
+        }
+        self.inner.read(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn it_stops_reading_once_cancelled() {
+        let token = CancellationToken::new();
+        let mut reader = CancellableReader::new(Cursor::new(vec![1, 2, 3, 4]), token.clone());
+        let mut buffer = [0; 2];
+        assert_eq!(reader.read(&mut buffer).unwrap(), 2);
+        token.cancel();
+        assert!(reader.read(&mut buffer).is_err());
+    }
+}
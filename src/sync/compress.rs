@@ -0,0 +1,237 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use flate2::Compression;
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use zstd;
+
+use responses::WeTransferError;
+use fsutil::{io_error, TempDir};
+use sync::spool::TempCopies;
+
+// Formats which gain nothing from being compressed again.
+const ALREADY_COMPRESSED: &[&str] = &[
+    "gz", "tgz", "zst", "zip", "7z", "rar", "xz", "txz", "bz2", "tbz2", "lz", "lzma", "br",
+    "jpg", "jpeg", "png", "gif", "webp", "heic", "avif", "mp3", "aac", "m4a", "ogg", "flac", "opus",
+    "mp4", "m4v", "mov", "mkv", "webm", "avi", "pdf", "docx", "xlsx", "pptx", "odt", "ods", "odp",
+    "epub", "jar", "apk", "dmg",
+];
+
+// Leading bytes of the same formats, for files with a misleading name.
+const ALREADY_COMPRESSED_MAGIC: &[&[u8]] = &[
+    &[0x1F, 0x8B], &[0x28, 0xB5, 0x2F, 0xFD], b"PK\x03\x04", b"7z\xBC\xAF\x27\x1C", &[0xFD, b'7', b'z', b'X', b'Z', 0],
+    b"BZh", b"Rar!", &[0xFF, 0xD8, 0xFF], b"\x89PNG", b"%PDF",
+];
+
+// Marks the files compressed by this crate, so that downloads do not mistake
+// archives such as `backup.tar.gz` for them.
+const MARKER: &str = "wtz";
+
+/// How files are compressed. The codec is recorded in the uploaded name behind
+/// a `.wtz` marker, e.g. `export.csv.wtz.gz`, which is how downloads recognize it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Gzip,
+    Zstd,
+}
+
+impl Codec {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Codec::Gzip => "gz",
+            Codec::Zstd => "zst",
+        }
+    }
+
+    /// What compressed copies are named with, e.g. `.wtz.gz`.
+    pub fn suffix(self) -> String {
+        format!(".{}.{}", MARKER, self.extension())
+    }
+
+    /// The codec recorded in `name` by a `Compressor`, if any. Names merely
+    /// ending with `.gz` or `.zst` are not recognized.
+    pub fn from_name(name: &str) -> Option<Codec> {
+        let name = name.to_ascii_lowercase();
+        [Codec::Gzip, Codec::Zstd].iter().copied()
+            .find(|codec| name.ends_with(&codec.suffix()) && name.len() > codec.suffix().len())
+    }
+
+    /// `name` without the suffix of its codec, when it has one.
+    pub fn strip_suffix(name: &str) -> &str {
+        match Codec::from_name(name) {
+            Some(codec) => &name[..name.len() - codec.suffix().len()],
+            None => name,
+        }
+    }
+
+    /// Compresses `reader` into `writer` at `level`, returning how many bytes were read.
+    pub fn compress<R: Read, W: Write>(self, reader: &mut R, writer: W, level: i32) -> io::Result<u64> {
+        match self {
+            Codec::Gzip => {
+                let mut encoder = GzEncoder::new(writer, Compression::new(level.clamp(0, 9) as u32));
+                let size = io::copy(reader, &mut encoder)?;
+                encoder.finish()?.flush()?;
+                Ok(size)
+            },
+            Codec::Zstd => {
+                let mut encoder = zstd::Encoder::new(writer, level)?;
+                let size = io::copy(reader, &mut encoder)?;
+                encoder.finish()?.flush()?;
+                Ok(size)
+            },
+        }
+    }
+
+    /// Decompresses `reader` into `writer`, returning how many bytes were written.
+    pub fn decompress<R: Read, W: Write>(self, reader: R, writer: &mut W) -> io::Result<u64> {
+        match self {
+            Codec::Gzip => io::copy(&mut MultiGzDecoder::new(reader), writer),
+            Codec::Zstd => io::copy(&mut zstd::Decoder::new(reader)?, writer),
+        }
+    }
+}
+
+/// Compresses files before they are uploaded, so that text-like files such as
+/// CSV exports and logs take less time and less of the transfer quota. Only
+/// files with one of `extensions` are compressed, or every file when it is
+/// empty; files already compressed, smaller than `min_size`, or which would not
+/// shrink are uploaded as they are. Compressed copies live in the temp
+/// directory until the returned `TempCopies` are dropped.
+#[derive(Debug)]
+pub struct Compressor {
+    pub codec: Codec,
+    pub level: i32,
+    pub extensions: Vec<String>,
+    pub min_size: u64,
+}
+
+impl Compressor {
+    /// Compresses every file at the default level of `codec`.
+    pub fn new(codec: Codec) -> Compressor {
+        let level = match codec {
+            Codec::Gzip => 6,
+            Codec::Zstd => 3,
+        };
+        Compressor { codec, level, extensions: Vec::new(), min_size: 1024 }
+    }
+
+    /// Only compresses files with one of `extensions`, e.g. `&["csv", "log"]`.
+    pub fn only(mut self, extensions: &[&str]) -> Compressor {
+        self.extensions = extensions.iter().map(|extension| extension.to_ascii_lowercase()).collect();
+        self
+    }
+
+    /// Writes a compressed copy of the file at `path`, returning its path and
+    /// where it lives, or `None` when the file is uploaded as it is.
+    pub fn compress(&self, path: &str) -> Result<Option<(String, TempCopies)>, WeTransferError> {
+        let source = Path::new(path);
+        let extension = source.extension().map(|extension| extension.to_string_lossy().to_ascii_lowercase()).unwrap_or_default();
+        if ALREADY_COMPRESSED.contains(&extension.as_str()) || !(self.extensions.is_empty() || self.extensions.contains(&extension)) {
+            return Ok(None);
+        }
+        let original_size = fs::metadata(source).map_err(|error| io_error(source, error))?.len();
+        if original_size < self.min_size {
+            return Ok(None);
+        }
+        let mut file = File::open(source).map_err(|error| io_error(source, error))?;
+        let mut head = [0; 8];
+        let read = file.read(&mut head).map_err(|error| io_error(source, error))?;
+        if ALREADY_COMPRESSED_MAGIC.iter().any(|magic| head[..read].starts_with(magic)) {
+            return Ok(None);
+        }
+
+        let directory = TempDir::new("compress")?;
+        let name = source.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        let compressed = directory.path().join(format!("{}{}", name, self.codec.suffix()));
+        let output = File::create(&compressed).map_err(|error| io_error(&compressed, error))?;
+        let mut reader = BufReader::new((&head[..read]).chain(file));
+        self.codec.compress(&mut reader, BufWriter::new(output), self.level).map_err(|error| io_error(source, error))?;
+
+        let compressed_size = fs::metadata(&compressed).map_err(|error| io_error(&compressed, error))?.len();
+        if compressed_size >= original_size {
+            return Ok(None);
+        }
+        Ok(Some((compressed.to_string_lossy().into_owned(), TempCopies::new(vec![directory]))))
+    }
+
+    /// Compresses every file, returning the paths to upload in the same order
+    /// and the copies to keep until the upload is over.
+    pub fn compress_all<S: AsRef<str>>(&self, paths: &[S]) -> Result<(Vec<String>, TempCopies), WeTransferError> {
+        let mut copies = TempCopies::default();
+        let paths = paths.iter().map(|path| {
+            let path = path.as_ref();
+            Ok(match self.compress(path)? {
+                Some((compressed, copy)) => {
+                    copies.merge(copy);
+                    compressed
+                },
+                None => path.to_string(),
+            })
+        }).collect::<Result<Vec<String>, WeTransferError>>()?;
+        Ok((paths, copies))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn file(name: &str, contents: &[u8]) -> String {
+        let directory = env::temp_dir().join("wetransfer-compress");
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join(name);
+        fs::write(&path, contents).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn it_compresses_selected_files() {
+        let rows = "id,name,amount\n1,Jane,10\n".repeat(200);
+        let export = file("export.csv", rows.as_bytes());
+        let notes = file("notes.txt", rows.as_bytes());
+        let archive = file("export.csv.gz", &fs::read(&export).unwrap());
+        let tiny = file("tiny.csv", b"id\n1\n");
+
+        for codec in &[Codec::Gzip, Codec::Zstd] {
+            let compressor = Compressor::new(*codec).only(&["csv", "gz"]);
+            let (paths, copies) = compressor.compress_all(&[&export, &notes, &archive, &tiny]).unwrap();
+            assert!(paths[0].ends_with(&format!("export.csv.wtz.{}", codec.extension())));
+            assert!(fs::metadata(&paths[0]).unwrap().len() < rows.len() as u64 / 10);
+            assert_eq!(&paths[1..], &[notes.clone(), archive.clone(), tiny.clone()]);
+
+            let mut decompressed = Vec::new();
+            Codec::from_name(&paths[0]).unwrap().decompress(File::open(&paths[0]).unwrap(), &mut decompressed).unwrap();
+            assert_eq!(decompressed, rows.as_bytes());
+            drop(copies);
+            assert!(!Path::new(&paths[0]).exists());
+        }
+    }
+
+    #[test]
+    fn it_skips_files_which_are_already_compressed() {
+        let mut gzipped = Vec::new();
+        Codec::Gzip.compress(&mut "log line\n".repeat(500).as_bytes(), &mut gzipped, 6).unwrap();
+        let disguised = file("disguised.log", &gzipped);
+        let mut state = 0x2545_F491u32;
+        let random: Vec<u8> = (0..4096).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        }).collect();
+        let noise = file("noise.bin", &random);
+
+        let compressor = Compressor::new(Codec::Zstd);
+        assert_eq!(compressor.compress(&disguised).unwrap(), None);
+        assert_eq!(compressor.compress(&noise).unwrap(), None);
+        assert_eq!(Codec::from_name("report.CSV.WTZ.GZ"), Some(Codec::Gzip));
+        assert_eq!(Codec::strip_suffix("report.CSV.WTZ.GZ"), "report.CSV");
+        assert_eq!(Codec::from_name("report.csv"), None);
+        assert_eq!(Codec::from_name("backup.tar.gz"), None);
+        assert_eq!(Codec::from_name("dump.sql.zst"), None);
+        assert_eq!(Codec::strip_suffix("dump.sql.zst"), "dump.sql.zst");
+    }
+}
@@ -0,0 +1,472 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, TcpListener};
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use serde_json;
+
+use responses::WeTransferError;
+use fsutil::{io_error, random_name};
+use sync::Client;
+use sync::jobs::{Job, JobId, JobManager, JobResult, JobSnapshot, JobStatus};
+
+/// Requests larger than this are refused.
+const MAX_BODY: usize = 1024 * 1024;
+
+/// Serves a `JobManager` over a small REST API on localhost or a Unix socket:
+///
+/// - `POST /jobs` submits the `Job` in the body, e.g. `{"type": "transfer", "message": "", "paths": ["a.mov"]}`
+/// - `GET /jobs` and `GET /jobs/{id}` return the status, progress and result of jobs
+/// - `DELETE /jobs/{id}` cancels a job
+///
+/// Every request must carry `Authorization: Bearer <token>`, where the token is
+/// read from `<queue>.token`. The file is created, only readable by the current
+/// user, on the first start. Requests with an `Origin` header, or a `Host` other
+/// than a loopback address, are refused so that web pages cannot reach the API.
+///
+/// Jobs are saved to a queue file as they move forward. Jobs left unfinished
+/// when the daemon stopped are queued again on the next start, and pick up
+/// their uploads where they were.
+pub struct Daemon {
+    manager: Arc<JobManager>,
+    queue_path: PathBuf,
+    token: String,
+    // `true` once the daemon is dropped: cancelling the running jobs on
+    // shutdown must not be recorded, or they would not be run again.
+    stopped: Arc<Mutex<bool>>,
+    persister: Option<JoinHandle<()>>,
+}
+
+#[derive(Debug, Serialize)]
+/// How a job is shown by the API.
+struct JobView<'a> {
+    id: JobId,
+    job: &'a Job,
+    status: JobStatus,
+    completed_parts: u64,
+    total_parts: u64,
+    result: Option<&'a JobResult>,
+    error: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct Submitted {
+    id: JobId,
+}
+
+#[derive(Serialize)]
+struct Failure<'a> {
+    error: &'a str,
+}
+
+impl Daemon {
+    /// Starts `concurrency` workers and restores the jobs saved in `queue_path`, if it exists.
+    pub fn open<P: AsRef<Path>>(client: Arc<Client>, concurrency: usize, queue_path: P) -> Result<Daemon, WeTransferError> {
+        let queue_path = queue_path.as_ref().to_path_buf();
+        let token = load_token(&token_path(&queue_path))?;
+        let saved: Vec<JobSnapshot> = if queue_path.exists() {
+            let contents = fs::read_to_string(&queue_path).map_err(|error| io_error(&queue_path, error))?;
+            serde_json::from_str(&contents).map_err(|error| WeTransferError {
+                status: 0,
+                message: format!("Invalid job queue {}: {}", queue_path.display(), error),
+                ..Default::default()
+            })?
+        } else {
+            Vec::new()
+        };
+
+        let manager = Arc::new(JobManager::new(client, concurrency));
+        let events = manager.subscribe();
+        for snapshot in saved {
+            manager.restore(snapshot);
+        }
+        save(&manager, &queue_path)?;
+
+        let stopped = Arc::new(Mutex::new(false));
+        let persister = {
+            let manager = Arc::downgrade(&manager);
+            let queue_path = queue_path.clone();
+            let stopped = stopped.clone();
+            thread::spawn(move || {
+                while events.recv().is_ok() {
+                    let stopped = stopped.lock().unwrap();
+                    match manager.upgrade() {
+                        Some(ref manager) if !*stopped => { let _ = save(manager, &queue_path); },
+                        _ => return,
+                    }
+                }
+            })
+        };
+        Ok(Daemon { manager, queue_path, token, stopped, persister: Some(persister) })
+    }
+
+    pub fn manager(&self) -> &JobManager {
+        &self.manager
+    }
+
+    pub fn queue_path(&self) -> &Path {
+        &self.queue_path
+    }
+
+    /// The bearer token requests must carry.
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    /// Where the token is kept: the queue path followed by `.token`.
+    pub fn token_path(&self) -> PathBuf {
+        token_path(&self.queue_path)
+    }
+
+    /// Answers connections one at a time until the listener fails. Only
+    /// listeners bound to a loopback address are accepted.
+    pub fn serve_tcp(&self, listener: TcpListener) -> Result<(), WeTransferError> {
+        let address = listener.local_addr().map_err(daemon_error)?;
+        if !address.ip().is_loopback() {
+            return Err(WeTransferError {
+                status: 0,
+                message: format!("Refusing to serve the daemon on {}: only loopback addresses are allowed", address),
+                ..Default::default()
+            });
+        }
+        for stream in listener.incoming() {
+            let stream = stream.map_err(daemon_error)?;
+            let _ = stream.set_read_timeout(Some(Duration::from_secs(10)));
+            let _ = self.handle_connection(stream);
+        }
+        Ok(())
+    }
+
+    /// Answers connections one at a time until the listener fails. The socket
+    /// must be created with `0600` permissions, so that other users cannot connect.
+    #[cfg(unix)]
+    pub fn serve_unix(&self, listener: UnixListener) -> Result<(), WeTransferError> {
+        for stream in listener.incoming() {
+            let stream = stream.map_err(daemon_error)?;
+            let _ = stream.set_read_timeout(Some(Duration::from_secs(10)));
+            let _ = self.handle_connection(stream);
+        }
+        Ok(())
+    }
+
+    /// Reads one HTTP/1.1 request from `stream` and writes the response.
+    pub fn handle_connection<S: Read + Write>(&self, mut stream: S) -> io::Result<()> {
+        let (status, body) = {
+            let mut reader = BufReader::new(&mut stream);
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line)?;
+            let mut content_length = 0;
+            let (mut host, mut origin, mut authorization) = (None, false, None);
+            loop {
+                let mut header = String::new();
+                if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+                    break;
+                }
+                let mut parts = header.splitn(2, ':');
+                let name = parts.next().unwrap_or_default().trim();
+                let value = parts.next().unwrap_or_default().trim();
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.parse().unwrap_or(0);
+                } else if name.eq_ignore_ascii_case("host") {
+                    host = Some(value.to_string());
+                } else if name.eq_ignore_ascii_case("origin") {
+                    origin = true;
+                } else if name.eq_ignore_ascii_case("authorization") {
+                    authorization = Some(value.to_string());
+                }
+            }
+            let mut parts = request_line.split_whitespace();
+            match (parts.next(), parts.next()) {
+                (Some(_), Some(_)) if origin => failure(403, "Requests from web pages are not allowed"),
+                (Some(_), Some(_)) if !host.as_deref().is_some_and(is_loopback_host) => failure(403, "The Host header must name a loopback address"),
+                (Some(_), Some(_)) if !self.is_authorized(authorization.as_deref()) => failure(401, "Missing or invalid bearer token"),
+                (Some(_), Some(_)) if content_length > MAX_BODY => failure(413, "The request body is too large"),
+                (Some(method), Some(path)) => {
+                    let mut body = vec![0; content_length];
+                    reader.read_exact(&mut body)?;
+                    self.handle_request(method, path, &String::from_utf8_lossy(&body))
+                },
+                _ => failure(400, "Malformed request"),
+            }
+        };
+        write!(stream, "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+               status, reason(status), body.len(), body)?;
+        stream.flush()
+    }
+
+    fn is_authorized(&self, authorization: Option<&str>) -> bool {
+        let token = match authorization.and_then(|authorization| authorization.strip_prefix("Bearer ")) {
+            Some(token) => token.trim().as_bytes(),
+            None => return false,
+        };
+        let expected = self.token.as_bytes();
+        // Compared in constant time, so the token cannot be guessed byte by byte.
+        token.len() == expected.len() && token.iter().zip(expected).fold(0, |difference, (a, b)| difference | (a ^ b)) == 0
+    }
+
+    /// Routes a request to the job manager, returning the status code and JSON body of the response.
+    pub fn handle_request(&self, method: &str, path: &str, body: &str) -> (u16, String) {
+        let path = path.split('?').next().unwrap_or_default().trim_end_matches('/');
+        let id = match path.strip_prefix("/jobs/") {
+            Some(id) => match id.parse::<JobId>() {
+                Ok(id) => Some(id),
+                Err(_) => return failure(404, "Not found"),
+            },
+            None if path == "/jobs" => None,
+            None => return failure(404, "Not found"),
+        };
+        match (method, id) {
+            ("GET", None) => {
+                let jobs = self.manager.jobs();
+                let views: Vec<JobView> = jobs.iter().map(view).collect();
+                (200, serde_json::to_string(&views).unwrap())
+            },
+            ("POST", None) => match serde_json::from_str::<Job>(body) {
+                Ok(job) => (201, serde_json::to_string(&Submitted { id: self.manager.submit(job) }).unwrap()),
+                Err(error) => failure(400, &format!("Invalid job: {}", error)),
+            },
+            ("GET", Some(id)) => match self.manager.status(id) {
+                Some(snapshot) => (200, serde_json::to_string(&view(&snapshot)).unwrap()),
+                None => failure(404, "No such job"),
+            },
+            ("DELETE", Some(id)) => match self.manager.status(id) {
+                None => failure(404, "No such job"),
+                Some(_) if self.manager.cancel(id) => (202, serde_json::to_string(&view(&self.manager.status(id).unwrap())).unwrap()),
+                Some(_) => failure(409, "The job is already finished"),
+            },
+            _ => failure(405, "Method not allowed"),
+        }
+    }
+}
+
+impl Drop for Daemon {
+    /// Saves the queue one last time, then stops the workers.
+    fn drop(&mut self) {
+        let mut stopped = self.stopped.lock().unwrap();
+        let _ = save(&self.manager, &self.queue_path);
+        *stopped = true;
+        drop(stopped);
+        self.persister.take();
+    }
+}
+
+fn view(snapshot: &JobSnapshot) -> JobView<'_> {
+    let progress = snapshot.progress.as_ref();
+    JobView {
+        id: snapshot.id,
+        job: &snapshot.job,
+        status: snapshot.status,
+        completed_parts: progress.map(|progress| progress.completed_parts()).unwrap_or(0),
+        total_parts: progress.map(|progress| progress.total_parts()).unwrap_or(0),
+        result: snapshot.result.as_ref(),
+        error: snapshot.error.as_ref().map(|error| error.message.as_str()),
+    }
+}
+
+fn token_path(queue_path: &Path) -> PathBuf {
+    let mut path = queue_path.as_os_str().to_os_string();
+    path.push(".token");
+    PathBuf::from(path)
+}
+
+// Reads the token at `path`, or creates it with a new random token.
+fn load_token(path: &Path) -> Result<String, WeTransferError> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    match options.open(path) {
+        Ok(mut file) => {
+            let token = format!("{}{}", random_name(), random_name());
+            file.write_all(token.as_bytes()).map_err(|error| io_error(path, error))?;
+            return Ok(token);
+        },
+        Err(ref error) if error.kind() == io::ErrorKind::AlreadyExists => {},
+        Err(error) => return Err(io_error(path, error)),
+    }
+    #[cfg(unix)]
+    {
+        let mode = fs::metadata(path).map_err(|error| io_error(path, error))?.permissions().mode();
+        if mode & 0o077 != 0 {
+            return Err(WeTransferError {
+                status: 0,
+                message: format!("The token file {} must only be accessible to its owner", path.display()),
+                ..Default::default()
+            });
+        }
+    }
+    let token = fs::read_to_string(path).map_err(|error| io_error(path, error))?.trim().to_string();
+    if token.is_empty() {
+        return Err(WeTransferError { status: 0, message: format!("The token file {} is empty", path.display()), ..Default::default() });
+    }
+    Ok(token)
+}
+
+// `localhost`, `127.0.0.1` or `[::1]`, with or without a port.
+fn is_loopback_host(host: &str) -> bool {
+    let name = match host.strip_prefix('[') {
+        Some(bracketed) => bracketed.split(']').next().unwrap_or_default(),
+        None => host.split(':').next().unwrap_or_default(),
+    };
+    name.eq_ignore_ascii_case("localhost") || name.parse::<IpAddr>().map(|ip| ip.is_loopback()).unwrap_or(false)
+}
+
+// Written next to the queue file first, so a crash never leaves it half written.
+fn save(manager: &JobManager, queue_path: &Path) -> Result<(), WeTransferError> {
+    let mut temporary = queue_path.as_os_str().to_os_string();
+    temporary.push(".tmp");
+    let contents = serde_json::to_string_pretty(&manager.jobs()).unwrap();
+    fs::write(&temporary, contents).map_err(|error| io_error(queue_path, error))?;
+    fs::rename(&temporary, queue_path).map_err(|error| io_error(queue_path, error))
+}
+
+fn failure(status: u16, message: &str) -> (u16, String) {
+    (status, serde_json::to_string(&Failure { error: message }).unwrap())
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        _ => "Internal Server Error",
+    }
+}
+
+fn daemon_error(error: io::Error) -> WeTransferError {
+    WeTransferError { status: 0, message: error.to_string(), ..Default::default() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::mock;
+    use std::env;
+    use std::net::TcpStream;
+
+    fn client() -> Arc<Client> {
+        let _m = mock("POST", "/")
+          .with_status(200)
+          .with_body("{\"token\": \"jwt_token\", \"success\": true}")
+          .create();
+        Arc::new(Client::new("1234").unwrap())
+    }
+
+    fn queue_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(name);
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(token_path(&path));
+        path
+    }
+
+    fn send(address: ::std::net::SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn it_routes_requests_to_the_job_manager() {
+        let _files = mock("POST", "/daemon-board/files")
+          .with_status(404)
+          .with_body("{\"success\": false, \"message\": \"Board not found\"}")
+          .create();
+        let path = queue_path("wetransfer-daemon-routes.json");
+        let daemon = Daemon::open(client(), 1, &path).unwrap();
+
+        let (status, body) = daemon.handle_request("POST", "/jobs", r#"{"type": "board_upload", "board_id": "daemon-board", "paths": ["Cargo.toml"]}"#);
+        assert_eq!((status, body.as_str()), (201, r#"{"id":1}"#));
+        assert_eq!(daemon.manager().wait(1).unwrap().status, JobStatus::Failed);
+
+        let (status, body) = daemon.handle_request("GET", "/jobs/1", "");
+        assert_eq!(status, 200);
+        assert!(body.contains(r#""status":"failed""#));
+        assert!(body.contains(r#""error":"Board not found""#));
+        assert_eq!(daemon.handle_request("GET", "/jobs", "").1, format!("[{}]", body));
+        assert_eq!(daemon.handle_request("DELETE", "/jobs/1", "").0, 409);
+        assert_eq!(daemon.handle_request("GET", "/jobs/2", "").0, 404);
+        assert_eq!(daemon.handle_request("POST", "/jobs", "{\"type\": \"unknown\"}").0, 400);
+        assert_eq!(daemon.handle_request("PUT", "/jobs", "").0, 405);
+    }
+
+    #[test]
+    fn it_restores_unfinished_jobs_from_the_queue_file() {
+        let path = queue_path("wetransfer-daemon-restore.json");
+        let saved = r#"[
+            {"id": 3, "job": {"type": "transfer", "message": "foo", "paths": ["Cargo.toml"]}, "status": "paused", "progress": null, "result": null, "error": null},
+            {"id": 4, "job": {"type": "board_upload", "board_id": "b", "paths": []}, "status": "cancelled", "progress": null, "result": null, "error": null}
+        ]"#;
+        fs::write(&path, saved).unwrap();
+        {
+            let daemon = Daemon::open(client(), 1, &path).unwrap();
+            assert_eq!(daemon.manager().status(3).unwrap().status, JobStatus::Paused);
+            assert_eq!(daemon.manager().status(4).unwrap().status, JobStatus::Cancelled);
+            let (status, _) = daemon.handle_request("DELETE", "/jobs/3", "");
+            assert_eq!(status, 202);
+            assert_eq!(daemon.manager().wait(3).unwrap().status, JobStatus::Cancelled);
+        }
+        let reopened = Daemon::open(client(), 1, &path).unwrap();
+        assert_eq!(reopened.manager().status(3).unwrap().status, JobStatus::Cancelled);
+        assert!(fs::read_to_string(&path).unwrap().contains("\"board_id\": \"b\""));
+    }
+
+    #[test]
+    fn it_serves_http_on_loopback_only() {
+        let daemon = Arc::new(Daemon::open(client(), 1, queue_path("wetransfer-daemon-http.json")).unwrap());
+        assert!(daemon.serve_tcp(TcpListener::bind("0.0.0.0:0").unwrap()).is_err());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = daemon.clone();
+        thread::spawn(move || server.serve_tcp(listener));
+
+        let request = format!("GET /jobs HTTP/1.1\r\nHost: localhost:7331\r\nAuthorization: Bearer {}\r\n\r\n", daemon.token());
+        let response = send(address, &request);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\n[]"));
+    }
+
+    #[test]
+    fn it_requires_a_token_and_a_loopback_host() {
+        let path = queue_path("wetransfer-daemon-auth.json");
+        let daemon = Arc::new(Daemon::open(client(), 1, &path).unwrap());
+        assert_eq!(daemon.token().len(), 32);
+        assert_eq!(fs::read_to_string(daemon.token_path()).unwrap(), daemon.token());
+        #[cfg(unix)]
+        assert_eq!(fs::metadata(daemon.token_path()).unwrap().permissions().mode() & 0o777, 0o600);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = daemon.clone();
+        thread::spawn(move || server.serve_tcp(listener));
+
+        let authorization = format!("Authorization: Bearer {}\r\n", daemon.token());
+        let job = r#"{"type": "transfer", "message": "", "paths": ["Cargo.toml"]}"#;
+        let post = |headers: &str| send(address, &format!("POST /jobs HTTP/1.1\r\n{}Content-Length: {}\r\n\r\n{}", headers, job.len(), job));
+        assert!(post("Host: localhost\r\n").starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+        assert!(post("Host: localhost\r\nAuthorization: Bearer wrong\r\n").starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+        assert!(post(&format!("Host: attacker.example:7331\r\n{}", authorization)).starts_with("HTTP/1.1 403 Forbidden\r\n"));
+        assert!(post(&authorization).starts_with("HTTP/1.1 403 Forbidden\r\n"));
+        assert!(post(&format!("Host: [::1]:7331\r\nOrigin: http://localhost\r\n{}", authorization)).starts_with("HTTP/1.1 403 Forbidden\r\n"));
+        assert!(daemon.manager().jobs().is_empty());
+
+        let reopened = Daemon::open(client(), 1, &path).unwrap();
+        assert_eq!(reopened.token(), daemon.token());
+    }
+}
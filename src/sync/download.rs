@@ -0,0 +1,469 @@
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+use reqwest;
+use reqwest::StatusCode;
+use reqwest::header::RANGE;
+
+use responses::*;
+use fsutil::io_error;
+use sync::compress::Codec;
+
+/// A file to download: what to call it, how large it must be and where it is served.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DownloadItem {
+    pub name: String,
+    pub size: u64,
+    pub url: String,
+}
+
+impl DownloadItem {
+    /// Lists the files of a transfer. Every file needs a `download_url`.
+    pub fn from_transfer(transfer: &Transfer) -> Result<Vec<DownloadItem>, WeTransferError> {
+        transfer.files.iter().map(|file| match file.download_url {
+            Some(ref url) => Ok(DownloadItem { name: file.name.clone(), size: file.size as u64, url: url.clone() }),
+            None => Err(no_url(&file.name)),
+        }).collect()
+    }
+
+    /// Lists the files of a board, leaving its links aside. Every file needs a `download_url`.
+    pub fn from_board(board: &Board) -> Result<Vec<DownloadItem>, WeTransferError> {
+        board.items.iter()
+            .filter(|item| item["type"] == "file")
+            .map(|item| {
+                let name = item["name"].as_str().unwrap_or_default().to_string();
+                match item["download_url"].as_str() {
+                    Some(url) => Ok(DownloadItem { size: item["size"].as_u64().unwrap_or_default(), url: url.to_string(), name }),
+                    None => Err(no_url(&name)),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Fetches the files of transfers and boards. Downloads into a directory go
+/// through a `.part` file, which is resumed with a range request when found.
+/// Files compressed by a `Compressor`, e.g. `export.csv.wtz.gz`, are
+/// decompressed unless `keep_compressed` is used; other `.gz` and `.zst` files,
+/// such as `backup.tar.gz`, are saved as they are. Decompressing stops with an
+/// error past `max_decompressed_size` bytes.
+#[derive(Debug)]
+pub struct Downloader {
+    http_client: reqwest::Client,
+    decompress: bool,
+    max_decompressed_size: u64,
+}
+
+impl Downloader {
+    /// Constructor.
+    pub fn new() -> Downloader {
+        Downloader { http_client: reqwest::Client::new(), decompress: true, max_decompressed_size: 16 * 1024 * 1024 * 1024 }
+    }
+
+    /// Leaves compressed files as they were uploaded.
+    pub fn keep_compressed(mut self) -> Downloader {
+        self.decompress = false;
+        self
+    }
+
+    /// How large a decompressed file may get, 16 GiB by default.
+    pub fn max_decompressed_size(mut self, size: u64) -> Downloader {
+        self.max_decompressed_size = size;
+        self
+    }
+
+    /// The name `item` is saved under: its name without the codec suffix when it is decompressed.
+    pub fn local_name(&self, item: &DownloadItem) -> String {
+        match self.codec(item) {
+            Some(_) => Codec::strip_suffix(&item.name).to_string(),
+            None => item.name.clone(),
+        }
+    }
+
+    /// Checks that every name of `items` is safe, and that no two of them are
+    /// saved under the same name, e.g. `export.csv` and `export.csv.wtz.gz`
+    /// once decompressed.
+    pub fn check_names(&self, items: &[DownloadItem]) -> Result<(), WeTransferError> {
+        let mut names = HashSet::new();
+        for item in items {
+            check_name(&item.name)?;
+            let local_name = self.local_name(item);
+            if !names.insert(local_name.clone()) {
+                return Err(WeTransferError {
+                    status: 0,
+                    message: format!("Several files would be saved as {}", local_name),
+                    ..Default::default()
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `item` is already complete in `directory`: a file of the same size, or
+    /// the decompressed copy of a file which is no longer there. Decompressed files
+    /// are only moved into place once complete and flushed to disk, and the
+    /// compressed file is only removed after that.
+    pub fn is_downloaded(&self, item: &DownloadItem, directory: &Path) -> bool {
+        let path = directory.join(&item.name);
+        match self.codec(item) {
+            Some(_) => directory.join(self.local_name(item)).exists() && !path.exists() && !directory.join(format!("{}.part", item.name)).exists(),
+            None => fs::metadata(&path).map(|metadata| metadata.len() == item.size).unwrap_or(false),
+        }
+    }
+
+    /// Downloads every file of `transfer` into `directory`, returning their paths.
+    pub fn download_transfer<P: AsRef<Path>>(&self, transfer: &Transfer, directory: P) -> Result<Vec<PathBuf>, WeTransferError> {
+        self.download_all(&DownloadItem::from_transfer(transfer)?, directory)
+    }
+
+    /// Downloads every file of `board` into `directory`, returning their paths.
+    pub fn download_board<P: AsRef<Path>>(&self, board: &Board, directory: P) -> Result<Vec<PathBuf>, WeTransferError> {
+        self.download_all(&DownloadItem::from_board(board)?, directory)
+    }
+
+    /// Downloads `items` into `directory`. Names are checked before anything is
+    /// written, so that none of them can escape `directory` or overwrite another.
+    pub fn download_all<P: AsRef<Path>>(&self, items: &[DownloadItem], directory: P) -> Result<Vec<PathBuf>, WeTransferError> {
+        self.check_names(items)?;
+        fs::create_dir_all(directory.as_ref()).map_err(|error| io_error(directory.as_ref(), error))?;
+        items.iter().map(|item| self.download_file(item, directory.as_ref())).collect()
+    }
+
+    /// Downloads one file into `directory`. A complete file already there is kept as is.
+    pub fn download_file(&self, item: &DownloadItem, directory: &Path) -> Result<PathBuf, WeTransferError> {
+        check_name(&item.name)?;
+        let path = directory.join(self.local_name(item));
+        if self.is_downloaded(item, directory) {
+            return Ok(path);
+        }
+        let compressed = self.fetch(item, directory)?;
+        if let Some(codec) = self.codec(item) {
+            // A file which fails to decompress stays, so that it is not fetched again.
+            let partial = directory.join(format!("{}.part", self.local_name(item)));
+            let source = fs::File::open(&compressed).map_err(|error| io_error(&compressed, error))?;
+            let file = fs::File::create(&partial).map_err(|error| io_error(&partial, error))?;
+            let mut file = Limited { inner: file, remaining: self.max_decompressed_size };
+            if let Err(error) = codec.decompress(source, &mut file).and_then(|_| file.inner.sync_all()) {
+                let _ = fs::remove_file(&partial);
+                return Err(WeTransferError {
+                    status: 0,
+                    message: format!("Error while decompressing {}: {}", item.name, error),
+                    ..Default::default()
+                });
+            }
+            fs::rename(&partial, &path).map_err(|error| io_error(&path, error))?;
+            fs::remove_file(&compressed).map_err(|error| io_error(&compressed, error))?;
+        }
+        Ok(path)
+    }
+
+    // Downloads `item` as it was uploaded, resuming a previous attempt.
+    fn fetch(&self, item: &DownloadItem, directory: &Path) -> Result<PathBuf, WeTransferError> {
+        let path = directory.join(&item.name);
+        if fs::metadata(&path).map(|metadata| metadata.len() == item.size).unwrap_or(false) {
+            return Ok(path);
+        }
+        let partial = directory.join(format!("{}.part", item.name));
+        let mut offset = fs::metadata(&partial).map(|metadata| metadata.len()).unwrap_or(0);
+        // Leftovers larger than the file cannot be trusted.
+        if offset > item.size {
+            offset = 0;
+        }
+        if offset < item.size {
+            let mut response = self.get(item, offset)?;
+            if response.status() != StatusCode::PARTIAL_CONTENT {
+                // The server ignored the range and sends the whole file.
+                offset = 0;
+            }
+            let mut file = OpenOptions::new().create(true).write(true).truncate(offset == 0).append(offset > 0).open(&partial)
+                .map_err(|error| io_error(&partial, error))?;
+            response.copy_to(&mut file).map_err(|error| WeTransferError {
+                status: 0,
+                message: format!("Error while downloading {}: {}", item.name, error),
+                ..Default::default()
+            })?;
+        }
+        let size = fs::metadata(&partial).map_err(|error| io_error(&partial, error))?.len();
+        if size != item.size {
+            return Err(size_mismatch(item, size));
+        }
+        fs::rename(&partial, &path).map_err(|error| io_error(&path, error))?;
+        Ok(path)
+    }
+
+    /// Downloads one file into `sink`, decompressed when it is compressed, returning
+    /// how many bytes were written.
+    pub fn download_to<W: Write>(&self, item: &DownloadItem, sink: &mut W) -> Result<u64, WeTransferError> {
+        let mut response = Counted { inner: self.get(item, 0)?, count: 0 };
+        let written = match self.codec(item) {
+            // Whatever follows the compressed data still counts towards the size.
+            Some(codec) => {
+                let mut sink = Limited { inner: sink, remaining: self.max_decompressed_size };
+                codec.decompress(&mut response, &mut sink).and_then(|written| io::copy(&mut response, &mut io::sink()).map(|_| written))
+            },
+            None => io::copy(&mut response, sink),
+        };
+        let written = written.map_err(|error| WeTransferError {
+            status: 0,
+            message: format!("Error while downloading {}: {}", item.name, error),
+            ..Default::default()
+        })?;
+        if response.count != item.size {
+            return Err(size_mismatch(item, response.count));
+        }
+        Ok(written)
+    }
+
+    fn codec(&self, item: &DownloadItem) -> Option<Codec> {
+        if self.decompress {
+            Codec::from_name(&item.name)
+        } else {
+            None
+        }
+    }
+
+    fn get(&self, item: &DownloadItem, offset: u64) -> Result<reqwest::Response, WeTransferError> {
+        let mut request = self.http_client.get(item.url.as_str());
+        if offset > 0 {
+            request = request.header(RANGE, format!("bytes={}-", offset));
+        }
+        let response = request.send().map_err(|error| WeTransferError {
+            status: 0,
+            message: format!("Error while downloading {}: {}", item.name, error),
+            ..Default::default()
+        })?;
+        if !response.status().is_success() {
+            return Err(WeTransferError {
+                status: response.status().as_u16(),
+                message: format!("Error while downloading {}: {}", item.name, response.status()),
+                ..Default::default()
+            });
+        }
+        Ok(response)
+    }
+}
+
+impl Default for Downloader {
+    fn default() -> Downloader {
+        Downloader::new()
+    }
+}
+
+// Counts the bytes read from a response.
+struct Counted<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: Read> Read for Counted<R> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buffer)?;
+        self.count += read as u64;
+        Ok(read)
+    }
+}
+
+// Refuses to write more than `remaining` bytes.
+struct Limited<W> {
+    inner: W,
+    remaining: u64,
+}
+
+impl<W: Write> Write for Limited<W> {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        if buffer.len() as u64 > self.remaining {
+            return Err(io::Error::other("larger than the maximum decompressed size"));
+        }
+        let written = self.inner.write(buffer)?;
+        self.remaining -= written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Only plain file names are accepted: no separators, no `..`, nothing absolute.
+fn check_name(name: &str) -> Result<(), WeTransferError> {
+    let mut components = Path::new(name).components();
+    let plain = match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => !name.contains(['/', '\\', '\0']),
+        _ => false,
+    };
+    if plain {
+        Ok(())
+    } else {
+        Err(WeTransferError {
+            status: 0,
+            message: format!("Refusing to download to unsafe file name \"{}\"", name),
+            ..Default::default()
+        })
+    }
+}
+
+fn no_url(name: &str) -> WeTransferError {
+    WeTransferError {
+        status: 0,
+        message: format!("No download URL for {}", name),
+        ..Default::default()
+    }
+}
+
+fn size_mismatch(item: &DownloadItem, size: u64) -> WeTransferError {
+    WeTransferError {
+        status: 0,
+        message: format!("Downloaded {} bytes of {}, expected {}", size, item.name, item.size),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::mock;
+    use std::env;
+
+    fn item(name: &str, size: u64, path: &str) -> DownloadItem {
+        DownloadItem { name: name.to_string(), size, url: format!("{}{}", mockito::SERVER_URL, path) }
+    }
+
+    fn directory(name: &str) -> PathBuf {
+        let directory = env::temp_dir().join(format!("wetransfer-download-{}", name));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    #[test]
+    fn it_downloads_files_into_directories() {
+        let _m = mock("GET", "/download/notes.txt").with_status(200).with_body("hello world").create();
+
+        let directory = directory("complete");
+        let path = Downloader::new().download_file(&item("notes.txt", 11, "/download/notes.txt"), &directory).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "hello world");
+        assert!(!directory.join("notes.txt.part").exists());
+    }
+
+    #[test]
+    fn it_resumes_interrupted_downloads() {
+        let _m = mock("GET", "/download/resumed.txt")
+          .match_header("Range", "bytes=6-")
+          .with_status(206)
+          .with_body("world")
+          .create();
+
+        let directory = directory("resumed");
+        fs::write(directory.join("resumed.txt.part"), "hello ").unwrap();
+        let path = Downloader::new().download_file(&item("resumed.txt", 11, "/download/resumed.txt"), &directory).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "hello world");
+    }
+
+    #[test]
+    fn it_verifies_sizes() {
+        let _m = mock("GET", "/download/truncated.txt").with_status(200).with_body("hello").create();
+
+        let directory = directory("truncated");
+        let error = Downloader::new().download_file(&item("truncated.txt", 11, "/download/truncated.txt"), &directory).unwrap_err();
+        assert_eq!(error.message, "Downloaded 5 bytes of truncated.txt, expected 11");
+        assert!(!directory.join("truncated.txt").exists());
+
+        let mut sink = Vec::new();
+        assert!(Downloader::new().download_to(&item("truncated.txt", 11, "/download/truncated.txt"), &mut sink).is_err());
+        assert_eq!(sink, b"hello");
+    }
+
+    #[test]
+    fn it_decompresses_compressed_files() {
+        let mut compressed = Vec::new();
+        Codec::Zstd.compress(&mut "id,amount\n1,10\n".as_bytes(), &mut compressed, 3).unwrap();
+        let _m = mock("GET", "/download/export.csv.wtz.zst").with_status(200).with_body(&compressed).create();
+        let export = item("export.csv.wtz.zst", compressed.len() as u64, "/download/export.csv.wtz.zst");
+
+        let directory = directory("decompressed");
+        let path = Downloader::new().download_file(&export, &directory).unwrap();
+        assert_eq!(path, directory.join("export.csv"));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "id,amount\n1,10\n");
+        assert!(!directory.join("export.csv.wtz.zst").exists());
+        assert!(Downloader::new().is_downloaded(&export, &directory));
+
+        // Interrupted after the download: the partly decompressed file is made again.
+        fs::write(&path, "id,amo").unwrap();
+        fs::write(directory.join("export.csv.wtz.zst"), &compressed).unwrap();
+        assert!(!Downloader::new().is_downloaded(&export, &directory));
+        Downloader::new().download_file(&export, &directory).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "id,amount\n1,10\n");
+
+        let mut sink = Vec::new();
+        assert_eq!(Downloader::new().download_to(&export, &mut sink).unwrap(), 15);
+        assert_eq!(sink, b"id,amount\n1,10\n");
+        let path = Downloader::new().keep_compressed().download_file(&export, &directory).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), compressed);
+    }
+
+    #[test]
+    fn it_keeps_other_compressed_files_as_they_are() {
+        let mut compressed = Vec::new();
+        Codec::Gzip.compress(&mut "CREATE TABLE t;\n".as_bytes(), &mut compressed, 6).unwrap();
+        let _m = mock("GET", "/download/dump.sql.gz").with_status(200).with_body(&compressed).create();
+        let dump = item("dump.sql.gz", compressed.len() as u64, "/download/dump.sql.gz");
+
+        let directory = directory("archives");
+        let path = Downloader::new().download_file(&dump, &directory).unwrap();
+        assert_eq!(path, directory.join("dump.sql.gz"));
+        assert_eq!(fs::read(&path).unwrap(), compressed);
+    }
+
+    #[test]
+    fn it_limits_decompressed_sizes() {
+        let rows = "id,amount\n1,10\n".repeat(100);
+        let mut compressed = Vec::new();
+        Codec::Gzip.compress(&mut rows.as_bytes(), &mut compressed, 6).unwrap();
+        let _m = mock("GET", "/download/bomb.csv.wtz.gz").with_status(200).with_body(&compressed).create();
+        let bomb = item("bomb.csv.wtz.gz", compressed.len() as u64, "/download/bomb.csv.wtz.gz");
+
+        let directory = directory("limited");
+        let downloader = Downloader::new().max_decompressed_size(1000);
+        let error = downloader.download_file(&bomb, &directory).unwrap_err();
+        assert!(error.message.starts_with("Error while decompressing bomb.csv.wtz.gz"));
+        assert!(!directory.join("bomb.csv").exists());
+        assert!(!directory.join("bomb.csv.part").exists());
+        assert!(downloader.download_to(&bomb, &mut Vec::new()).is_err());
+        assert_eq!(Downloader::new().max_decompressed_size(rows.len() as u64).download_to(&bomb, &mut Vec::new()).unwrap(), rows.len() as u64);
+    }
+
+    #[test]
+    fn it_refuses_files_saved_under_the_same_name() {
+        let items = [item("export.csv", 1, "/never"), item("export.csv.wtz.gz", 1, "/never")];
+        let error = Downloader::new().download_all(&items, directory("collisions")).unwrap_err();
+        assert_eq!(error.message, "Several files would be saved as export.csv");
+        assert!(Downloader::new().keep_compressed().check_names(&items).is_ok());
+    }
+
+    #[test]
+    fn it_refuses_unsafe_file_names() {
+        for name in &["../escape.txt", "/etc/passwd", "nested/file.txt", "..", "", "back\\slash.txt", "trailing/."] {
+            let error = Downloader::new().download_all(&[item(name, 1, "/never")], env::temp_dir()).unwrap_err();
+            assert!(error.message.starts_with("Refusing to download to unsafe file name"), "{}", name);
+        }
+    }
+
+    #[test]
+    fn it_lists_files_of_transfers_and_boards() {
+        let transfer = Transfer {
+            files: vec![File { name: String::from("a.txt"), size: 3, download_url: Some(String::from("https://s3/a")), ..Default::default() }],
+            ..Default::default()
+        };
+        assert_eq!(DownloadItem::from_transfer(&transfer).unwrap(), vec![DownloadItem {
+            name: String::from("a.txt"), size: 3, url: String::from("https://s3/a"),
+        }]);
+
+        let board: Board = ::serde_json::from_str(r#"{"id": "b", "name": "n", "state": "downloadable", "url": "u", "items": [
+            {"type": "link", "url": "https://wetransfer.com"},
+            {"type": "file", "name": "b.txt", "size": 4, "download_url": "https://s3/b"},
+            {"type": "file", "name": "c.txt", "size": 5}]}"#).unwrap();
+        assert_eq!(DownloadItem::from_board(&board).unwrap_err().message, "No download URL for c.txt");
+    }
+}
@@ -0,0 +1,164 @@
+use std::io::Read;
+use std::time::Duration;
+
+use reqwest;
+use reqwest::{RedirectPolicy, Url};
+use reqwest::header::CONTENT_TYPE;
+
+use import::{attribute, decode_entities};
+use requests::AddLink;
+use responses::WeTransferError;
+
+/// Bounds the requests made to find link titles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EnrichOptions {
+    /// Applies to the whole request, redirects included.
+    pub timeout: Duration,
+    /// Pages are read up to this size; titles further down are not found.
+    pub max_bytes: u64,
+    pub max_redirects: usize,
+}
+
+impl Default for EnrichOptions {
+    fn default() -> EnrichOptions {
+        EnrichOptions { timeout: Duration::from_secs(5), max_bytes: 512 * 1024, max_redirects: 5 }
+    }
+}
+
+/// Fills in the titles of links whose title is missing, or is just their url,
+/// from the pages they point to.
+#[derive(Debug)]
+pub struct LinkEnricher {
+    http_client: reqwest::Client,
+    options: EnrichOptions,
+}
+
+impl LinkEnricher {
+    /// Constructor.
+    pub fn new(options: EnrichOptions) -> Result<LinkEnricher, WeTransferError> {
+        let http_client = reqwest::Client::builder()
+            .timeout(options.timeout)
+            .redirect(RedirectPolicy::limited(options.max_redirects))
+            .build()
+            .map_err(|error| WeTransferError { status: 0, message: error.to_string(), ..Default::default() })?;
+        Ok(LinkEnricher { http_client, options })
+    }
+
+    /// Returns `links` with a title for each of them. Links already titled are left alone.
+    pub fn enrich(&self, links: &[AddLink]) -> Vec<AddLink> {
+        links.iter().map(|link| {
+            let title = link.title.trim();
+            if title.is_empty() || title == link.url {
+                AddLink { url: link.url.clone(), title: self.title_for(&link.url) }
+            } else {
+                AddLink { url: link.url.clone(), title: link.title.clone() }
+            }
+        }).collect()
+    }
+
+    /// The title of the page at `url`, or its host and path when it cannot be found.
+    pub fn title_for(&self, url: &str) -> String {
+        self.fetch_title(url).unwrap_or_else(|| fallback_title(url))
+    }
+
+    fn fetch_title(&self, url: &str) -> Option<String> {
+        let response = self.http_client.get(url).send().ok()?;
+        if !response.status().is_success() {
+            return None;
+        }
+        let is_html = response.headers().get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_none_or(|value| value.contains("html"));
+        if !is_html {
+            return None;
+        }
+        let mut page = Vec::new();
+        response.take(self.options.max_bytes).read_to_end(&mut page).ok()?;
+        extract_title(&String::from_utf8_lossy(&page))
+    }
+}
+
+/// Finds a page's title in its OpenGraph tags, then its Twitter card tags, then `<title>`.
+pub fn extract_title(html: &str) -> Option<String> {
+    let lower = html.to_ascii_lowercase();
+    let mut meta_titles = Vec::new();
+    let mut position = 0;
+    while let Some(start) = lower[position..].find("<meta").map(|offset| position + offset) {
+        let end = lower[start..].find('>').map(|offset| start + offset).unwrap_or(html.len());
+        let tag = &html[start..end];
+        let key = attribute(tag, "property").or_else(|| attribute(tag, "name")).map(|key| key.to_ascii_lowercase());
+        if let (Some(key), Some(content)) = (key, attribute(tag, "content")) {
+            meta_titles.push((key, content));
+        }
+        position = end;
+    }
+    let meta = |key: &str| meta_titles.iter().find(|(found, _)| found == key).map(|(_, content)| content.clone());
+    let title = meta("og:title").or_else(|| meta("twitter:title")).or_else(|| {
+        let start = lower.find("<title")?;
+        let text_start = start + lower[start..].find('>')? + 1;
+        let text_end = text_start + lower[text_start..].find("</title")?;
+        Some(decode_entities(&html[text_start..text_end]))
+    })?;
+    let title = title.split_whitespace().collect::<Vec<&str>>().join(" ");
+    if title.is_empty() { None } else { Some(title) }
+}
+
+// `example.com/some/page` for `https://www.example.com/some/page/?q=1`.
+fn fallback_title(url: &str) -> String {
+    match Url::parse(url) {
+        Ok(parsed) => {
+            let host = parsed.host_str().unwrap_or_default();
+            let host = host.strip_prefix("www.").unwrap_or(host);
+            format!("{}{}", host, parsed.path().trim_end_matches('/'))
+        },
+        Err(_) => url.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::mock;
+
+    fn link(path: &str) -> AddLink {
+        let url = format!("{}{}", mockito::SERVER_URL, path);
+        AddLink { title: url.clone(), url }
+    }
+
+    #[test]
+    fn it_extracts_titles() {
+        let html = r#"<html><head><title> Plain
+            title </title><meta name="twitter:title" content="Twitter title"><meta property="og:title" content="Tom &amp; Jerry"></head></html>"#;
+        assert_eq!(extract_title(html), Some(String::from("Tom & Jerry")));
+        assert_eq!(extract_title(&html.replace("og:title", "og:other")), Some(String::from("Twitter title")));
+        assert_eq!(extract_title("<TITLE>Plain\n  title</TITLE>"), Some(String::from("Plain title")));
+        assert_eq!(extract_title("<p>No title</p>"), None);
+    }
+
+    #[test]
+    fn it_titles_links_from_their_pages() {
+        let _page = mock("GET", "/enrich/page").with_status(200).with_header("content-type", "text/html; charset=utf-8")
+          .with_body("<title>Moodboard</title>").create();
+        let _redirect = mock("GET", "/enrich/old").with_status(301).with_header("location", "/enrich/page").create();
+        let _missing = mock("GET", "/enrich/missing").with_status(404).create();
+        let _image = mock("GET", "/enrich/image.png").with_status(200).with_header("content-type", "image/png").create();
+
+        let enricher = LinkEnricher::new(EnrichOptions::default()).unwrap();
+        let titled = AddLink { url: String::from("https://example.com"), title: String::from("Kept") };
+        let links = enricher.enrich(&[link("/enrich/page"), link("/enrich/old"), link("/enrich/missing"), link("/enrich/image.png"), titled]);
+        let titles: Vec<&str> = links.iter().map(|link| link.title.as_str()).collect();
+        assert_eq!(titles, vec!["Moodboard", "Moodboard", "127.0.0.1/enrich/missing", "127.0.0.1/enrich/image.png", "Kept"]);
+    }
+
+    #[test]
+    fn it_caps_pages_and_redirects() {
+        let body = format!("{}<title>Too far</title>", " ".repeat(64));
+        let _page = mock("GET", "/enrich/large").with_status(200).with_body(body.as_str()).create();
+        let _loop = mock("GET", "/enrich/loop").with_status(302).with_header("location", "/enrich/loop").create();
+
+        let enricher = LinkEnricher::new(EnrichOptions { max_bytes: 32, ..Default::default() }).unwrap();
+        assert_eq!(enricher.title_for(&link("/enrich/large").url), "127.0.0.1/enrich/large");
+        assert_eq!(enricher.title_for(&link("/enrich/loop").url), "127.0.0.1/enrich/loop");
+        assert_eq!(fallback_title("https://www.example.com/some/page/?q=1"), "example.com/some/page");
+    }
+}
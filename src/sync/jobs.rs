@@ -0,0 +1,552 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+
+use responses::*;
+use sync::Client;
+use sync::cancel::CancellationToken;
+use sync::upload::UploadOptions;
+use sync::spool::TempCopies;
+
+pub type JobId = u64;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+/// An upload handed to the `JobManager`.
+pub enum Job {
+    /// Creates a transfer with the files at `paths`, then finalizes it.
+    Transfer { message: String, paths: Vec<String> },
+    /// Adds the files at `paths` to an existing board.
+    BoardUpload { board_id: String, paths: Vec<String> },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Paused,
+    Cancelled,
+    Completed,
+    Failed,
+}
+
+impl JobStatus {
+    /// Whether the job will not change anymore.
+    pub fn is_finished(self) -> bool {
+        matches!(self, JobStatus::Cancelled | JobStatus::Completed | JobStatus::Failed)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+/// The final state of the transfer or board once a job completes.
+pub enum JobResult {
+    Transfer(Transfer),
+    Board(Board),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+/// A copy of the state of a job at a given time.
+pub struct JobSnapshot {
+    pub id: JobId,
+    pub job: Job,
+    pub status: JobStatus,
+    /// known once the transfer (or the board files) were created in the API.
+    pub progress: Option<UploadProgress>,
+    pub result: Option<JobResult>,
+    pub error: Option<WeTransferError>,
+}
+
+#[derive(Debug, Clone)]
+/// Sent to every `JobManager::subscribe` receiver as jobs move forward.
+pub enum JobEvent {
+    Queued(JobId),
+    Started(JobId),
+    Progress { id: JobId, completed_parts: u64, total_parts: u64 },
+    Paused(JobId),
+    Cancelled(JobId),
+    Completed(JobId, JobResult),
+    Failed(JobId, WeTransferError),
+}
+
+/// Runs transfer and board-upload jobs on a pool of worker threads sharing one `Client`.
+/// The pool size caps how many uploads run at the same time.
+pub struct JobManager {
+    shared: Arc<Shared>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+struct Shared {
+    client: Arc<Client>,
+    state: Mutex<State>,
+    // Signalled when a job is queued or on shutdown.
+    queued: Condvar,
+    // Signalled on every status change.
+    changed: Condvar,
+    subscribers: Mutex<Vec<Sender<JobEvent>>>,
+}
+
+#[derive(Default)]
+struct State {
+    next_id: JobId,
+    jobs: HashMap<JobId, Entry>,
+    queue: VecDeque<JobId>,
+    shutdown: bool,
+}
+
+struct Entry {
+    job: Job,
+    status: JobStatus,
+    progress: Option<UploadProgress>,
+    result: Option<JobResult>,
+    error: Option<WeTransferError>,
+    cancel: CancellationToken,
+    pause_requested: bool,
+}
+
+impl JobManager {
+    /// Constructor. Spawns `concurrency` workers (at least one).
+    pub fn new(client: Arc<Client>, concurrency: usize) -> JobManager {
+        let shared = Arc::new(Shared {
+            client,
+            state: Mutex::new(State::default()),
+            queued: Condvar::new(),
+            changed: Condvar::new(),
+            subscribers: Mutex::new(Vec::new()),
+        });
+        let workers = (0..concurrency.max(1)).map(|_| {
+            let shared = shared.clone();
+            thread::spawn(move || shared.work())
+        }).collect();
+        JobManager { shared, workers }
+    }
+
+    /// Returns a receiver for the events of every job submitted from now on.
+    pub fn subscribe(&self) -> Receiver<JobEvent> {
+        let (sender, receiver) = channel();
+        self.shared.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Queues a job and returns its id.
+    pub fn submit(&self, job: Job) -> JobId {
+        let id = {
+            let mut state = self.shared.state.lock().unwrap();
+            state.next_id += 1;
+            let id = state.next_id;
+            state.jobs.insert(id, Entry {
+                job,
+                status: JobStatus::Queued,
+                progress: None,
+                result: None,
+                error: None,
+                cancel: CancellationToken::new(),
+                pause_requested: false,
+            });
+            state.queue.push_back(id);
+            id
+        };
+        self.shared.queued.notify_one();
+        self.shared.emit(JobEvent::Queued(id));
+        id
+    }
+
+    /// Brings back a job saved from an earlier `JobManager`, under the same id.
+    /// Jobs that were queued or running are queued again and resume from their
+    /// saved progress. Returns `false` if the id is already taken.
+    pub fn restore(&self, snapshot: JobSnapshot) -> bool {
+        let id = snapshot.id;
+        let status = match snapshot.status {
+            JobStatus::Running => JobStatus::Queued,
+            status => status,
+        };
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            if state.jobs.contains_key(&id) {
+                return false;
+            }
+            state.next_id = state.next_id.max(id);
+            state.jobs.insert(id, Entry {
+                job: snapshot.job,
+                status,
+                progress: snapshot.progress,
+                result: snapshot.result,
+                error: snapshot.error,
+                cancel: CancellationToken::new(),
+                pause_requested: false,
+            });
+            if status != JobStatus::Queued {
+                return true;
+            }
+            state.queue.push_back(id);
+        }
+        self.shared.queued.notify_one();
+        self.shared.emit(JobEvent::Queued(id));
+        true
+    }
+
+    pub fn status(&self, id: JobId) -> Option<JobSnapshot> {
+        self.shared.state.lock().unwrap().snapshot(id)
+    }
+
+    /// Every job known to the manager, sorted by id.
+    pub fn jobs(&self) -> Vec<JobSnapshot> {
+        let state = self.shared.state.lock().unwrap();
+        let mut ids: Vec<JobId> = state.jobs.keys().cloned().collect();
+        ids.sort();
+        ids.into_iter().filter_map(|id| state.snapshot(id)).collect()
+    }
+
+    /// Blocks until the job completes, fails or is cancelled.
+    pub fn wait(&self, id: JobId) -> Option<JobSnapshot> {
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            match state.jobs.get(&id) {
+                None => return None,
+                Some(entry) if entry.status.is_finished() => return state.snapshot(id),
+                Some(_) => state = self.shared.changed.wait(state).unwrap(),
+            }
+        }
+    }
+
+    /// Stops a queued or running job, keeping its progress for `resume`.
+    /// A running job is paused once its current part is aborted.
+    pub fn pause(&self, id: JobId) -> bool {
+        let mut state = self.shared.state.lock().unwrap();
+        let status = match state.jobs.get(&id) {
+            Some(entry) => entry.status,
+            None => return false,
+        };
+        match status {
+            JobStatus::Queued => {
+                state.queue.retain(|queued| *queued != id);
+                state.jobs.get_mut(&id).unwrap().status = JobStatus::Paused;
+                drop(state);
+                self.shared.changed.notify_all();
+                self.shared.emit(JobEvent::Paused(id));
+                true
+            },
+            JobStatus::Running => {
+                let entry = state.jobs.get_mut(&id).unwrap();
+                entry.pause_requested = true;
+                entry.cancel.cancel();
+                true
+            },
+            _ => false,
+        }
+    }
+
+    /// Queues a paused job again. Parts already uploaded are not sent twice.
+    pub fn resume(&self, id: JobId) -> bool {
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            match state.jobs.get_mut(&id) {
+                Some(ref mut entry) if entry.status == JobStatus::Paused => {
+                    entry.status = JobStatus::Queued;
+                    entry.cancel = CancellationToken::new();
+                    entry.pause_requested = false;
+                },
+                _ => return false,
+            }
+            state.queue.push_back(id);
+        }
+        self.shared.queued.notify_one();
+        self.shared.emit(JobEvent::Queued(id));
+        true
+    }
+
+    /// Cancels a queued, paused or running job for good.
+    pub fn cancel(&self, id: JobId) -> bool {
+        let mut state = self.shared.state.lock().unwrap();
+        let status = match state.jobs.get(&id) {
+            Some(entry) => entry.status,
+            None => return false,
+        };
+        match status {
+            JobStatus::Queued | JobStatus::Paused => {
+                state.queue.retain(|queued| *queued != id);
+                state.jobs.get_mut(&id).unwrap().status = JobStatus::Cancelled;
+                drop(state);
+                self.shared.changed.notify_all();
+                self.shared.emit(JobEvent::Cancelled(id));
+                true
+            },
+            JobStatus::Running => {
+                let entry = state.jobs.get_mut(&id).unwrap();
+                entry.pause_requested = false;
+                entry.cancel.cancel();
+                true
+            },
+            _ => false,
+        }
+    }
+}
+
+impl Drop for JobManager {
+    /// Cancels the running jobs and waits for the workers to exit.
+    fn drop(&mut self) {
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            state.shutdown = true;
+            for entry in state.jobs.values() {
+                if entry.status == JobStatus::Running {
+                    entry.cancel.cancel();
+                }
+            }
+        }
+        self.shared.queued.notify_all();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Shared {
+    fn emit(&self, event: JobEvent) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    fn work(&self) {
+        while let Some((id, job, progress, cancel)) = self.next_job() {
+            self.emit(JobEvent::Started(id));
+            let outcome = self.run(id, &job, progress, &cancel);
+            self.finish(id, outcome);
+        }
+    }
+
+    // Blocks until a job is queued, and marks it as running. `None` on shutdown.
+    fn next_job(&self) -> Option<(JobId, Job, Option<UploadProgress>, CancellationToken)> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.shutdown {
+                return None;
+            }
+            if let Some(id) = state.queue.pop_front() {
+                let entry = state.jobs.get_mut(&id).unwrap();
+                entry.status = JobStatus::Running;
+                let next = (id, entry.job.clone(), entry.progress.clone(), entry.cancel.clone());
+                drop(state);
+                self.changed.notify_all();
+                return Some(next);
+            }
+            state = self.queued.wait(state).unwrap();
+        }
+    }
+
+    fn run(&self, id: JobId, job: &Job, progress: Option<UploadProgress>, cancel: &CancellationToken) -> Result<JobResult, WeTransferError> {
+        let on_part = |progress: &UploadProgress| self.record_progress(id, progress);
+        match *job {
+            Job::Transfer { ref message, ref paths } => {
+                let transfers = &self.client.transfers;
+                let mut progress = match progress {
+                    Some(progress) => progress,
+                    None => {
+                        let path_refs: Vec<&str> = paths.iter().map(String::as_str).collect();
+                        if let Some(transfer) = transfers.find_duplicate(message, &path_refs)? {
+                            return Ok(JobResult::Transfer(transfer));
+                        }
+                        transfers.start_upload(message, &path_refs)?
+                    }
+                };
+                on_part(&progress);
+                let transfer = transfers.resume_observed(&mut progress, cancel, on_part)?;
+                self.store_progress(id, &progress);
+                Ok(JobResult::Transfer(transfer))
+            },
+            Job::BoardUpload { ref board_id, ref paths } => {
+                let boards = &self.client.boards;
+                let mut progress = match progress {
+                    Some(progress) => progress,
+                    None => {
+                        boards.start_upload(board_id, paths)?
+                    }
+                };
+                on_part(&progress);
+                boards.resume_file_uploads_with(&mut progress, cancel, &UploadOptions::default(), on_part)?;
+                self.store_progress(id, &progress);
+                boards.find(board_id.as_str()).map(JobResult::Board)
+            },
+        }
+    }
+
+    // Without the copies the files are read from: the running upload holds them,
+    // and they must not outlive it in the snapshot of a failed job.
+    fn store_progress(&self, id: JobId, progress: &UploadProgress) {
+        if let Some(entry) = self.state.lock().unwrap().jobs.get_mut(&id) {
+            entry.progress = Some(UploadProgress { copies: TempCopies::default(), ..progress.clone() });
+        }
+    }
+
+    fn record_progress(&self, id: JobId, progress: &UploadProgress) {
+        self.store_progress(id, progress);
+        self.emit(JobEvent::Progress {
+            id,
+            completed_parts: progress.completed_parts(),
+            total_parts: progress.total_parts(),
+        });
+    }
+
+    fn finish(&self, id: JobId, outcome: Result<JobResult, WeTransferError>) {
+        {
+            // The event is sent before releasing the lock, so whoever `wait`s
+            // for this job has it in their receiver once woken up.
+            let mut state = self.state.lock().unwrap();
+            let entry = state.jobs.get_mut(&id).unwrap();
+            let event = match outcome {
+                Ok(result) => {
+                    entry.status = JobStatus::Completed;
+                    entry.result = Some(result.clone());
+                    JobEvent::Completed(id, result)
+                },
+                Err(mut error) => match error.cancelled.take() {
+                    Some(progress) => {
+                        entry.progress = Some(*progress);
+                        if entry.pause_requested {
+                            entry.status = JobStatus::Paused;
+                            JobEvent::Paused(id)
+                        } else {
+                            entry.status = JobStatus::Cancelled;
+                            JobEvent::Cancelled(id)
+                        }
+                    },
+                    None => {
+                        entry.status = JobStatus::Failed;
+                        entry.error = Some(error.clone());
+                        JobEvent::Failed(id, error)
+                    }
+                },
+            };
+            self.emit(event);
+        }
+        self.changed.notify_all();
+    }
+}
+
+impl State {
+    fn snapshot(&self, id: JobId) -> Option<JobSnapshot> {
+        self.jobs.get(&id).map(|entry| JobSnapshot {
+            id,
+            job: entry.job.clone(),
+            status: entry.status,
+            progress: entry.progress.clone(),
+            result: entry.result.clone(),
+            error: entry.error.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::mock;
+    use sync::webhook::{EventKind, Webhook, WebhookDispatcher};
+
+    fn client() -> Arc<Client> {
+        let _m = mock("POST", "/")
+          .with_status(200)
+          .with_body("{\"token\": \"jwt_token\", \"success\": true}")
+          .create();
+        Arc::new(Client::new("1234").unwrap())
+    }
+
+    #[test]
+    fn it_runs_board_upload_jobs() {
+        let _login = mock("POST", "/")
+          .with_status(200)
+          .with_body("{\"token\": \"jwt_token\", \"success\": true}")
+          .create();
+        let mut client = Client::new("1234").unwrap();
+        let hook = Webhook { events: vec![EventKind::BoardFilesAdded], ..Webhook::new(format!("{}/hooks/jobs", mockito::SERVER_URL)) };
+        let dispatcher = Arc::new(WebhookDispatcher::new(vec![hook]));
+        client.boards.set_webhooks(dispatcher.clone());
+        let delivered = mock("POST", "/hooks/jobs")
+          .match_body(mockito::Matcher::Regex(String::from("job-board")))
+          .with_status(200)
+          .expect(1)
+          .create();
+        let files = "[{\"id\": \"job-file\", \"name\": \"Cargo.toml\", \"size\": 10, \"type\": \"file\", \
+                     \"multipart\": {\"id\": \"multipart-id\", \"part_numbers\": 1, \"chunk_size\": 10}}]";
+        let _files = mock("POST", "/job-board/files").with_status(201).with_body(files).create();
+        let upload_url = format!("{{\"success\": true, \"url\": \"{}/job-s3\"}}", mockito::SERVER_URL);
+        let _url = mock("GET", "/job-board/files/job-file/upload-url/1/multipart-id").with_status(200).with_body(upload_url.as_str()).create();
+        let _s3 = mock("PUT", "/job-s3").with_status(200).create();
+        let _complete = mock("PUT", "/job-board/files/job-file/upload-complete")
+          .with_status(200)
+          .with_body("{\"success\": true, \"message\": \"File is marked as complete.\"}")
+          .create();
+        let board = "{\"id\": \"job-board\", \"name\": \"Jobs\", \"description\": null, \"state\": \"downloadable\", \
+                     \"url\": \"https://we.tl/b-job\", \"items\": []}";
+        let _board = mock("GET", "/job-board").with_status(200).with_body(board).create();
+
+        let manager = JobManager::new(Arc::new(client), 2);
+        let events = manager.subscribe();
+        let id = manager.submit(Job::BoardUpload { board_id: String::from("job-board"), paths: vec![String::from("Cargo.toml")] });
+        let snapshot = manager.wait(id).unwrap();
+
+        assert_eq!(snapshot.status, JobStatus::Completed);
+        match snapshot.result {
+            Some(JobResult::Board(board)) => assert_eq!(board.url, "https://we.tl/b-job"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        let progress = snapshot.progress.unwrap();
+        assert_eq!(progress.completed_parts(), 1);
+        assert!(progress.files[0].completed);
+
+        let received: Vec<JobEvent> = events.try_iter().collect();
+        match received.first() { Some(&JobEvent::Queued(queued)) => assert_eq!(queued, id), other => panic!("{:?}", other) }
+        match received.last() { Some(&JobEvent::Completed(done, _)) => assert_eq!(done, id), other => panic!("{:?}", other) }
+        assert!(received.iter().any(|event| matches!(*event, JobEvent::Progress { completed_parts: 1, total_parts: 1, .. })));
+
+        dispatcher.flush();
+        delivered.assert();
+    }
+
+    #[test]
+    fn it_reports_failed_jobs() {
+        let client = client();
+        let _files = mock("POST", "/missing-board/files")
+          .with_status(404)
+          .with_body("{\"success\": false, \"message\": \"Board not found\"}")
+          .create();
+
+        let manager = JobManager::new(client, 1);
+        let id = manager.submit(Job::BoardUpload { board_id: String::from("missing-board"), paths: vec![String::from("Cargo.toml")] });
+        let snapshot = manager.wait(id).unwrap();
+        assert_eq!(snapshot.status, JobStatus::Failed);
+        assert_eq!(snapshot.error.unwrap().status, 404);
+        assert!(!manager.pause(id));
+        assert!(!manager.resume(id));
+    }
+
+    #[test]
+    fn it_pauses_resumes_and_cancels_queued_jobs() {
+        let manager = JobManager { shared: Arc::new(Shared {
+            client: client(),
+            state: Mutex::new(State::default()),
+            queued: Condvar::new(),
+            changed: Condvar::new(),
+            subscribers: Mutex::new(Vec::new()),
+        }), workers: Vec::new() };
+        let id = manager.submit(Job::Transfer { message: String::from("foo"), paths: vec![String::from("Cargo.toml")] });
+
+        assert!(manager.pause(id));
+        assert_eq!(manager.status(id).unwrap().status, JobStatus::Paused);
+        assert!(manager.resume(id));
+        assert_eq!(manager.status(id).unwrap().status, JobStatus::Queued);
+        assert!(manager.cancel(id));
+        assert_eq!(manager.wait(id).unwrap().status, JobStatus::Cancelled);
+        assert!(manager.shared.state.lock().unwrap().queue.is_empty());
+        assert_eq!(manager.jobs().len(), 1);
+
+        let mut snapshot = manager.status(id).unwrap();
+        assert!(!manager.restore(snapshot.clone()));
+        snapshot.id = 7;
+        snapshot.status = JobStatus::Running;
+        assert!(manager.restore(snapshot));
+        assert_eq!(manager.status(7).unwrap().status, JobStatus::Queued);
+        assert_eq!(manager.submit(Job::Transfer { message: String::from("bar"), paths: Vec::new() }), 8);
+    }
+}
@@ -1,42 +1,150 @@
 use responses::Login;
 use responses::WeTransferError;
+use secret::Secret;
+use history::History;
+use dedup::DedupCache;
+use std::sync::Arc;
 
 pub mod transfer;
 pub mod board;
 pub mod requester;
+pub mod rate_limit;
+pub mod bandwidth;
+pub mod cancel;
+pub mod upload;
+pub mod builder;
+pub mod spool;
+pub mod download;
+pub mod enrich;
+pub mod watch;
+pub mod jobs;
+pub mod daemon;
+pub mod notify;
+pub mod webhook;
+pub mod policy;
+pub mod privacy;
+pub mod preset;
+pub mod compress;
+
+use self::rate_limit::{RateLimiter, RateLimitPolicy};
+use self::bandwidth::BandwidthLimiter;
+use self::enrich::LinkEnricher;
+use self::notify::Notifier;
+use self::webhook::WebhookDispatcher;
+use self::policy::UploadPolicy;
+use self::privacy::MetadataStripper;
+use self::compress::Compressor;
 
 #[cfg(not(test))]
 const LOGIN_URL: &str = "https://dev.wetransfer.com/v2/authorize";
 #[cfg(test)]
 const LOGIN_URL: &str = mockito::SERVER_URL;
 
+/// Entry point of the synchronous API. Its `Debug` output never
+/// contains the app token nor the JWT.
 #[derive(Debug)]
 pub struct Client {
     pub transfers: transfer::TransferService,
     pub boards: board::BoardService,
+    bandwidth: Arc<BandwidthLimiter>,
 }
 
 impl Client {
-    pub fn new<S: Into<String>+ToString>(app_token: S) -> Result<Client, WeTransferError> {
-        let result = Client::login(app_token.to_string());
+    /// Logs in and builds the services. Accepts a `&str`, a `String` or a
+    /// `Secret` loaded with `Secret::from_file`, `from_env` or `from_command`.
+    pub fn new<S: Into<Secret>>(app_token: S) -> Result<Client, WeTransferError> {
+        let app_token = app_token.into();
+        let result = Client::login(&app_token);
         match result {
             Ok(login) => {
                 let jwt = login.token;
-                let client = Client {
-                    transfers: transfer::TransferService::new(jwt.clone(), app_token.to_string()),
-                    boards: board::BoardService::new(jwt.clone(), app_token.to_string())
+                let mut client = Client {
+                    transfers: transfer::TransferService::new(jwt.clone(), app_token.clone()),
+                    boards: board::BoardService::new(jwt, app_token),
+                    bandwidth: Arc::new(BandwidthLimiter::default()),
                 };
+                client.transfers.set_bandwidth_limiter(client.bandwidth.clone());
+                client.boards.set_bandwidth_limiter(client.bandwidth.clone());
                 Ok(client)
             },
             Err(error) => Err(error),
         }
     }
 
-    fn login<S: Into<String>+ToString>(app_token: S) -> Result<Login, WeTransferError> {
+    /// Shares one token bucket between transfers and boards, so every thread
+    /// using this client draws from the same API quota.
+    pub fn set_rate_limiter(&mut self, limiter: RateLimiter) {
+        let limiter = Arc::new(limiter);
+        self.transfers.set_rate_limiter(limiter.clone());
+        self.boards.set_rate_limiter(limiter);
+    }
+
+    /// Sets whether `429 Too Many Requests` responses are retried or reported.
+    pub fn set_rate_limit_policy(&mut self, policy: RateLimitPolicy) {
+        self.transfers.set_rate_limit_policy(policy.clone());
+        self.boards.set_rate_limit_policy(policy);
+    }
+
+    /// Records the transfers and boards created through this client in `history`.
+    pub fn set_history(&mut self, history: History) {
+        let history = Arc::new(history);
+        self.transfers.set_history(history.clone());
+        self.boards.set_history(history);
+    }
+
+    /// Reuses live transfers holding identical contents instead of uploading them again.
+    pub fn set_dedup_cache(&mut self, cache: DedupCache) {
+        self.transfers.set_dedup_cache(Arc::new(cache));
+    }
+
+    /// Titles the links added to boards which have none, from the pages they point to.
+    pub fn set_link_enricher(&mut self, enricher: LinkEnricher) {
+        self.boards.set_link_enricher(Arc::new(enricher));
+    }
+
+    /// Emails the link of every transfer once it is finalized, or found downloadable by
+    /// `TransferService::wait_until_downloadable`.
+    pub fn set_notifier(&mut self, notifier: Notifier) {
+        self.transfers.set_notifier(Arc::new(notifier));
+    }
+
+    /// Sends transfer and board events to the webhooks of `dispatcher`.
+    pub fn set_webhooks(&mut self, dispatcher: WebhookDispatcher) {
+        let dispatcher = Arc::new(dispatcher);
+        self.transfers.set_webhooks(dispatcher.clone());
+        self.boards.set_webhooks(dispatcher);
+    }
+
+    /// Screens the files of every transfer and board upload with `policy` first.
+    pub fn set_upload_policy(&mut self, policy: UploadPolicy) {
+        let policy = Arc::new(policy);
+        self.transfers.set_upload_policy(policy.clone());
+        self.boards.set_upload_policy(policy);
+    }
+
+    /// Uploads copies of images and documents without their metadata.
+    pub fn set_metadata_stripper(&mut self, stripper: MetadataStripper) {
+        let stripper = Arc::new(stripper);
+        self.transfers.set_metadata_stripper(stripper.clone());
+        self.boards.set_metadata_stripper(stripper);
+    }
+
+    /// Compresses the files of new transfers selected by `compressor` before uploading them.
+    pub fn set_compressor(&mut self, compressor: Compressor) {
+        self.transfers.set_compressor(Arc::new(compressor));
+    }
+
+    /// The upload bandwidth limiter shared by every S3 part upload of this client.
+    /// Unlimited by default; it can be adjusted while uploads are running.
+    pub fn bandwidth(&self) -> &BandwidthLimiter {
+        &self.bandwidth
+    }
+
+    fn login(app_token: &Secret) -> Result<Login, WeTransferError> {
         let http_client = reqwest::Client::new();
         let mut response = http_client
           .post(LOGIN_URL)
-          .header("x-api-key", app_token.to_string())
+          .header("x-api-key", app_token.expose())
           .send().unwrap();
         if response.status().is_success() {
             Ok(response.json::<Login>().unwrap())
@@ -64,6 +172,8 @@ mod tests {
 
         let client_creation = Client::new(app_token);
         assert!(client_creation.is_ok());
+        let output = format!("{:?}", client_creation.unwrap());
+        assert!(!output.contains("jwt_token"));
     }
 
     #[test]
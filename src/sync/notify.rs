@@ -0,0 +1,454 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use base64;
+use chrono::{DateTime, Utc};
+use native_tls::{TlsConnector, TlsStream};
+
+use export::human_size;
+use responses::{Transfer, WeTransferError};
+use secret::Secret;
+
+/// How the connection to the SMTP server is secured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmtpSecurity {
+    /// Plain text, for local relays only.
+    None,
+    /// Upgrades a plain connection with `STARTTLS`, usually on port 587.
+    StartTls,
+    /// TLS from the start, usually on port 465.
+    Tls,
+}
+
+/// Where and how emails are sent.
+#[derive(Debug, Clone)]
+pub struct SmtpConfig {
+    pub host: String,
+    pub port: u16,
+    pub security: SmtpSecurity,
+    /// user name and password, sent with `AUTH PLAIN`.
+    pub credentials: Option<(String, Secret)>,
+    /// sender address, as in `MAIL FROM`.
+    pub from: String,
+    /// name this client introduces itself with in `EHLO`.
+    pub hello_name: String,
+    pub timeout: Duration,
+}
+
+impl SmtpConfig {
+    /// A STARTTLS connection to `host` on port 587, without authentication.
+    pub fn new<S: Into<String>, T: Into<String>>(host: S, from: T) -> SmtpConfig {
+        SmtpConfig {
+            host: host.into(),
+            port: 587,
+            security: SmtpSecurity::StartTls,
+            credentials: None,
+            from: from.into(),
+            hello_name: String::from("localhost"),
+            timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Subject and body of the emails. `{url}`, `{message}`, `{files}`,
+/// `{expires_at}` and `{id}` are replaced with the transfer's details.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EmailTemplate {
+    pub subject: String,
+    pub body: String,
+}
+
+impl Default for EmailTemplate {
+    fn default() -> EmailTemplate {
+        EmailTemplate {
+            subject: String::from("Files shared with you"),
+            body: String::from("{message}\n\nDownload the files at {url}\n\n{files}\n\nThe link expires on {expires_at}.\n"),
+        }
+    }
+}
+
+impl EmailTemplate {
+    /// Returns the subject and body for `transfer`.
+    pub fn render(&self, transfer: &Transfer) -> (String, String) {
+        let files: Vec<String> = transfer.files.iter().map(|file| format!("- {} ({})", file.name, human_size(file.size as u64))).collect();
+        let expires_at = DateTime::parse_from_rfc3339(&transfer.expires_at)
+            .map(|date| date.with_timezone(&Utc).format("%Y-%m-%d %H:%M UTC").to_string())
+            .unwrap_or_else(|_| transfer.expires_at.clone());
+        let render = |template: &str| template
+            .replace("{url}", transfer.url.as_deref().unwrap_or_default())
+            .replace("{message}", &transfer.message)
+            .replace("{files}", &files.join("\n"))
+            .replace("{expires_at}", &expires_at)
+            .replace("{id}", &transfer.id);
+        // A subject spanning several lines would add headers to the email.
+        let subject = render(&self.subject).lines().map(str::trim).filter(|line| !line.is_empty()).collect::<Vec<&str>>().join(" ");
+        (subject, render(&self.body))
+    }
+}
+
+type FailureReporter = Box<dyn Fn(&Transfer, &WeTransferError) + Send + Sync>;
+
+/// Emails the link of finalized transfers to a list of recipients.
+pub struct Notifier {
+    config: SmtpConfig,
+    recipients: Vec<String>,
+    template: EmailTemplate,
+    // Transfers already announced, so finalizing and waiting do not both send an email.
+    sent: Mutex<HashSet<String>>,
+    on_failure: Option<FailureReporter>,
+}
+
+impl fmt::Debug for Notifier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Notifier")
+            .field("config", &self.config)
+            .field("recipients", &self.recipients)
+            .field("template", &self.template)
+            .finish()
+    }
+}
+
+impl Notifier {
+    /// Constructor.
+    pub fn new(config: SmtpConfig, recipients: Vec<String>, template: EmailTemplate) -> Notifier {
+        Notifier { config, recipients, template, sent: Mutex::new(HashSet::new()), on_failure: None }
+    }
+
+    /// Receives the emails that could not be sent after `finalize` or
+    /// `wait_until_downloadable`, which return the transfer regardless.
+    pub fn on_failure<F: Fn(&Transfer, &WeTransferError) + Send + Sync + 'static>(mut self, reporter: F) -> Notifier {
+        self.on_failure = Some(Box::new(reporter));
+        self
+    }
+
+    /// Like `notify`, but hands errors to the failure reporter instead of returning them.
+    pub fn notify_or_report(&self, transfer: &Transfer) {
+        if let Err(error) = self.notify(transfer) {
+            if let Some(ref reporter) = self.on_failure {
+                reporter(transfer, &error);
+            }
+        }
+    }
+
+    /// Emails `transfer` to the recipients, unless it was already sent or has no url yet.
+    /// Returns whether an email went out.
+    pub fn notify(&self, transfer: &Transfer) -> Result<bool, WeTransferError> {
+        if transfer.url.is_none() || self.recipients.is_empty() || self.sent.lock().unwrap().contains(&transfer.id) {
+            return Ok(false);
+        }
+        let (subject, body) = self.template.render(transfer);
+        self.send(&subject, &body).map_err(|error| WeTransferError {
+            status: 0,
+            message: format!("Could not email transfer {}: {}", transfer.id, error),
+            ..Default::default()
+        })?;
+        self.sent.lock().unwrap().insert(transfer.id.clone());
+        Ok(true)
+    }
+
+    /// Sends one email to every recipient. Addresses, `hello_name` and the
+    /// subject must not contain line breaks.
+    pub fn send(&self, subject: &str, body: &str) -> io::Result<()> {
+        let config = &self.config;
+        let addresses = self.recipients.iter().chain(Some(&config.from)).chain(Some(&config.hello_name));
+        for field in addresses.map(String::as_str).chain(Some(subject)) {
+            if field.contains(['\r', '\n']) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("line break in {:?}", field)));
+            }
+        }
+        let stream = TcpStream::connect((config.host.as_str(), config.port))?;
+        stream.set_read_timeout(Some(config.timeout))?;
+        stream.set_write_timeout(Some(config.timeout))?;
+        let mut connection = match config.security {
+            SmtpSecurity::Tls => Connection::Tls(Box::new(tls(&config.host, stream)?)),
+            _ => Connection::Plain(stream),
+        };
+        expect(&mut connection, 220)?;
+        command(&mut connection, &format!("EHLO {}", config.hello_name), 250)?;
+        if config.security == SmtpSecurity::StartTls {
+            command(&mut connection, "STARTTLS", 220)?;
+            connection = match connection {
+                Connection::Plain(stream) => Connection::Tls(Box::new(tls(&config.host, stream)?)),
+                tls => tls,
+            };
+            command(&mut connection, &format!("EHLO {}", config.hello_name), 250)?;
+        }
+        if let Some((ref user, ref password)) = config.credentials {
+            let token = base64::encode(&format!("\0{}\0{}", user, password.expose()));
+            command(&mut connection, &format!("AUTH PLAIN {}", token), 235)?;
+        }
+        command(&mut connection, &format!("MAIL FROM:<{}>", config.from), 250)?;
+        for recipient in &self.recipients {
+            command(&mut connection, &format!("RCPT TO:<{}>", recipient), 250)?;
+        }
+        command(&mut connection, "DATA", 354)?;
+        let message = format_message(&config.from, &self.recipients, subject, body);
+        connection.write_all(message.as_bytes())?;
+        command(&mut connection, ".", 250)?;
+        let _ = command(&mut connection, "QUIT", 221);
+        Ok(())
+    }
+}
+
+enum Connection {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl Read for Connection {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Connection::Plain(ref mut stream) => stream.read(buffer),
+            Connection::Tls(ref mut stream) => stream.read(buffer),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        match *self {
+            Connection::Plain(ref mut stream) => stream.write(buffer),
+            Connection::Tls(ref mut stream) => stream.write(buffer),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Connection::Plain(ref mut stream) => stream.flush(),
+            Connection::Tls(ref mut stream) => stream.flush(),
+        }
+    }
+}
+
+fn tls(host: &str, stream: TcpStream) -> io::Result<TlsStream<TcpStream>> {
+    let connector = TlsConnector::new().map_err(|error| io::Error::other(error.to_string()))?;
+    connector.connect(host, stream).map_err(|error| io::Error::other(error.to_string()))
+}
+
+fn command<S: Read + Write>(stream: &mut S, line: &str, code: u16) -> io::Result<String> {
+    write!(stream, "{}\r\n", line)?;
+    stream.flush()?;
+    expect(stream, code)
+}
+
+// Reads a possibly multiline reply (`250-...` lines followed by `250 ...`) and checks its code.
+fn expect<S: Read>(stream: &mut S, code: u16) -> io::Result<String> {
+    let mut reply = String::new();
+    loop {
+        let line = read_line(stream)?;
+        reply.push_str(&line);
+        reply.push('\n');
+        if line.len() < 4 || line.as_bytes()[3] != b'-' {
+            break;
+        }
+    }
+    if reply.get(..3).and_then(|found| found.parse::<u16>().ok()) == Some(code) {
+        Ok(reply)
+    } else {
+        Err(io::Error::other(format!("unexpected SMTP reply: {}", reply.trim())))
+    }
+}
+
+// Byte by byte, so nothing is buffered past the reply when switching to TLS.
+fn read_line<S: Read>(stream: &mut S) -> io::Result<String> {
+    let mut line = Vec::new();
+    let mut byte = [0; 1];
+    loop {
+        if stream.read(&mut byte)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the SMTP server closed the connection"));
+        }
+        match byte[0] {
+            b'\n' => break,
+            b'\r' => {},
+            other => line.push(other),
+        }
+    }
+    Ok(String::from_utf8_lossy(&line).into_owned())
+}
+
+fn format_message(from: &str, recipients: &[String], subject: &str, body: &str) -> String {
+    let subject = if subject.is_ascii() {
+        subject.to_string()
+    } else {
+        format!("=?UTF-8?B?{}?=", base64::encode(subject))
+    };
+    let mut message = format!(
+        "From: <{}>\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nMIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\r\n",
+        from,
+        recipients.iter().map(|recipient| format!("<{}>", recipient)).collect::<Vec<String>>().join(", "),
+        subject,
+        Utc::now().to_rfc2822(),
+    );
+    for line in body.lines() {
+        // Dot-stuffing: a line starting with a dot would otherwise end the message early.
+        if line.starts_with('.') {
+            message.push('.');
+        }
+        // A lone carriage return is not a line break everywhere.
+        message.push_str(&line.replace('\r', ""));
+        message.push_str("\r\n");
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use responses::File;
+    use mockito::mock;
+    use sync::transfer::TransferService;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::thread::{self, JoinHandle};
+
+    // Accepts one session, answering every command with success, and returns what it received.
+    fn smtp_stand_in() -> (u16, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut received = Vec::new();
+            writer.write_all(b"220 stand-in ESMTP\r\n").unwrap();
+            let mut in_data = false;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                let line = line.trim_end_matches("\r\n").to_string();
+                received.push(line.clone());
+                let reply: &[u8] = if in_data {
+                    if line != "." {
+                        continue;
+                    }
+                    in_data = false;
+                    b"250 queued\r\n"
+                } else if line.starts_with("EHLO") {
+                    b"250-stand-in\r\n250 AUTH PLAIN\r\n"
+                } else if line.starts_with("AUTH") {
+                    b"235 accepted\r\n"
+                } else if line == "DATA" {
+                    in_data = true;
+                    b"354 go ahead\r\n"
+                } else if line == "QUIT" {
+                    writer.write_all(b"221 bye\r\n").unwrap();
+                    break;
+                } else {
+                    b"250 ok\r\n"
+                };
+                writer.write_all(reply).unwrap();
+            }
+            received
+        });
+        (port, server)
+    }
+
+    fn transfer() -> Transfer {
+        Transfer {
+            id: String::from("notify-transfer"),
+            message: String::from("Renders\n.final"),
+            state: String::from("downloadable"),
+            url: Some(String::from("https://we.tl/t-notify")),
+            expires_at: String::from("2019-01-08T09:30:00Z"),
+            files: vec![File { name: String::from("render.mov"), size: 1536, ..Default::default() }],
+            ..Default::default()
+        }
+    }
+
+    fn config(port: u16) -> SmtpConfig {
+        SmtpConfig { port, security: SmtpSecurity::None, ..SmtpConfig::new("127.0.0.1", "studio@example.com") }
+    }
+
+    #[test]
+    fn it_renders_templates() {
+        let (subject, body) = EmailTemplate { subject: String::from("[{id}] ready"), ..Default::default() }.render(&transfer());
+        assert_eq!(subject, "[notify-transfer] ready");
+        assert_eq!(body, "Renders\n.final\n\nDownload the files at https://we.tl/t-notify\n\n- render.mov (1.5 KB)\n\nThe link expires on 2019-01-08 09:30 UTC.\n");
+    }
+
+    #[test]
+    fn it_sends_transfers_over_smtp() {
+        let (port, server) = smtp_stand_in();
+        let config = SmtpConfig { credentials: Some((String::from("studio"), Secret::new("hunter2"))), ..config(port) };
+        let recipients = vec![String::from("a@example.com"), String::from("b@example.com")];
+        let notifier = Notifier::new(config, recipients, EmailTemplate { subject: String::from("Déjà vu"), ..Default::default() });
+        assert!(notifier.notify(&transfer()).unwrap());
+        assert!(!notifier.notify(&transfer()).unwrap());
+
+        let received = server.join().unwrap();
+        assert_eq!(&received[..6], &["EHLO localhost", "AUTH PLAIN AHN0dWRpbwBodW50ZXIy", "MAIL FROM:<studio@example.com>",
+                                     "RCPT TO:<a@example.com>", "RCPT TO:<b@example.com>", "DATA"]);
+        assert!(received.contains(&String::from("To: <a@example.com>, <b@example.com>")));
+        assert!(received.contains(&String::from("Subject: =?UTF-8?B?RMOpasOgIHZ1?=")));
+        assert!(received.contains(&String::from("..final")));
+        assert_eq!(&received[received.len() - 2..], &[".", "QUIT"]);
+    }
+
+    #[test]
+    fn it_reports_refused_emails() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"554 go away\r\n").unwrap();
+        });
+        let notifier = Notifier::new(config(port), vec![String::from("a@example.com")], EmailTemplate::default());
+        let error = notifier.notify(&transfer()).unwrap_err();
+        assert_eq!(error.message, "Could not email transfer notify-transfer: unexpected SMTP reply: 554 go away");
+    }
+
+    #[test]
+    fn it_refuses_line_breaks_in_headers() {
+        let transfer = Transfer { message: String::from("Renders\r\nBcc: everyone@example.com"), ..transfer() };
+        let (subject, _) = EmailTemplate { subject: String::from("{message}"), ..Default::default() }.render(&transfer);
+        assert_eq!(subject, "Renders Bcc: everyone@example.com");
+
+        let recipients = vec![String::from("a@example.com>\r\nRCPT TO:<b@example.com")];
+        let notifier = Notifier::new(config(1), recipients, EmailTemplate::default());
+        let error = notifier.send("Renders", "").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        let notifier = Notifier::new(config(1), vec![String::from("a@example.com")], EmailTemplate::default());
+        assert_eq!(notifier.send("Renders\nBcc: b@example.com", "").unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn it_returns_finalized_transfers_when_emails_fail() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"554 go away\r\n").unwrap();
+        });
+        let body = r#"{"id": "notify-failing", "state": "processing", "url": "https://we.tl/t-failing", "message": "", "expires_at": "", "files": []}"#;
+        let _finalize = mock("PUT", "/notify-failing/finalize").with_status(200).with_body(body).create();
+
+        let failures = Arc::new(Mutex::new(Vec::new()));
+        let reported = failures.clone();
+        let notifier = Notifier::new(config(port), vec![String::from("a@example.com")], EmailTemplate::default())
+            .on_failure(move |transfer, error| reported.lock().unwrap().push(format!("{}: {}", transfer.id, error.message)));
+        let mut service = TransferService::new("jwt".into(), "1234".into());
+        service.set_notifier(Arc::new(notifier));
+        assert_eq!(service.finalize("notify-failing").unwrap().url, Some(String::from("https://we.tl/t-failing")));
+        assert_eq!(*failures.lock().unwrap(), vec![String::from("notify-failing: Could not email transfer notify-failing: unexpected SMTP reply: 554 go away")]);
+    }
+
+    #[test]
+    fn it_notifies_once_transfers_are_downloadable() {
+        let (port, server) = smtp_stand_in();
+        let body = r#"{"id": "notify-wait", "state": "downloadable", "url": "https://we.tl/t-wait", "message": "", "expires_at": "", "files": []}"#;
+        let _find = mock("GET", "/notify-wait").with_status(200).with_body(body).create();
+
+        let mut service = TransferService::new("jwt".into(), "1234".into());
+        service.set_notifier(Arc::new(Notifier::new(config(port), vec![String::from("a@example.com")], EmailTemplate::default())));
+        let transfer = service.wait_until_downloadable("notify-wait", Duration::from_millis(10), Duration::from_secs(1)).unwrap();
+        assert_eq!(transfer.url, Some(String::from("https://we.tl/t-wait")));
+        assert!(server.join().unwrap().contains(&String::from("Download the files at https://we.tl/t-wait")));
+    }
+}
@@ -0,0 +1,255 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use image::{self, DynamicImage, GenericImageView, ImageDecoder, ImageReader, RgbImage};
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::imageops::FilterType;
+use image::metadata::Orientation;
+
+use responses::WeTransferError;
+use fsutil::TempDir;
+use self::TranscodeError::{Failed, Undecodable};
+
+/// What images are transcoded to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Lossy, at the preset's quality. Transparent areas become white.
+    Jpeg,
+    /// Lossless, keeping transparency; the quality is ignored.
+    WebP,
+}
+
+impl OutputFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::WebP => "webp",
+        }
+    }
+}
+
+/// An image `ImagePreset::apply` could not transcode, and which is uploaded as it is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SkippedImage {
+    pub path: String,
+    pub reason: String,
+}
+
+impl fmt::Display for SkippedImage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: uploaded as it is, could not transcode it: {}", self.path, self.reason)
+    }
+}
+
+/// A named way of shrinking images before they are added to a board, selected
+/// with `BoardService::set_image_preset`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImagePreset {
+    pub name: String,
+    /// Images wider or taller than this are scaled down to fit, keeping their aspect ratio.
+    pub max_dimension: u32,
+    pub format: OutputFormat,
+    /// JPEG quality, from 1 to 100.
+    pub quality: u8,
+    /// Extensions of the files transcoded; other files are uploaded as they are.
+    pub extensions: Vec<String>,
+}
+
+impl ImagePreset {
+    /// `web-preview`: at most 2560 px, JPEG at quality 85.
+    pub fn web_preview() -> ImagePreset {
+        ImagePreset {
+            name: String::from("web-preview"),
+            max_dimension: 2560,
+            format: OutputFormat::Jpeg,
+            quality: 85,
+            extensions: ["jpg", "jpeg", "png", "tif", "tiff", "webp"].iter().map(|extension| extension.to_string()).collect(),
+        }
+    }
+
+    /// Transcodes the images among `paths` into a new spool directory. The
+    /// returned paths keep the order of `paths`, and images keep their name
+    /// with the extension of the output format. Images which cannot be decoded
+    /// are kept as they are and listed in `skipped`.
+    pub fn apply<S: AsRef<str>>(&self, paths: &[S]) -> Result<Transcoded, WeTransferError> {
+        let mut transcoded = Transcoded { paths: Vec::new(), skipped: Vec::new(), directory: TempDir::new("preset")? };
+        for (index, path) in paths.iter().enumerate() {
+            let path = path.as_ref();
+            let output = transcoded.directory.path().join(index.to_string());
+            match self.transcode(Path::new(path), &output) {
+                Ok(Some(output)) => transcoded.paths.push(output.to_string_lossy().into_owned()),
+                Ok(None) => transcoded.paths.push(path.to_string()),
+                Err(Undecodable(reason)) => {
+                    transcoded.skipped.push(SkippedImage { path: path.to_string(), reason });
+                    transcoded.paths.push(path.to_string());
+                },
+                Err(Failed(error)) => return Err(error),
+            }
+        }
+        Ok(transcoded)
+    }
+
+    // Writes the image at `path` into `directory`, or returns `None` when it is left as it is.
+    fn transcode(&self, path: &Path, directory: &Path) -> Result<Option<PathBuf>, TranscodeError> {
+        let extension = path.extension().map(|extension| extension.to_string_lossy().to_ascii_lowercase()).unwrap_or_default();
+        if !self.extensions.iter().any(|matched| matched.eq_ignore_ascii_case(&extension)) {
+            return Ok(None);
+        }
+        let (image, rotated) = match open(path) {
+            Ok(opened) => opened,
+            Err(image::ImageError::IoError(error)) => return Err(Failed(preset_error(path, error.to_string()))),
+            Err(error) => return Err(Undecodable(error.to_string())),
+        };
+        let (width, height) = image.dimensions();
+        let fits = width <= self.max_dimension && height <= self.max_dimension;
+        let same_format = match self.format {
+            OutputFormat::Jpeg => extension == "jpg" || extension == "jpeg",
+            OutputFormat::WebP => extension == "webp",
+        };
+        if fits && same_format && !rotated {
+            return Ok(None);
+        }
+        let image = if fits { image } else { image.resize(self.max_dimension, self.max_dimension, FilterType::Lanczos3) };
+
+        fs::create_dir_all(directory).map_err(|error| Failed(preset_error(directory, error.to_string())))?;
+        let output = directory.join(path.with_extension(self.format.extension()).file_name().unwrap_or_default());
+        let file = File::create(&output).map_err(|error| Failed(preset_error(&output, error.to_string())))?;
+        self.encode(&image, BufWriter::new(file)).map_err(|error| Failed(preset_error(&output, error.to_string())))?;
+        Ok(Some(output))
+    }
+
+    fn encode(&self, image: &DynamicImage, writer: BufWriter<File>) -> image::ImageResult<()> {
+        match self.format {
+            OutputFormat::Jpeg => JpegEncoder::new_with_quality(writer, self.quality.clamp(1, 100)).encode_image(&flatten(image)),
+            OutputFormat::WebP if image.color().has_alpha() => DynamicImage::from(image.to_rgba8()).write_with_encoder(WebPEncoder::new_lossless(writer)),
+            OutputFormat::WebP => DynamicImage::from(image.to_rgb8()).write_with_encoder(WebPEncoder::new_lossless(writer)),
+        }
+    }
+}
+
+// Decodes the image at `path`, turned upright according to its EXIF orientation.
+// Also returns whether it had to be turned.
+fn open(path: &Path) -> image::ImageResult<(DynamicImage, bool)> {
+    let mut decoder = ImageReader::open(path)?.with_guessed_format()?.into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+    Ok((image, orientation != Orientation::NoTransforms))
+}
+
+// Blends transparent pixels onto white, as JPEG has no alpha channel.
+fn flatten(image: &DynamicImage) -> RgbImage {
+    if !image.color().has_alpha() {
+        return image.to_rgb8();
+    }
+    let rgba = image.to_rgba8();
+    RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let pixel = rgba.get_pixel(x, y).0;
+        let alpha = pixel[3] as u32;
+        let blend = |channel: u8| ((channel as u32 * alpha + 255 * (255 - alpha) + 127) / 255) as u8;
+        image::Rgb([blend(pixel[0]), blend(pixel[1]), blend(pixel[2])])
+    })
+}
+
+/// The paths produced by `ImagePreset::apply`. Its spool directory is removed when dropped.
+#[derive(Debug)]
+pub struct Transcoded {
+    pub paths: Vec<String>,
+    /// Images uploaded as they are, since they could not be decoded.
+    pub skipped: Vec<SkippedImage>,
+    pub(crate) directory: TempDir,
+}
+
+// Why an image was not transcoded: broken images are uploaded as they are,
+// while failing to read or write files stops the upload.
+enum TranscodeError {
+    Undecodable(String),
+    Failed(WeTransferError),
+}
+
+fn preset_error(path: &Path, message: String) -> WeTransferError {
+    WeTransferError {
+        status: 0,
+        message: format!("Could not transcode {}: {}", path.display(), message),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use image::{ImageFormat, RgbImage};
+
+    fn image(name: &str, width: u32, height: u32) -> String {
+        let directory = env::temp_dir().join("wetransfer-preset");
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join(name);
+        RgbImage::from_pixel(width, height, image::Rgb([200, 40, 40])).save(&path).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn it_shrinks_and_converts_images() {
+        let large = image("large.png", 40, 20);
+        let small = image("small.jpg", 8, 8);
+        let notes = env::temp_dir().join("wetransfer-preset").join("notes.txt");
+        fs::write(&notes, "notes").unwrap();
+        let notes = notes.to_string_lossy().into_owned();
+
+        let preset = ImagePreset { max_dimension: 10, ..ImagePreset::web_preview() };
+        let transcoded = preset.apply(&[&large, &small, &notes]).unwrap();
+        let output = transcoded.paths[0].clone();
+        assert!(output.ends_with("large.jpg"));
+        assert_eq!(image::ImageFormat::from_path(&output).unwrap(), ImageFormat::Jpeg);
+        assert_eq!(image::open(&output).unwrap().dimensions(), (10, 5));
+        assert_eq!(&transcoded.paths[1..], &[small, notes]);
+
+        drop(transcoded);
+        assert!(!Path::new(&output).exists());
+    }
+
+    #[test]
+    fn it_uploads_undecodable_images_as_they_are() {
+        let small = image("preview.png", 8, 4);
+        let broken = env::temp_dir().join("wetransfer-preset").join("broken.tif");
+        fs::write(&broken, "not an image").unwrap();
+        let broken = broken.to_string_lossy().into_owned();
+
+        let transcoded = ImagePreset::web_preview().apply(&[&broken, &small]).unwrap();
+        assert_eq!(transcoded.paths[0], broken);
+        assert!(transcoded.paths[1].ends_with("preview.jpg"));
+        assert_eq!(transcoded.skipped.len(), 1);
+        assert_eq!(transcoded.skipped[0].path, broken);
+        assert!(transcoded.skipped[0].to_string().starts_with(&format!("{}: uploaded as it is", broken)));
+
+        let missing = env::temp_dir().join("wetransfer-preset").join("missing.png");
+        assert!(ImagePreset::web_preview().apply(&[missing.to_str().unwrap()]).unwrap_err().message.starts_with("Could not transcode"));
+    }
+
+    #[test]
+    fn it_turns_images_upright_and_flattens_transparency() {
+        let directory = env::temp_dir().join("wetransfer-preset");
+        let mut jpeg = Vec::new();
+        JpegEncoder::new(&mut jpeg).encode_image(&RgbImage::new(30, 10)).unwrap();
+        // An EXIF segment with the orientation "rotate 90° clockwise".
+        let exif: &[u8] = b"\xFF\xE1\x00\x22Exif\0\0MM\0\x2A\0\0\0\x08\0\x01\x01\x12\0\x03\0\0\0\x01\0\x06\0\0\0\0\0\0";
+        let rotated = directory.join("rotated.jpg");
+        fs::write(&rotated, [&jpeg[..2], exif, &jpeg[2..]].concat()).unwrap();
+        let transparent = directory.join("transparent.png");
+        image::RgbaImage::from_pixel(4, 4, image::Rgba([0, 0, 0, 0])).save(&transparent).unwrap();
+
+        let transcoded = ImagePreset::web_preview().apply(&[rotated.to_str().unwrap(), transparent.to_str().unwrap()]).unwrap();
+        assert_eq!(image::open(&transcoded.paths[0]).unwrap().dimensions(), (10, 30));
+        let flattened = image::open(&transcoded.paths[1]).unwrap().to_rgb8();
+        assert!(flattened.pixels().all(|pixel| pixel.0.iter().all(|&channel| channel > 245)));
+
+        let webp = ImagePreset { format: OutputFormat::WebP, ..ImagePreset::web_preview() };
+        let transcoded = webp.apply(&[transparent.to_str().unwrap()]).unwrap();
+        assert!(transcoded.paths[0].ends_with("transparent.webp"));
+        assert_eq!(image::open(&transcoded.paths[0]).unwrap().to_rgba8().get_pixel(0, 0).0[3], 0);
+    }
+}
@@ -0,0 +1,632 @@
+use std::fmt;
+use std::fs;
+use std::io::{Cursor, Read, Write};
+use std::path::Path;
+
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+use zip::write::FileOptions;
+
+use responses::WeTransferError;
+use fsutil::{io_error, TempDir};
+use sync::spool::TempCopies;
+
+/// What was removed from a file before uploading it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StripReport {
+    pub path: String,
+    /// the copy uploaded instead of `path`; `None` when there was nothing to remove.
+    pub cleaned_path: Option<String>,
+    /// e.g. `EXIF`, `GPS`, `XMP`, `document properties`.
+    pub removed: Vec<String>,
+    pub original_size: u64,
+    pub cleaned_size: u64,
+}
+
+impl fmt::Display for StripReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.removed.is_empty() {
+            write!(f, "{}: no metadata found", self.path)
+        } else {
+            write!(f, "{}: removed {} ({} -> {} bytes)", self.path, self.removed.join(", "), self.original_size, self.cleaned_size)
+        }
+    }
+}
+
+type Reporter = Box<dyn Fn(&StripReport) + Send + Sync>;
+
+/// Removes EXIF, XMP and IPTC metadata from JPEG, PNG and TIFF images, and the
+/// document properties of PDF, Office (OOXML) and OpenDocument files, along
+/// with the metadata of the images embedded in them, before they are uploaded. Cleaned copies keep their file name and live in the temp
+/// directory until the returned `TempCopies` are dropped, which uploads do once
+/// they are over. Other files, and files larger than `max_size`, are uploaded
+/// as they are.
+pub struct MetadataStripper {
+    pub max_size: u64,
+    reporter: Option<Reporter>,
+}
+
+impl fmt::Debug for MetadataStripper {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MetadataStripper").field("max_size", &self.max_size).finish()
+    }
+}
+
+impl Default for MetadataStripper {
+    fn default() -> MetadataStripper {
+        MetadataStripper { max_size: 512 * 1024 * 1024, reporter: None }
+    }
+}
+
+impl MetadataStripper {
+    /// Constructor.
+    pub fn new() -> MetadataStripper {
+        MetadataStripper::default()
+    }
+
+    /// Receives the report of every file cleaned by `clean_all`.
+    pub fn on_report<F: Fn(&StripReport) + Send + Sync + 'static>(mut self, reporter: F) -> MetadataStripper {
+        self.reporter = Some(Box::new(reporter));
+        self
+    }
+
+    /// Writes a cleaned copy of the file at `path` when it holds metadata.
+    pub fn clean(&self, path: &str) -> Result<(StripReport, TempCopies), WeTransferError> {
+        let original_size = fs::metadata(path).map_err(|error| io_error(Path::new(path), error))?.len();
+        let mut report = StripReport {
+            path: path.to_string(),
+            cleaned_path: None,
+            removed: Vec::new(),
+            original_size,
+            cleaned_size: original_size,
+        };
+        if original_size > self.max_size {
+            return Ok((report, TempCopies::default()));
+        }
+        let name = Path::new(path).file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        let contents = fs::read(path).map_err(|error| io_error(Path::new(path), error))?;
+        let (cleaned, removed) = strip_metadata(&name, &contents);
+        if removed.is_empty() {
+            return Ok((report, TempCopies::default()));
+        }
+
+        let directory = TempDir::new("clean")?;
+        let cleaned_path = directory.path().join(&name);
+        fs::write(&cleaned_path, &cleaned).map_err(|error| io_error(&cleaned_path, error))?;
+        report.cleaned_path = Some(cleaned_path.to_string_lossy().into_owned());
+        report.removed = removed;
+        report.cleaned_size = cleaned.len() as u64;
+        Ok((report, TempCopies::new(vec![directory])))
+    }
+
+    /// Cleans every file, returning the paths to upload in the same order and
+    /// the copies to keep until the upload is over.
+    pub fn clean_all<S: AsRef<str>>(&self, paths: &[S]) -> Result<(Vec<String>, TempCopies), WeTransferError> {
+        let mut copies = TempCopies::default();
+        let paths = paths.iter().map(|path| {
+            let (report, copy) = self.clean(path.as_ref())?;
+            if let Some(ref reporter) = self.reporter {
+                reporter(&report);
+            }
+            copies.merge(copy);
+            Ok(report.cleaned_path.unwrap_or(report.path))
+        }).collect::<Result<Vec<String>, WeTransferError>>()?;
+        Ok((paths, copies))
+    }
+}
+
+/// Returns `contents` without its metadata, and what was removed. The format
+/// is told from the contents for images, and from `name`'s extension for documents.
+pub fn strip_metadata(name: &str, contents: &[u8]) -> (Vec<u8>, Vec<String>) {
+    let extension = Path::new(name).extension().map(|extension| extension.to_string_lossy().to_ascii_lowercase()).unwrap_or_default();
+    let stripped = if contents.starts_with(&[0xFF, 0xD8]) {
+        strip_jpeg(contents)
+    } else if contents.starts_with(PNG_SIGNATURE) {
+        strip_png(contents)
+    } else if contents.starts_with(b"II*\0") || contents.starts_with(b"MM\0*") {
+        strip_tiff(contents)
+    } else if contents.starts_with(b"%PDF") {
+        strip_pdf(contents)
+    } else if OFFICE_EXTENSIONS.contains(&extension.as_str()) {
+        strip_office(contents)
+    } else {
+        None
+    };
+    match stripped {
+        Some((cleaned, mut removed)) if !removed.is_empty() => {
+            removed.dedup();
+            (cleaned, removed)
+        },
+        _ => (contents.to_vec(), Vec::new()),
+    }
+}
+
+fn strip_jpeg(contents: &[u8]) -> Option<(Vec<u8>, Vec<String>)> {
+    let mut cleaned = contents[..2].to_vec();
+    let mut removed = Vec::new();
+    let mut position = 2;
+    while position + 4 <= contents.len() && contents[position] == 0xFF {
+        let marker = contents[position + 1];
+        // Start of scan or end of image: the rest is image data.
+        if marker == 0xDA || marker == 0xD9 {
+            break;
+        }
+        if marker == 0xFF || marker == 0x01 || (0xD0..=0xD7).contains(&marker) {
+            cleaned.extend_from_slice(&contents[position..position + 2]);
+            position += 2;
+            continue;
+        }
+        let end = (position + 2 + read_u16(contents, position + 2, true)? as usize).min(contents.len());
+        let payload = &contents[(position + 4).min(end)..end];
+        let kind = match marker {
+            0xE1 if payload.starts_with(b"Exif\0") => Some("EXIF"),
+            0xE1 if payload.starts_with(b"http://ns.adobe.com/") => Some("XMP"),
+            0xED => Some("IPTC"),
+            0xFE => Some("comment"),
+            _ => None,
+        };
+        match kind {
+            Some(kind) => removed.push(kind.to_string()),
+            None => cleaned.extend_from_slice(&contents[position..end]),
+        }
+        position = end;
+    }
+    cleaned.extend_from_slice(&contents[position.min(contents.len())..]);
+    Some((cleaned, removed))
+}
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+fn strip_png(contents: &[u8]) -> Option<(Vec<u8>, Vec<String>)> {
+    let mut cleaned = PNG_SIGNATURE.to_vec();
+    let mut removed = Vec::new();
+    let mut position = PNG_SIGNATURE.len();
+    while position + 12 <= contents.len() {
+        let end = (position + 12 + read_u32(contents, position, true)? as usize).min(contents.len());
+        let kind = &contents[position + 4..position + 8];
+        let data = &contents[position + 8..end];
+        let dropped = match kind {
+            b"eXIf" => Some("EXIF"),
+            b"iTXt" if data.starts_with(b"XML:com.adobe.xmp\0") => Some("XMP"),
+            b"tEXt" | b"zTXt" | b"iTXt" => Some("text"),
+            b"tIME" => Some("timestamp"),
+            _ => None,
+        };
+        match dropped {
+            Some(dropped) => removed.push(dropped.to_string()),
+            None => cleaned.extend_from_slice(&contents[position..end]),
+        }
+        position = end;
+    }
+    cleaned.extend_from_slice(&contents[position.min(contents.len())..]);
+    Some((cleaned, removed))
+}
+
+// Tags removed from TIFF directories, with what they hold.
+const TIFF_TAGS: [(u16, &str); 13] = [
+    (270, "description"), (271, "camera make"), (272, "camera model"), (305, "software"), (306, "date"),
+    (315, "artist"), (316, "host computer"), (700, "XMP"), (33432, "copyright"), (33723, "IPTC"),
+    (34377, "Photoshop"), (34665, "EXIF"), (34853, "GPS"),
+];
+
+// Tags pointing to another directory.
+const TIFF_POINTERS: [u16; 3] = [34665, 34853, 40965];
+
+// Rewrites the directories in place, blanking what removed entries pointed to,
+// so that no other offset in the file changes.
+fn strip_tiff(contents: &[u8]) -> Option<(Vec<u8>, Vec<String>)> {
+    let big_endian = contents.starts_with(b"MM");
+    let mut cleaned = contents.to_vec();
+    let mut removed = Vec::new();
+    let mut offset = read_u32(contents, 4, big_endian)? as usize;
+    let mut visited = 0;
+    while offset != 0 && visited < 64 {
+        visited += 1;
+        let count = read_u16(&cleaned, offset, big_endian)? as usize;
+        let entries_end = offset + 2 + 12 * count;
+        let next = read_u32(&cleaned, entries_end, big_endian)?;
+        let mut kept = Vec::new();
+        for index in 0..count {
+            let entry = offset + 2 + 12 * index;
+            let tag = read_u16(&cleaned, entry, big_endian)?;
+            match TIFF_TAGS.iter().find(|(removed_tag, _)| *removed_tag == tag) {
+                Some(&(_, kind)) => {
+                    removed.push(kind.to_string());
+                    blank_entry(&mut cleaned, entry, big_endian, 0);
+                },
+                None => kept.push(cleaned[entry..entry + 12].to_vec()),
+            }
+        }
+        write_u16(&mut cleaned, offset, kept.len() as u16, big_endian);
+        let mut position = offset + 2;
+        for entry in kept {
+            cleaned[position..position + 12].copy_from_slice(&entry);
+            position += 12;
+        }
+        write_u32(&mut cleaned, position, next, big_endian);
+        for byte in &mut cleaned[position + 4..entries_end + 4] {
+            *byte = 0;
+        }
+        offset = next as usize;
+    }
+    Some((cleaned, removed))
+}
+
+// Zeroes the value of a directory entry stored elsewhere in the file, and the whole directory it points to, if any.
+fn blank_entry(contents: &mut [u8], entry: usize, big_endian: bool, depth: usize) -> Option<()> {
+    let tag = read_u16(contents, entry, big_endian)?;
+    let kind = read_u16(contents, entry + 2, big_endian)?;
+    let count = read_u32(contents, entry + 4, big_endian)? as usize;
+    let value = read_u32(contents, entry + 8, big_endian)? as usize;
+    let size = count.saturating_mul(match kind { 3 | 8 => 2, 4 | 9 | 11 | 13 => 4, 5 | 10 | 12 => 8, _ => 1 });
+    if size > 4 {
+        blank(contents, value, size);
+    }
+    if TIFF_POINTERS.contains(&tag) && depth < 4 {
+        let entries = read_u16(contents, value, big_endian)? as usize;
+        for index in 0..entries {
+            blank_entry(contents, value + 2 + 12 * index, big_endian, depth + 1);
+        }
+        blank(contents, value, 2 + 12 * entries + 4);
+    }
+    Some(())
+}
+
+// Blanks the document information dictionary and uncompressed XMP streams with
+// spaces, which keeps every byte offset of the cross-reference table valid.
+fn strip_pdf(contents: &[u8]) -> Option<(Vec<u8>, Vec<String>)> {
+    let mut cleaned = contents.to_vec();
+    let mut removed = Vec::new();
+
+    let mut search = 0;
+    while let Some(found) = find(&cleaned, b"/Info", search) {
+        search = found + 5;
+        let reference = String::from_utf8_lossy(&cleaned[search..(search + 32).min(cleaned.len())]).into_owned();
+        let parts: Vec<&str> = reference.split_whitespace().take(3).collect();
+        if parts.len() < 3 || !parts[2].starts_with('R') {
+            continue;
+        }
+        let header = format!("{} {} obj", parts[0], parts[1]);
+        let object = match find_object(&cleaned, header.as_bytes()) {
+            Some(object) => object,
+            None => continue,
+        };
+        if let Some((start, end)) = find(&cleaned, b"<<", object).and_then(|start| dictionary_end(&cleaned, start).map(|end| (start + 2, end))) {
+            if cleaned[start..end].iter().any(|byte| !byte.is_ascii_whitespace()) {
+                blank_with_spaces(&mut cleaned[start..end]);
+                removed.push(String::from("document info"));
+            }
+        }
+    }
+
+    let mut search = 0;
+    while let Some(found) = find(&cleaned, b"/Metadata", search) {
+        search = found + 9;
+        let start = match cleaned[..found].windows(2).rposition(|window| window == b"<<") {
+            Some(start) => start,
+            None => continue,
+        };
+        let end = match dictionary_end(&cleaned, start) {
+            Some(end) if end > found => end,
+            _ => continue,
+        };
+        let dictionary = String::from_utf8_lossy(&cleaned[start..end]).replace(' ', "");
+        if !dictionary.contains("/Type/Metadata") || dictionary.contains("/Filter") {
+            continue;
+        }
+        let data_start = match find(&cleaned, b"stream", end) {
+            Some(keyword) if keyword - end < 16 => keyword + 6 + if cleaned.get(keyword + 6) == Some(&b'\r') { 2 } else { 1 },
+            _ => continue,
+        };
+        if let Some(data_end) = find(&cleaned, b"endstream", data_start) {
+            blank_with_spaces(&mut cleaned[data_start..data_end]);
+            removed.push(String::from("XMP"));
+            search = data_end;
+        }
+    }
+    Some((cleaned, removed))
+}
+
+const OFFICE_EXTENSIONS: [&str; 12] = ["docx", "docm", "dotx", "xlsx", "xlsm", "xltx", "pptx", "pptm", "potx", "odt", "ods", "odp"];
+
+// Entries replaced in Office and OpenDocument files, with what they hold and their blank version.
+const OFFICE_METADATA: [(&str, &str, &str); 4] = [
+    ("docProps/core.xml", "document properties", "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\r\n<cp:coreProperties xmlns:cp=\"http://schemas.openxmlformats.org/package/2006/metadata/core-properties\" xmlns:dc=\"http://purl.org/dc/elements/1.1/\" xmlns:dcterms=\"http://purl.org/dc/terms/\" xmlns:dcmitype=\"http://purl.org/dc/dcmitype/\" xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\"/>"),
+    ("docProps/app.xml", "application properties", "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\r\n<Properties xmlns=\"http://schemas.openxmlformats.org/officeDocument/2006/extended-properties\" xmlns:vt=\"http://schemas.openxmlformats.org/officeDocument/2006/docPropsVTypes\"/>"),
+    ("docProps/custom.xml", "custom properties", "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\r\n<Properties xmlns=\"http://schemas.openxmlformats.org/officeDocument/2006/custom-properties\" xmlns:vt=\"http://schemas.openxmlformats.org/officeDocument/2006/docPropsVTypes\"/>"),
+    ("meta.xml", "document properties", "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<office:document-meta xmlns:office=\"urn:oasis:names:tc:opendocument:xmlns:office:1.0\" office:version=\"1.2\"><office:meta/></office:document-meta>"),
+];
+
+// Whether an Office or OpenDocument entry is an embedded image or thumbnail,
+// e.g. `word/media/image1.jpg`, `docProps/thumbnail.jpeg` or `Pictures/photo.png`.
+fn is_embedded_media(name: &str) -> bool {
+    name.contains("/media/") || name.starts_with("docProps/thumbnail.") || name.starts_with("Pictures/") || name.starts_with("Thumbnails/")
+}
+
+// Embedded media larger than this once decompressed are copied as they are,
+// so that a crafted archive cannot exhaust the memory.
+const MAX_MEDIA_SIZE: u64 = 64 * 1024 * 1024;
+
+fn strip_office(contents: &[u8]) -> Option<(Vec<u8>, Vec<String>)> {
+    let mut archive = ZipArchive::new(Cursor::new(contents)).ok()?;
+    let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+    let mut removed = Vec::new();
+    for index in 0..archive.len() {
+        let mut entry = archive.by_index_raw(index).ok()?;
+        let name = entry.name().to_string();
+        if let Some(&(_, kind, blank)) = OFFICE_METADATA.iter().find(|(metadata, _, _)| *metadata == name) {
+            drop(entry);
+            writer.start_file(name, FileOptions::default().compression_method(CompressionMethod::Deflated)).ok()?;
+            writer.write_all(blank.as_bytes()).ok()?;
+            removed.push(kind.to_string());
+            continue;
+        }
+        if is_embedded_media(&name) && entry.size() <= MAX_MEDIA_SIZE {
+            let method = entry.compression();
+            drop(entry);
+            let mut media = Vec::new();
+            // The declared size may lie.
+            archive.by_index(index).ok()?.take(MAX_MEDIA_SIZE + 1).read_to_end(&mut media).ok()?;
+            let (cleaned, kinds) = if media.len() as u64 <= MAX_MEDIA_SIZE { strip_metadata(&name, &media) } else { (Vec::new(), Vec::new()) };
+            if !kinds.is_empty() {
+                writer.start_file(name.as_str(), FileOptions::default().compression_method(method)).ok()?;
+                writer.write_all(&cleaned).ok()?;
+                removed.extend(kinds.into_iter().map(|kind| format!("{} in {}", kind, name)));
+                continue;
+            }
+            entry = archive.by_index_raw(index).ok()?;
+        }
+        writer.raw_copy_file(entry).ok()?;
+    }
+    let cleaned = writer.finish().ok()?.into_inner();
+    Some((cleaned, removed))
+}
+
+fn find(contents: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    if from >= contents.len() {
+        return None;
+    }
+    contents[from..].windows(needle.len()).position(|window| window == needle).map(|position| from + position)
+}
+
+// `header` (e.g. `12 0 obj`) at the start of the file or after whitespace.
+fn find_object(contents: &[u8], header: &[u8]) -> Option<usize> {
+    let mut search = 0;
+    while let Some(found) = find(contents, header, search) {
+        if found == 0 || contents[found - 1].is_ascii_whitespace() {
+            return Some(found + header.len());
+        }
+        search = found + 1;
+    }
+    None
+}
+
+// Position of the `>>` closing the dictionary opened at `start`, skipping nested dictionaries and strings.
+fn dictionary_end(contents: &[u8], start: usize) -> Option<usize> {
+    let (mut depth, mut strings, mut position) = (0, 0, start);
+    while position + 1 < contents.len() {
+        match (contents[position], contents[position + 1]) {
+            (b'\\', _) if strings > 0 => position += 1,
+            (b'(', _) => strings += 1,
+            (b')', _) if strings > 0 => strings -= 1,
+            (b'<', b'<') if strings == 0 => {
+                depth += 1;
+                position += 1;
+            },
+            (b'>', b'>') if strings == 0 => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(position);
+                }
+                position += 1;
+            },
+            _ => {},
+        }
+        position += 1;
+    }
+    None
+}
+
+fn blank_with_spaces(bytes: &mut [u8]) {
+    for byte in bytes.iter_mut().filter(|byte| **byte != b'\n' && **byte != b'\r') {
+        *byte = b' ';
+    }
+}
+
+fn blank(contents: &mut [u8], offset: usize, size: usize) {
+    let end = offset.saturating_add(size).min(contents.len());
+    if offset < end {
+        for byte in &mut contents[offset..end] {
+            *byte = 0;
+        }
+    }
+}
+
+fn read_u16(contents: &[u8], offset: usize, big_endian: bool) -> Option<u16> {
+    let bytes = [*contents.get(offset)?, *contents.get(offset + 1)?];
+    Some(if big_endian { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) })
+}
+
+fn read_u32(contents: &[u8], offset: usize, big_endian: bool) -> Option<u32> {
+    let bytes = [*contents.get(offset)?, *contents.get(offset + 1)?, *contents.get(offset + 2)?, *contents.get(offset + 3)?];
+    Some(if big_endian { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) })
+}
+
+fn write_u16(contents: &mut [u8], offset: usize, value: u16, big_endian: bool) {
+    let bytes = if big_endian { value.to_be_bytes() } else { value.to_le_bytes() };
+    contents[offset..offset + 2].copy_from_slice(&bytes);
+}
+
+fn write_u32(contents: &mut [u8], offset: usize, value: u32, big_endian: bool) {
+    let bytes = if big_endian { value.to_be_bytes() } else { value.to_le_bytes() };
+    contents[offset..offset + 4].copy_from_slice(&bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::sync::{Arc, Mutex};
+    use mockito::{mock, Matcher};
+    use sync::board::BoardService;
+
+    fn segment(marker: u8, payload: &[u8]) -> Vec<u8> {
+        let mut segment = vec![0xFF, marker];
+        segment.extend_from_slice(&((payload.len() + 2) as u16).to_be_bytes());
+        segment.extend_from_slice(payload);
+        segment
+    }
+
+    fn chunk(kind: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+        chunk.extend_from_slice(kind);
+        chunk.extend_from_slice(data);
+        chunk.extend_from_slice(&[0; 4]);
+        chunk
+    }
+
+    #[test]
+    fn it_strips_jpeg_and_png_metadata() {
+        let jfif = segment(0xE0, b"JFIF\0\x01\x01");
+        let image_data = [0xFF, 0xDA, 0x00, 0x02, 0x12, 0x34, 0xFF, 0xD9];
+        let jpeg: Vec<u8> = [&[0xFF, 0xD8][..], &jfif, &segment(0xE1, b"Exif\0\0GPS serial"), &segment(0xE1, b"http://ns.adobe.com/xap/1.0/\0<x/>"),
+                             &segment(0xED, b"Photoshop 3.0"), &segment(0xFE, b"comment"), &image_data].concat();
+        let (cleaned, removed) = strip_metadata("photo.jpg", &jpeg);
+        assert_eq!(cleaned, [&[0xFF, 0xD8][..], &jfif, &image_data].concat());
+        assert_eq!(removed, vec!["EXIF", "XMP", "IPTC", "comment"]);
+
+        let header = chunk(b"IHDR", &[0; 13]);
+        let end = chunk(b"IEND", &[]);
+        let png: Vec<u8> = [PNG_SIGNATURE, &header, &chunk(b"eXIf", b"MM\0*"), &chunk(b"iTXt", b"XML:com.adobe.xmp\0<x/>"),
+                            &chunk(b"tEXt", b"Author\0Jane"), &end].concat();
+        let (cleaned, removed) = strip_metadata("image.png", &png);
+        assert_eq!(cleaned, [PNG_SIGNATURE, &header, &end].concat());
+        assert_eq!(removed, vec!["EXIF", "XMP", "text"]);
+
+        assert_eq!(strip_metadata("clean.png", &cleaned), (cleaned.clone(), Vec::new()));
+    }
+
+    #[test]
+    fn it_strips_tiff_metadata_in_place() {
+        // Little endian: IFD0 at 8 with width, make (out of line at 50) and a GPS pointer (IFD at 56).
+        let mut tiff = b"II*\0\x08\0\0\0".to_vec();
+        tiff.extend_from_slice(&3u16.to_le_bytes());
+        for &(tag, kind, count, value) in &[(256u16, 3u16, 1u32, 64u32), (271, 2, 6, 50), (34853, 4, 1, 56)] {
+            tiff.extend_from_slice(&tag.to_le_bytes());
+            tiff.extend_from_slice(&kind.to_le_bytes());
+            tiff.extend_from_slice(&count.to_le_bytes());
+            tiff.extend_from_slice(&value.to_le_bytes());
+        }
+        tiff.extend_from_slice(&0u32.to_le_bytes());
+        tiff.extend_from_slice(b"Canon\0");
+        tiff.extend_from_slice(&1u16.to_le_bytes());
+        tiff.extend_from_slice(&[2, 0, 5, 0, 1, 0, 0, 0, 74, 0, 0, 0, 0, 0, 0, 0]);
+        tiff.extend_from_slice(b"latitude");
+
+        let (cleaned, removed) = strip_metadata("scan.tif", &tiff);
+        assert_eq!(removed, vec!["camera make", "GPS"]);
+        assert_eq!(cleaned.len(), tiff.len());
+        assert_eq!(&cleaned[8..24], &[1, 0, 0, 1, 3, 0, 1, 0, 0, 0, 64, 0, 0, 0, 0, 0]);
+        assert!(cleaned[24..].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn it_blanks_pdf_metadata() {
+        let pdf = "%PDF-1.4\n1 0 obj\n<< /Type /Catalog /Metadata 3 0 R >>\nendobj\n\
+                   2 0 obj\n<< /Author (Jane \\(Studio\\)) /Producer (Writer) >>\nendobj\n\
+                   3 0 obj\n<< /Type /Metadata /Subtype /XML /Length 12 >>\nstream\n<x:xmpmeta/>\nendstream\nendobj\n\
+                   trailer\n<< /Root 1 0 R /Info 2 0 R >>\n%%EOF\n";
+        let (cleaned, removed) = strip_metadata("brief.pdf", pdf.as_bytes());
+        let cleaned = String::from_utf8(cleaned).unwrap();
+        assert_eq!(removed, vec!["document info", "XMP"]);
+        assert_eq!(cleaned.len(), pdf.len());
+        assert!(!cleaned.contains("Jane") && !cleaned.contains("xmpmeta"));
+        assert!(cleaned.contains("<< /Type /Catalog /Metadata 3 0 R >>"));
+    }
+
+    #[test]
+    fn it_replaces_office_properties() {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        writer.start_file("word/document.xml", FileOptions::default()).unwrap();
+        writer.write_all(b"<w:document>Deliverables</w:document>").unwrap();
+        writer.start_file("docProps/core.xml", FileOptions::default()).unwrap();
+        writer.write_all(b"<cp:coreProperties><dc:creator>Jane</dc:creator></cp:coreProperties>").unwrap();
+        writer.start_file("word/media/image1.jpg", FileOptions::default().compression_method(CompressionMethod::Stored)).unwrap();
+        writer.write_all(&[&[0xFF, 0xD8][..], &segment(0xE1, b"Exif\0\0GPS"), &[0xFF, 0xD9]].concat()).unwrap();
+        writer.start_file("docProps/thumbnail.jpeg", FileOptions::default()).unwrap();
+        writer.write_all(&[0xFF, 0xD8, 0xFF, 0xD9]).unwrap();
+        let docx = writer.finish().unwrap().into_inner();
+
+        let (cleaned, removed) = strip_metadata("Brief.DOCX", &docx);
+        assert_eq!(removed, vec!["document properties", "EXIF in word/media/image1.jpg"]);
+        let mut archive = ZipArchive::new(Cursor::new(cleaned)).unwrap();
+        let mut read = |name: &str| {
+            let mut contents = Vec::new();
+            archive.by_name(name).unwrap().read_to_end(&mut contents).unwrap();
+            contents
+        };
+        assert_eq!(read("word/document.xml"), b"<w:document>Deliverables</w:document>");
+        assert!(!String::from_utf8(read("docProps/core.xml")).unwrap().contains("Jane"));
+        assert_eq!(read("word/media/image1.jpg"), vec![0xFF, 0xD8, 0xFF, 0xD9]);
+        assert_eq!(read("docProps/thumbnail.jpeg"), vec![0xFF, 0xD8, 0xFF, 0xD9]);
+    }
+
+    #[test]
+    fn it_writes_cleaned_copies_under_the_same_name() {
+        let directory = env::temp_dir().join("wetransfer-privacy");
+        fs::create_dir_all(&directory).unwrap();
+        let photo = directory.join("photo.jpg");
+        fs::write(&photo, [&[0xFF, 0xD8][..], &segment(0xFE, b"comment"), &[0xFF, 0xD9]].concat()).unwrap();
+        let notes = directory.join("notes.txt");
+        fs::write(&notes, "notes").unwrap();
+
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let reported = reports.clone();
+        let stripper = MetadataStripper::new().on_report(move |report| reported.lock().unwrap().push(report.to_string()));
+        let (paths, copies) = stripper.clean_all(&[photo.to_str().unwrap(), notes.to_str().unwrap()]).unwrap();
+        let cleaned = ::std::path::PathBuf::from(&paths[0]);
+        assert_eq!(cleaned.file_name(), photo.file_name());
+        assert_eq!(fs::read(&cleaned).unwrap(), vec![0xFF, 0xD8, 0xFF, 0xD9]);
+        assert_eq!(paths[1], notes.to_str().unwrap());
+        assert_eq!(*reports.lock().unwrap(), vec![
+            format!("{}: removed comment (15 -> 4 bytes)", photo.display()),
+            format!("{}: no metadata found", notes.display()),
+        ]);
+        drop(copies);
+        assert!(!cleaned.exists());
+    }
+
+    #[test]
+    fn it_declares_the_size_of_cleaned_copies() {
+        let directory = env::temp_dir().join("wetransfer-privacy-board");
+        fs::create_dir_all(&directory).unwrap();
+        let photo = directory.join("holiday.jpg");
+        fs::write(&photo, [&[0xFF, 0xD8][..], &segment(0xE1, b"Exif\0\0GPS"), &[0xFF, 0xD9]].concat()).unwrap();
+        let files = r#"[{"id": "clean-file", "name": "holiday.jpg", "size": 4, "type": "file", "multipart": {"id": "m", "part_numbers": 1, "chunk_size": 4}}]"#;
+        let add = mock("POST", "/privacy-board/files")
+          .match_body(Matcher::JsonString(r#"[{"name": "holiday.jpg", "size": 4}]"#.to_string()))
+          .with_status(201)
+          .with_body(files)
+          .create();
+
+        let mut service = BoardService::new("jwt".into(), "1234".into());
+        service.set_metadata_stripper(Arc::new(MetadataStripper::new()));
+        let progress = service.start_upload("privacy-board", &[photo.to_str().unwrap()]).unwrap();
+        let copy = progress.files[0].path.clone();
+        assert_ne!(copy, photo.to_str().unwrap());
+        assert_eq!(progress.files[0].source.as_deref(), photo.to_str());
+        assert_eq!(fs::metadata(&copy).unwrap().len(), 4);
+        add.assert();
+
+        // A saved progress does not keep the copy, which is made again on resume.
+        let saved = ::serde_json::to_string(&progress).unwrap();
+        drop(progress);
+        assert!(!Path::new(&copy).exists());
+        let upload_url = format!("{{\"success\": true, \"url\": \"{}/privacy-s3\"}}", ::mockito::SERVER_URL);
+        let _url = mock("GET", "/privacy-board/files/clean-file/upload-url/1/m").with_status(200).with_body(upload_url.as_str()).create();
+        let s3 = mock("PUT", "/privacy-s3").with_status(200).create();
+        let _complete = mock("PUT", "/privacy-board/files/clean-file/upload-complete").with_status(200).with_body(r#"{"success": true, "message": "File is marked as complete."}"#).create();
+        let report = service.resume_file_uploads(::serde_json::from_str(&saved).unwrap(), &::sync::cancel::CancellationToken::new()).unwrap();
+        assert_eq!(report.files[0].path, photo.to_str().unwrap());
+        s3.assert();
+    }
+}
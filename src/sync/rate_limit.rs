@@ -0,0 +1,192 @@
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use httpdate;
+use reqwest::header::HeaderMap;
+use responses::RateLimit;
+
+/// A client-side token bucket. Share one instance (through `Client::set_rate_limiter`)
+/// between every thread using the same `Client` to stay under the API quota.
+#[derive(Debug)]
+pub struct RateLimiter {
+    capacity: f64,
+    refill_per_second: f64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+    blocked_until: Option<Instant>,
+}
+
+impl RateLimiter {
+    /// Constructor. Allows `requests_per_second` on average, with bursts of up to `burst` requests.
+    /// A rate which is not a positive number leaves requests unlimited, apart from `hold_off`.
+    pub fn new(requests_per_second: f64, burst: u32) -> RateLimiter {
+        let capacity = f64::from(burst.max(1));
+        RateLimiter {
+            capacity,
+            refill_per_second: if requests_per_second > 0.0 { requests_per_second } else { f64::INFINITY },
+            bucket: Mutex::new(Bucket {
+                tokens: capacity,
+                last_refill: Instant::now(),
+                blocked_until: None,
+            }),
+        }
+    }
+
+    /// Takes a token if one is available right now.
+    pub fn try_acquire(&self) -> bool {
+        self.reserve().is_none()
+    }
+
+    /// Blocks the current thread until a token is available, then takes it.
+    pub fn acquire(&self) {
+        while let Some(wait) = self.reserve() {
+            thread::sleep(wait);
+        }
+    }
+
+    /// Empties the bucket and blocks every caller for `wait`. Used when
+    /// the API answers with `429 Too Many Requests`.
+    pub fn hold_off(&self, wait: Duration) {
+        let mut bucket = self.bucket.lock().unwrap();
+        let until = Instant::now() + wait;
+        bucket.tokens = 0.0;
+        bucket.blocked_until = match bucket.blocked_until {
+            Some(current) if current > until => Some(current),
+            _ => Some(until),
+        };
+    }
+
+    // Takes a token and returns `None`, or returns how long to wait for the next one.
+    fn reserve(&self) -> Option<Duration> {
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        if let Some(until) = bucket.blocked_until {
+            if until > now {
+                return Some(until - now);
+            }
+            bucket.blocked_until = None;
+            bucket.last_refill = now;
+        }
+        if self.refill_per_second.is_infinite() {
+            return None;
+        }
+        let elapsed = now.duration_since(bucket.last_refill);
+        let elapsed_secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        bucket.tokens = (bucket.tokens + elapsed_secs * self.refill_per_second).min(self.capacity);
+        bucket.last_refill = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            None
+        } else {
+            let missing = (1.0 - bucket.tokens) / self.refill_per_second;
+            Some(Duration::from_millis((missing * 1000.0).ceil() as u64))
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// Decides what happens when the API answers with `429 Too Many Requests`.
+pub struct RateLimitPolicy {
+    /// How many times the request is retried before the error is reported.
+    pub max_retries: u32,
+    /// Rate-limited requests asking to wait longer than this fail right away.
+    pub max_wait: Duration,
+}
+
+impl Default for RateLimitPolicy {
+    /// Reports the rate-limit error without waiting, as the API returned it.
+    fn default() -> RateLimitPolicy {
+        RateLimitPolicy { max_retries: 0, max_wait: Duration::from_secs(60) }
+    }
+}
+
+impl RateLimitPolicy {
+    /// Retries up to `max_retries` times, waiting as long as the API asks up to `max_wait`.
+    pub fn wait(max_retries: u32, max_wait: Duration) -> RateLimitPolicy {
+        RateLimitPolicy { max_retries, max_wait }
+    }
+}
+
+/// Reads `Retry-After` and the `X-RateLimit-*` headers of a response.
+pub fn parse_rate_limit(headers: &HeaderMap) -> RateLimit {
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok()).map(str::trim);
+    RateLimit {
+        retry_after: header("retry-after").and_then(parse_retry_after),
+        limit: header("x-ratelimit-limit").and_then(|value| value.parse().ok()),
+        remaining: header("x-ratelimit-remaining").and_then(|value| value.parse().ok()),
+        reset: header("x-ratelimit-reset").and_then(|value| value.parse().ok()),
+    }
+}
+
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    httpdate::parse_http_date(value).ok().map(|date| {
+        date.duration_since(SystemTime::now()).unwrap_or_else(|_| Duration::from_secs(0))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    #[test]
+    fn it_limits_bursts() {
+        let limiter = RateLimiter::new(1.0, 2);
+        assert!(limiter.try_acquire());
+        assert!(limiter.try_acquire());
+        assert!(!limiter.try_acquire());
+    }
+
+    #[test]
+    fn it_treats_non_positive_rates_as_unlimited() {
+        for rate in &[0.0, -1.0, f64::NAN] {
+            let limiter = RateLimiter::new(*rate, 1);
+            for _ in 0..10 {
+                assert!(limiter.try_acquire());
+            }
+            limiter.hold_off(Duration::from_secs(60));
+            assert!(!limiter.try_acquire());
+        }
+    }
+
+    #[test]
+    fn it_holds_off_every_caller() {
+        let limiter = RateLimiter::new(1000.0, 5);
+        limiter.hold_off(Duration::from_millis(50));
+        assert!(!limiter.try_acquire());
+        let start = Instant::now();
+        limiter.acquire();
+        assert!(start.elapsed() >= Duration::from_millis(40));
+    }
+
+    #[test]
+    fn it_parses_rate_limit_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("retry-after", HeaderValue::from_static("7"));
+        headers.insert("x-ratelimit-limit", HeaderValue::from_static("100"));
+        headers.insert("x-ratelimit-remaining", HeaderValue::from_static("0"));
+        headers.insert("x-ratelimit-reset", HeaderValue::from_static("1548513285"));
+        let rate_limit = parse_rate_limit(&headers);
+        assert_eq!(rate_limit.retry_after, Some(Duration::from_secs(7)));
+        assert_eq!(rate_limit.limit, Some(100));
+        assert_eq!(rate_limit.remaining, Some(0));
+        assert_eq!(rate_limit.reset, Some(1_548_513_285));
+    }
+
+    #[test]
+    fn it_parses_http_dates_in_retry_after() {
+        let date = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(120));
+        let wait = parse_retry_after(&date).unwrap();
+        assert!(wait > Duration::from_secs(100) && wait <= Duration::from_secs(120));
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"), Some(Duration::from_secs(0)));
+    }
+}
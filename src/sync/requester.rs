@@ -1,67 +1,117 @@
 use serde::de::DeserializeOwned;
 use serde::Serialize;
-use reqwest::{Response, Client};
+use reqwest::{Response, Client, RequestBuilder, StatusCode};
 use reqwest::header::{AUTHORIZATION, ACCEPT, CONTENT_TYPE, HeaderValue, HeaderMap, HeaderName};
 use responses::WeTransferError;
-use std::error::Error;
+use secret::Secret;
+use zeroize::Zeroize;
+use sync::rate_limit::{self, RateLimiter, RateLimitPolicy};
+use sync::bandwidth::{BandwidthLimiter, ThrottledReader};
+use sync::cancel::{CancellableReader, CancellationToken};
+use std::io::{Cursor, Read};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
 /// A wrapper around `reqwest::Client` that builds requests 
 /// and parses their responses into well-known structs.
 #[derive(Debug)]
 pub struct RequestService {
     http_client: Client,
-    jwt: String,
-    app_token: String,
-    base_url: String
+    jwt: Secret,
+    app_token: Secret,
+    base_url: String,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    rate_limit_policy: RateLimitPolicy,
+    bandwidth_limiter: Option<Arc<BandwidthLimiter>>,
 }
 
 impl RequestService {
     /// Constructor.
-    pub fn new(jwt: String, app_token: String, base_url: String) -> RequestService {
+    pub fn new(jwt: Secret, app_token: Secret, base_url: String) -> RequestService {
         RequestService {
             http_client: Client::new(),
-            jwt: jwt.to_string(),
-            app_token: app_token.to_string(),
-            base_url: base_url.to_string()
+            jwt,
+            app_token,
+            base_url: base_url.to_string(),
+            rate_limiter: None,
+            rate_limit_policy: RateLimitPolicy::default(),
+            bandwidth_limiter: None,
         }
     }
 
+    /// Throttles the S3 part uploads through `limiter`.
+    pub fn set_bandwidth_limiter(&mut self, limiter: Arc<BandwidthLimiter>) {
+        self.bandwidth_limiter = Some(limiter);
+    }
+
+    /// Makes every API call take a token from `limiter` before being sent.
+    pub fn set_rate_limiter(&mut self, limiter: Arc<RateLimiter>) {
+        self.rate_limiter = Some(limiter);
+    }
+
+    /// Sets whether `429 Too Many Requests` responses are retried or reported.
+    pub fn set_rate_limit_policy(&mut self, policy: RateLimitPolicy) {
+        self.rate_limit_policy = policy;
+    }
+
     /// Performs a GET HTTP requests
     pub fn get<U: DeserializeOwned>(&self, path: &str) -> Result<U, WeTransferError> {
         let url = format!("{}{}", self.base_url, path);
-        let result = self.http_client
+        self.execute(|| self.http_client
             .get(url.as_str())
-            .headers(self.construct_headers())
-            .send();
-        self.handle_response(result)
+            .headers(self.construct_headers()))
     }
 
     /// Performs a POST HTTP request
     pub fn post<T: Serialize, U: DeserializeOwned>(&self, path: &str, payload: T) -> Result<U, WeTransferError> {
         let url = format!("{}{}", self.base_url, path);
-        let result = self.http_client
+        self.execute(|| self.http_client
             .post(url.as_str())
             .headers(self.construct_headers())
-            .json(&payload).send();
-        self.handle_response(result)
+            .json(&payload))
     }
 
     /// Performs a PUT HTTP request
     pub fn put<T: Serialize, U: DeserializeOwned>(&self, path: &str, payload: T) -> Result<U, WeTransferError> {
         let url = format!("{}{}", self.base_url, path);
-        let result = self.http_client
+        self.execute(|| self.http_client
             .put(url.as_str())
             .headers(self.construct_headers())
-            .json(&payload).send();
-        self.handle_response(result)
+            .json(&payload))
     }
 
     /// Performs a file upload using a presigned S3 url.
     pub fn file_upload<S: Into<String>+ToString>(&self, url: S, io: &[u8]) -> Result<reqwest::Response, WeTransferError> {
-        let result = self.http_client
-            .put(url.to_string().as_str())
-            .body(io.to_vec())
-            .send();
+        self.upload(url.to_string(), io, None)
+    }
+
+    /// Performs a file upload using a presigned S3 url, aborting it as soon as `cancel` is cancelled.
+    pub fn file_upload_cancellable<S: Into<String>+ToString>(&self, url: S, io: &[u8], cancel: &CancellationToken) -> Result<reqwest::Response, WeTransferError> {
+        self.upload(url.to_string(), io, Some(cancel))
+    }
+
+    fn upload(&self, url: String, io: &[u8], cancel: Option<&CancellationToken>) -> Result<reqwest::Response, WeTransferError> {
+        if cancel.is_some_and(CancellationToken::is_cancelled) {
+            return Err(Self::cancelled_error());
+        }
+        let request = self.http_client.put(url.as_str());
+        let request = if self.bandwidth_limiter.is_none() && cancel.is_none() {
+            request.body(io.to_vec())
+        } else {
+            let mut reader: Box<dyn Read + Send> = Box::new(Cursor::new(io.to_vec()));
+            if let Some(token) = cancel {
+                reader = Box::new(CancellableReader::new(reader, token.clone()));
+            }
+            if let Some(ref limiter) = self.bandwidth_limiter {
+                reader = Box::new(ThrottledReader::new(reader, limiter.clone()));
+            }
+            request.body(reqwest::Body::sized(reader, io.len() as u64))
+        };
+        let result = request.send();
+        if cancel.is_some_and(CancellationToken::is_cancelled) {
+            return Err(Self::cancelled_error());
+        }
         match result {
             Ok(response) => {
                 if response.status().is_success() {
@@ -70,16 +120,62 @@ impl RequestService {
                     Err(WeTransferError{
                         status: response.status().as_u16(),
                         message: String::from("S3 file upload failed"),
+                        ..Default::default()
                     })
                 }
             },
             Err(error_raw) => Err(WeTransferError {
                 status: 0,
-                message: format!("Error while uploading file for transfer: {}", error_raw.description().to_string())
+                message: format!("Error while uploading file for transfer: {}", error_raw),
+                ..Default::default()
             })
         }
     }
 
+    fn cancelled_error() -> WeTransferError {
+        WeTransferError {
+            status: 0,
+            message: String::from("Upload cancelled"),
+            cancelled: Some(Box::default()),
+            ..Default::default()
+        }
+    }
+
+    // Sends the request built by `build`, going through the rate limiter and
+    // retrying `429` responses as long as the policy allows it.
+    fn execute<U: DeserializeOwned, F: Fn() -> RequestBuilder>(&self, build: F) -> Result<U, WeTransferError> {
+        let mut attempt = 0;
+        loop {
+            if let Some(ref limiter) = self.rate_limiter {
+                limiter.acquire();
+            }
+            match build().send() {
+                Ok(response) => {
+                    if response.status() != StatusCode::TOO_MANY_REQUESTS {
+                        return self.handle_response(Ok(response));
+                    }
+                    let rate_limit = rate_limit::parse_rate_limit(response.headers());
+                    let wait = rate_limit.retry_after.unwrap_or_else(|| Duration::from_secs(1 << attempt.min(6)));
+                    if let Some(ref limiter) = self.rate_limiter {
+                        limiter.hold_off(wait);
+                    }
+                    if attempt >= self.rate_limit_policy.max_retries || wait > self.rate_limit_policy.max_wait {
+                        let mut error = self.handle_response::<()>(Ok(response)).unwrap_err();
+                        if error.status == 0 {
+                            error.message = String::from("Rate limit exceeded");
+                        }
+                        error.status = StatusCode::TOO_MANY_REQUESTS.as_u16();
+                        error.rate_limit = Some(rate_limit);
+                        return Err(error);
+                    }
+                    attempt += 1;
+                    thread::sleep(wait);
+                },
+                Err(error) => return self.handle_response(Err(error)),
+            }
+        }
+    }
+
     fn handle_response<U: DeserializeOwned>(&self, result: Result<Response, reqwest::Error>) -> Result<U, WeTransferError> {
         match result {
             Ok(mut response) => {
@@ -87,35 +183,41 @@ impl RequestService {
                     match response.json::<U>() {
                         Ok(final_response) => Ok(final_response),
                         Err(error) => {
-                            Err(WeTransferError { status: 0, message: error.to_string() })
+                            Err(WeTransferError { status: 0, message: error.to_string(), ..Default::default() })
                         }
                     }
                 } else {
-                    let parsed_result = response.json::<WeTransferError>();
-                    println!("{:?}", parsed_result);
-                    match parsed_result {
+                    match response.json::<WeTransferError>() {
                         Ok(mut wetransfer_error) => {
                             wetransfer_error.status = response.status().as_u16();
                             Err(wetransfer_error)
                         },
                         Err(_) => Err(WeTransferError{
                             status: 0,
-                            message: String::from("Error while parsing a WeTransfer error response. Contact mainteners.")
+                            message: String::from("Error while parsing a WeTransfer error response. Contact mainteners."),
+                            ..Default::default()
                         }) 
                     }
                 }
             },
             Err(error) => Err(WeTransferError {
                 status: 0,
-                message: error.description().to_string()
+                message: error.to_string(),
+                ..Default::default()
             })
         }
     }
 
+    // The only place where the secrets are read in cleartext. Header values
+    // are flagged as sensitive so they are not printed by `reqwest` either.
     fn construct_headers(&self) -> HeaderMap {
         let mut headers = HeaderMap::new();
-        let api_key_value= HeaderValue::from_str(self.app_token.as_str()).unwrap();
-        let jwt_value = HeaderValue::from_str(format!("Bearer {}", self.jwt).as_str()).unwrap();
+        let mut api_key_value = HeaderValue::from_str(self.app_token.expose()).unwrap();
+        api_key_value.set_sensitive(true);
+        let mut bearer = format!("Bearer {}", self.jwt.expose());
+        let mut jwt_value = HeaderValue::from_str(bearer.as_str()).unwrap();
+        bearer.zeroize();
+        jwt_value.set_sensitive(true);
         headers.insert(HeaderName::from_static("x-api-key"), api_key_value);
         headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
         headers.insert(AUTHORIZATION, jwt_value);
@@ -129,6 +231,46 @@ mod tests {
     use super::*;
     use mockito::mock;
 
+    #[test]
+    fn it_does_not_leak_secrets_in_debug_output() {
+        let service = RequestService::new("jwt-token".into(), "app-token".into(), mockito::SERVER_URL.to_string());
+        let output = format!("{:?}", service);
+        assert!(!output.contains("jwt-token"));
+        assert!(!output.contains("app-token"));
+    }
+
+    #[test]
+    fn it_reports_rate_limits() {
+        let _m = mock("GET", "/rate-limited")
+          .with_status(429)
+          .with_header("Retry-After", "30")
+          .with_header("X-RateLimit-Remaining", "0")
+          .with_body("{\"message\": \"Too many requests\"}")
+          .create();
+        let service = RequestService::new("jwt-token".into(), "1234".into(), mockito::SERVER_URL.to_string());
+        let error = service.get::<::responses::Transfer>("/rate-limited").unwrap_err();
+        assert_eq!(error.status, 429);
+        assert_eq!(error.message, "Too many requests");
+        let rate_limit = error.rate_limit.unwrap();
+        assert_eq!(rate_limit.retry_after, Some(Duration::from_secs(30)));
+        assert_eq!(rate_limit.remaining, Some(0));
+    }
+
+    #[test]
+    fn it_retries_rate_limited_requests() {
+        let m = mock("GET", "/rate-limited-retry")
+          .with_status(429)
+          .with_header("Retry-After", "0")
+          .expect(3)
+          .create();
+        let mut service = RequestService::new("jwt-token".into(), "1234".into(), mockito::SERVER_URL.to_string());
+        service.set_rate_limit_policy(RateLimitPolicy::wait(2, Duration::from_secs(1)));
+        let error = service.get::<::responses::Transfer>("/rate-limited-retry").unwrap_err();
+        assert_eq!(error.status, 429);
+        assert_eq!(error.message, "Rate limit exceeded");
+        m.assert();
+    }
+
     #[test]
     fn it_uploads_files_to_s3() {
         let url = format!("{}/upload", mockito::SERVER_URL);
@@ -137,4 +279,35 @@ mod tests {
         let result = service.file_upload(url, &[0;10]);
         assert!(result.is_ok());
     }
+
+    #[test]
+    fn it_cancels_uploads_in_flight() {
+        let url = format!("{}/cancelled-upload", mockito::SERVER_URL);
+        let _m = mock("PUT", "/cancelled-upload").with_status(200).create();
+        let mut service = RequestService::new("jwt-token".into(), "1234".into(), mockito::SERVER_URL.to_string());
+        service.set_bandwidth_limiter(Arc::new(BandwidthLimiter::new(Some(16 * 1024))));
+        let token = CancellationToken::new();
+        let canceller = token.clone();
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            canceller.cancel();
+        });
+        let start = ::std::time::Instant::now();
+        let error = service.file_upload_cancellable(url, &[0; 256 * 1024], &token).unwrap_err();
+        handle.join().unwrap();
+        assert!(error.cancelled.is_some());
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn it_throttles_uploads_to_s3() {
+        let url = format!("{}/throttled-upload", mockito::SERVER_URL);
+        let _m = mock("PUT", "/throttled-upload").with_status(200).match_body("a".repeat(2048).as_str()).create();
+        let mut service = RequestService::new("jwt-token".into(), "1234".into(), mockito::SERVER_URL.to_string());
+        service.set_bandwidth_limiter(Arc::new(BandwidthLimiter::new(Some(4096))));
+        let start = ::std::time::Instant::now();
+        let result = service.file_upload(url, &[b'a'; 2048]);
+        assert!(result.is_ok());
+        assert!(start.elapsed() >= Duration::from_millis(400));
+    }
 }
@@ -0,0 +1,177 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use responses::WeTransferError;
+use fsutil::{create_temp_file, TempDir};
+
+/// Bounds how input of unknown length is buffered before being uploaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpoolOptions {
+    /// Input up to this size stays in memory; larger input goes to a temp file.
+    pub memory_threshold: u64,
+    /// Input larger than this is rejected.
+    pub max_size: u64,
+}
+
+impl Default for SpoolOptions {
+    fn default() -> SpoolOptions {
+        SpoolOptions { memory_threshold: 8 * 1024 * 1024, max_size: 2 * 1024 * 1024 * 1024 }
+    }
+}
+
+/// Where spooled input ended up.
+#[derive(Debug, PartialEq)]
+pub enum Spooled {
+    Memory(Vec<u8>),
+    File(TempFile),
+}
+
+/// A file in the temp directory, only readable by the current user and removed when dropped.
+#[derive(Debug, PartialEq)]
+pub struct TempFile {
+    path: PathBuf,
+    size: u64,
+}
+
+impl TempFile {
+    /// Writes `contents` to a new temp file.
+    pub fn write(contents: &[u8]) -> Result<TempFile, WeTransferError> {
+        let (path, mut file) = TempFile::create()?;
+        let temp_file = TempFile { path, size: contents.len() as u64 };
+        file.write_all(contents).map_err(|error| temp_file.error(error))?;
+        Ok(temp_file)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn create() -> Result<(PathBuf, File), WeTransferError> {
+        create_temp_file("spool")
+    }
+
+    fn error(&self, error: io::Error) -> WeTransferError {
+        WeTransferError {
+            status: 0,
+            message: format!("Error while writing {}: {}", self.path.display(), error),
+            ..Default::default()
+        }
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// The directories holding copies of files made for one upload, e.g. without
+/// metadata or compressed. They are removed once the last clone is dropped.
+#[derive(Debug, Clone, Default)]
+pub struct TempCopies {
+    directories: Vec<Arc<TempDir>>,
+}
+
+impl TempCopies {
+    pub(crate) fn new(directories: Vec<TempDir>) -> TempCopies {
+        TempCopies { directories: directories.into_iter().map(Arc::new).collect() }
+    }
+
+    /// Keeps the copies of `other` as well.
+    pub fn merge(&mut self, other: TempCopies) {
+        self.directories.extend(other.directories);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.directories.is_empty()
+    }
+}
+
+// Only where the copies live, which says nothing about the upload itself.
+impl PartialEq for TempCopies {
+    fn eq(&self, _: &TempCopies) -> bool {
+        true
+    }
+}
+
+/// Reads `reader` to its end, keeping it in memory or in a temp file depending
+/// on its size, so that the size is known before the upload starts.
+pub fn spool<R: Read>(mut reader: R, options: &SpoolOptions) -> Result<Spooled, WeTransferError> {
+    let mut buffer = Vec::new();
+    (&mut reader).take(options.memory_threshold + 1).read_to_end(&mut buffer).map_err(read_error)?;
+    if buffer.len() as u64 > options.max_size {
+        return Err(too_large(options));
+    }
+    if buffer.len() as u64 <= options.memory_threshold {
+        return Ok(Spooled::Memory(buffer));
+    }
+    let (path, mut file) = TempFile::create()?;
+    // Created first, so the file is removed on every error below.
+    let mut temp_file = TempFile { path, size: 0 };
+    file.write_all(&buffer).map_err(|error| temp_file.error(error))?;
+    let remaining = options.max_size - buffer.len() as u64;
+    let copied = io::copy(&mut reader.take(remaining + 1), &mut file).map_err(read_error)?;
+    if copied > remaining {
+        return Err(too_large(options));
+    }
+    file.flush().map_err(|error| temp_file.error(error))?;
+    temp_file.size = buffer.len() as u64 + copied;
+    Ok(Spooled::File(temp_file))
+}
+
+fn read_error(error: io::Error) -> WeTransferError {
+    WeTransferError {
+        status: 0,
+        message: format!("Error while reading the input: {}", error),
+        ..Default::default()
+    }
+}
+
+fn too_large(options: &SpoolOptions) -> WeTransferError {
+    WeTransferError {
+        status: 0,
+        message: format!("The input is larger than {} bytes", options.max_size),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn it_keeps_small_input_in_memory() {
+        let options = SpoolOptions { memory_threshold: 4, max_size: 10 };
+        assert_eq!(spool(Cursor::new(b"abcd".to_vec()), &options).unwrap(), Spooled::Memory(b"abcd".to_vec()));
+    }
+
+    #[test]
+    fn it_spools_large_input_to_temp_files() {
+        let options = SpoolOptions { memory_threshold: 4, max_size: 10 };
+        let path = match spool(Cursor::new(b"abcdefghij".to_vec()), &options).unwrap() {
+            Spooled::File(temp_file) => {
+                assert_eq!(temp_file.size(), 10);
+                assert_eq!(fs::read(temp_file.path()).unwrap(), b"abcdefghij");
+                temp_file.path().to_path_buf()
+            },
+            Spooled::Memory(_) => panic!("expected a temp file"),
+        };
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn it_rejects_input_above_the_limit() {
+        let options = SpoolOptions { memory_threshold: 4, max_size: 10 };
+        let error = spool(Cursor::new(b"abcdefghijk".to_vec()), &options).unwrap_err();
+        assert_eq!(error.message, "The input is larger than 10 bytes");
+        let error = spool(Cursor::new(b"abcdefghijk".to_vec()), &SpoolOptions { memory_threshold: 20, max_size: 10 }).unwrap_err();
+        assert_eq!(error.message, "The input is larger than 10 bytes");
+    }
+}
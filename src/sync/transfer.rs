@@ -1,10 +1,24 @@
 use sync::requester::RequestService;
+use secret::Secret;
+use sync::rate_limit::{RateLimiter, RateLimitPolicy};
+use sync::bandwidth::BandwidthLimiter;
+use sync::cancel::CancellationToken;
+use sync::upload::{self, UploadOptions, UploadTarget};
+use sync::builder::TransferBuilder;
+use sync::notify::Notifier;
+use sync::policy::UploadPolicy;
+use sync::privacy::MetadataStripper;
+use sync::compress::Compressor;
+use sync::spool::TempCopies;
+use sync::webhook::{WebhookDispatcher, WebhookEvent};
+use history::History;
+use dedup::DedupCache;
+use std::sync::Arc;
 use std::fs;
 use std::path::Path;
-use std::fs::File;
-use std::error::Error;
-use std::io::prelude::*;
 use std::borrow::ToOwned;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use responses::*;
 use requests::*;
@@ -13,6 +27,13 @@ use requests::*;
 /// A service to perform operations related to file Transfers. 
 pub struct TransferService {
     requester: RequestService,
+    history: Option<Arc<History>>,
+    dedup: Option<Arc<DedupCache>>,
+    notifier: Option<Arc<Notifier>>,
+    webhooks: Option<Arc<WebhookDispatcher>>,
+    policy: Option<Arc<UploadPolicy>>,
+    stripper: Option<Arc<MetadataStripper>>,
+    compressor: Option<Arc<Compressor>>,
 }
 
 #[cfg(not(test))]
@@ -21,36 +42,273 @@ const TRANSFERS_URL: &str = "https://dev.wetransfer.com/v2/transfers";
 const TRANSFERS_URL: &str = mockito::SERVER_URL;
 
 impl TransferService {
-    pub fn new(jwt: String, app_token: String) -> TransferService {
+    pub fn new(jwt: Secret, app_token: Secret) -> TransferService {
         TransferService {
-            requester: RequestService::new(jwt, app_token, TRANSFERS_URL.to_owned())
+            requester: RequestService::new(jwt, app_token, TRANSFERS_URL.to_owned()),
+            history: None,
+            dedup: None,
+            notifier: None,
+            webhooks: None,
+            policy: None,
+            stripper: None,
+            compressor: None,
         }
     }
 
+    pub fn set_rate_limiter(&mut self, limiter: Arc<RateLimiter>) {
+        self.requester.set_rate_limiter(limiter);
+    }
+
+    pub fn set_rate_limit_policy(&mut self, policy: RateLimitPolicy) {
+        self.requester.set_rate_limit_policy(policy);
+    }
+
+    pub fn set_bandwidth_limiter(&mut self, limiter: Arc<BandwidthLimiter>) {
+        self.requester.set_bandwidth_limiter(limiter);
+    }
+
+    /// Records every finalized transfer in `history`, and keeps it up to date on `find`.
+    pub fn set_history(&mut self, history: Arc<History>) {
+        self.history = Some(history);
+    }
+
+    /// Reuses live transfers holding identical contents instead of uploading them again.
+    pub fn set_dedup_cache(&mut self, cache: Arc<DedupCache>) {
+        self.dedup = Some(cache);
+    }
+
+    /// Emails the link of every transfer once `finalize` or `wait_until_downloadable` succeeds.
+    pub fn set_notifier(&mut self, notifier: Arc<Notifier>) {
+        self.notifier = Some(notifier);
+    }
+
+    /// Sends transfer events (finalized, downloadable, upload failed) to `webhooks`.
+    pub fn set_webhooks(&mut self, webhooks: Arc<WebhookDispatcher>) {
+        self.webhooks = Some(webhooks);
+    }
+
+    /// Screens the files of every new transfer with `policy` before creating it.
+    pub fn set_upload_policy(&mut self, policy: Arc<UploadPolicy>) {
+        self.policy = Some(policy);
+    }
+
+    /// Uploads copies of the files without their metadata, once the upload policy let them through.
+    pub fn set_metadata_stripper(&mut self, stripper: Arc<MetadataStripper>) {
+        self.stripper = Some(stripper);
+    }
+
+    /// Uploads compressed copies of the files `compressor` selects, named after their codec,
+    /// e.g. `export.csv.wtz.gz`. `Downloader` decompresses them again.
+    pub fn set_compressor(&mut self, compressor: Arc<Compressor>) {
+        self.compressor = Some(compressor);
+    }
+
     pub fn find<S: Into<String>>(&self, transfer_id: S) -> Result<Transfer, WeTransferError> {
         let path = format!("/{}", transfer_id.into());
-        self.requester.get::<Transfer>(&path)
+        let transfer = self.requester.get::<Transfer>(&path)?;
+        if let Some(ref history) = self.history {
+            // The history is best effort: a failing write must not hide the API response.
+            let _ = history.update_transfer(&transfer);
+        }
+        Ok(transfer)
+    }
+
+    /// Starts describing a transfer, for when `create` is not flexible enough.
+    pub fn builder(&self) -> TransferBuilder<'_> {
+        TransferBuilder::new(self)
     }
 
     pub fn create(&self, message: &str, paths: &[&str]) -> Result<Transfer, WeTransferError> {
-        let transfer = self.create_transfer_request(message, paths)?;
-        for (index, file) in transfer.files.iter().enumerate() {
-            let path = Path::new(paths[index]);
-            let mut file_io = File::open(path).unwrap();
-            let mut buffer = std::vec::from_elem(0, file.multipart.chunk_size as usize);
-            for part in 1..=file.multipart.part_numbers {
-                file_io.read_exact(&mut buffer).unwrap();
-                let s3_url = self.upload_url_for(&transfer.id, &file.id, part)?.url;
-                self.requester.file_upload(s3_url, &buffer).unwrap();
+        self.create_with_cancellation(message, paths, &CancellationToken::new())
+    }
+
+    /// Same as `create`, but stops as soon as `cancel` is cancelled. The returned
+    /// error then carries an `UploadProgress` which can be handed to `resume`.
+    pub fn create_with_cancellation(&self, message: &str, paths: &[&str], cancel: &CancellationToken) -> Result<Transfer, WeTransferError> {
+        if let Some(transfer) = self.find_duplicate(message, paths)? {
+            return Ok(transfer);
+        }
+        self.resume(self.start_upload(message, paths)?, cancel)
+    }
+
+    /// Uploads whatever is left of a transfer, then finalizes it.
+    pub fn resume(&self, mut progress: UploadProgress, cancel: &CancellationToken) -> Result<Transfer, WeTransferError> {
+        self.resume_observed(&mut progress, cancel, |_| {})
+    }
+
+    /// Same as `resume`, keeping `progress` up to date and calling `on_part` after every uploaded part.
+    pub fn resume_observed<F: FnMut(&UploadProgress)>(&self, progress: &mut UploadProgress, cancel: &CancellationToken, on_part: F) -> Result<Transfer, WeTransferError> {
+        let report = upload::upload_files_observed(self, progress, cancel, on_part).map_err(|error| self.upload_failed(progress, error))?;
+        self.complete_upload(progress, report)
+    }
+
+    /// Same as `resume_observed`, following `options`.
+    pub fn resume_with<F: FnMut(&UploadProgress) + Send>(&self, progress: &mut UploadProgress, cancel: &CancellationToken, options: &UploadOptions, on_part: F) -> Result<Transfer, WeTransferError> {
+        let report = upload::upload_files_with(self, progress, cancel, options, on_part).map_err(|error| self.upload_failed(progress, error))?;
+        self.complete_upload(progress, report)
+    }
+
+    fn complete_upload(&self, progress: &UploadProgress, report: IntegrityReport) -> Result<Transfer, WeTransferError> {
+        let mut transfer = self.finalize(&progress.upload_id).map_err(|error| self.upload_failed(progress, error))?;
+        transfer.integrity = Some(report);
+        if let Some(ref cache) = self.dedup {
+            // Copies are gone once the upload is over: the originals are what later uploads are compared with.
+            let paths: Vec<&str> = progress.files.iter().map(|file| file.source.as_ref().unwrap_or(&file.path).as_str()).collect();
+            // The cache is best effort: a failing write must not hide the finalized transfer.
+            let _ = cache.store(&paths, &transfer);
+        }
+        Ok(transfer)
+    }
+
+    /// Continues a transfer created by a previous run, knowing only its id. Local
+    /// files go through the upload policy, metadata stripper and compressor again,
+    /// and are matched to the transfer's files by the name and size they are
+    /// uploaded with; any difference
+    /// between both lists is reported as an error before uploading anything.
+    /// Files the API reports as uploaded are skipped. For the others, the API does
+    /// not tell which parts already reached S3, so every part is sent again.
+    /// Transfers no longer `uploading` are returned as they are.
+    pub fn resume_from_server(&self, transfer_id: &str, paths: &[&str]) -> Result<Transfer, WeTransferError> {
+        let transfer = self.find(transfer_id)?;
+        if transfer.state != "uploading" {
+            return Ok(transfer);
+        }
+        let (originals, paths, copies) = self.prepare(paths)?;
+        let order = self.match_local_files(&transfer, &originals, &paths)?;
+        let ordered_paths: Vec<String> = order.iter().map(|&index| paths[index].clone()).collect();
+        let ordered_originals: Vec<String> = order.iter().map(|&index| originals[index].clone()).collect();
+        let mut progress = UploadProgress::from_transfer(&transfer, &ordered_paths).with_copies(&ordered_originals, copies);
+        for (file, progress) in transfer.files.iter().zip(progress.files.iter_mut()) {
+            if file.state.as_ref().is_some_and(|state| state == "uploaded" || state == "completed") {
+                progress.completed_parts = (1..=progress.part_numbers).collect();
+                progress.completed = true;
+            }
+        }
+        self.resume(progress, &CancellationToken::new())
+    }
+
+    // Returns the indices of the local files in the order of `transfer.files`, comparing
+    // the files uploaded from `paths` and naming their `originals` in errors.
+    fn match_local_files(&self, transfer: &Transfer, originals: &[String], paths: &[String]) -> Result<Vec<usize>, WeTransferError> {
+        let mut local: Vec<(String, FileRequest)> = Vec::new();
+        for (original, path) in originals.iter().zip(paths) {
+            local.push((original.clone(), self.extract_file_info(Path::new(path))?));
+        }
+        let mut ordered = Vec::new();
+        let mut problems = Vec::new();
+        // Local files either matched, or already reported as differing.
+        let mut accounted = vec![false; local.len()];
+        for file in &transfer.files {
+            let same_name: Vec<usize> = (0..local.len()).filter(|&index| !accounted[index] && local[index].1.name == file.name).collect();
+            match same_name.iter().find(|&&index| local[index].1.size as i64 == file.size) {
+                Some(&index) => {
+                    accounted[index] = true;
+                    ordered.push(index);
+                },
+                None => match same_name.first() {
+                    Some(&index) => {
+                        accounted[index] = true;
+                        problems.push(format!("size differs: {} ({} bytes in the transfer, {} bytes in {})",
+                                              file.name, file.size, local[index].1.size, local[index].0));
+                    },
+                    None => problems.push(format!("missing locally: {} ({} bytes)", file.name, file.size)),
+                }
+            }
+        }
+        for (index, (path, _)) in local.iter().enumerate() {
+            if !accounted[index] {
+                problems.push(format!("not part of the transfer: {}", path));
             }
-            self.mark_as_complete(&transfer.id, &file.id, file.multipart.part_numbers)?;
         }
-        self.finalize(&transfer.id)
+        if problems.is_empty() {
+            Ok(ordered)
+        } else {
+            Err(WeTransferError {
+                status: 0,
+                message: format!("Local files do not match transfer {}:\n - {}", transfer.id, problems.join("\n - ")),
+                ..Default::default()
+            })
+        }
+    }
+
+    /// Returns a live transfer already holding the contents of `paths`, when the
+    /// dedup cache knows one and its policy allows reusing it for `message`.
+    pub fn find_duplicate(&self, message: &str, paths: &[&str]) -> Result<Option<Transfer>, WeTransferError> {
+        let cache = match self.dedup {
+            Some(ref cache) => cache,
+            None => return Ok(None),
+        };
+        let cached = match cache.lookup(message, paths)? {
+            Some(cached) => cached,
+            None => return Ok(None),
+        };
+        match self.find(cached.id.as_str()) {
+            Ok(ref transfer) if transfer.state == "downloadable" || transfer.state == "processing" => Ok(Some(transfer.clone())),
+            Ok(_) => {
+                let _ = cache.forget(&cached.id);
+                Ok(None)
+            },
+            Err(ref error) if error.status == 404 => {
+                let _ = cache.forget(&cached.id);
+                Ok(None)
+            },
+            Err(error) => Err(error),
+        }
     }
 
     pub fn finalize(&self, transfer_id: &str) -> Result<Transfer, WeTransferError> {
         let path = format!("/{}/finalize", transfer_id);
-        self.requester.put::<FinalizeRequest, Transfer>(&path, FinalizeRequest {})
+        let transfer = self.requester.put::<FinalizeRequest, Transfer>(&path, FinalizeRequest {})?;
+        if let Some(ref history) = self.history {
+            let _ = history.record_transfer(&transfer);
+        }
+        self.dispatch(WebhookEvent::TransferFinalized(transfer.clone()));
+        self.notify(&transfer);
+        Ok(transfer)
+    }
+
+    /// Polls the transfer every `interval` until it is `downloadable`, for at most `timeout`.
+    pub fn wait_until_downloadable(&self, transfer_id: &str, interval: Duration, timeout: Duration) -> Result<Transfer, WeTransferError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let transfer = self.find(transfer_id)?;
+            if transfer.state == "downloadable" {
+                self.dispatch(WebhookEvent::TransferDownloadable(transfer.clone()));
+                self.notify(&transfer);
+                return Ok(transfer);
+            }
+            if Instant::now() + interval > deadline {
+                return Err(WeTransferError {
+                    status: 0,
+                    message: format!("Transfer {} is still {} after {:?}", transfer_id, transfer.state, timeout),
+                    ..Default::default()
+                });
+            }
+            thread::sleep(interval);
+        }
+    }
+
+    // Webhooks are best effort and delivered in the background: a slow or failing
+    // delivery must neither delay nor hide the API response.
+    fn dispatch(&self, event: WebhookEvent) {
+        if let Some(ref webhooks) = self.webhooks {
+            webhooks.queue(event);
+        }
+    }
+
+    // Reports `error` to the webhooks, unless the upload was cancelled on purpose.
+    fn upload_failed(&self, progress: &UploadProgress, error: WeTransferError) -> WeTransferError {
+        if error.cancelled.is_none() {
+            self.dispatch(WebhookEvent::UploadFailed { kind: String::from("transfer"), id: progress.upload_id.clone(), error: error.clone() });
+        }
+        error
+    }
+
+    // Emails are best effort too: the transfer exists whether or not they went out.
+    fn notify(&self, transfer: &Transfer) {
+        if let Some(ref notifier) = self.notifier {
+            notifier.notify_or_report(transfer);
+        }
     }
 
     pub fn upload_url_for(&self, upload_id: &str, file_id: &str, part: u64) -> Result<GetUploadUrlResponse, WeTransferError> {
@@ -58,12 +316,64 @@ impl TransferService {
         self.requester.get::<GetUploadUrlResponse>(&path)
     }
 
+    /// Creates a transfer for the files at `paths`. Files dropped by the upload
+    /// policy are left out of it. The files are declared as they are, to be
+    /// uploaded as they are: only `start_upload` cleans or compresses them.
     pub fn create_transfer_request(&self, message: &str, paths: &[&str]) -> Result<Transfer, WeTransferError> {
-        let files = paths.iter().map(|path_str: &&str| {
+        let paths = self.screen(paths)?;
+        self.request_transfer(message, &paths)
+    }
+
+    /// Same as `create_transfer_request`, returning what is needed to upload the files
+    /// left by the policy, from their cleaned or compressed copies where needed.
+    pub fn start_upload(&self, message: &str, paths: &[&str]) -> Result<UploadProgress, WeTransferError> {
+        let (originals, paths, copies) = self.prepare(paths)?;
+        let transfer = self.request_transfer(message, &paths)?;
+        Ok(UploadProgress::from_transfer(&transfer, &paths).with_copies(&originals, copies))
+    }
+
+    // Runs the upload policy, the metadata stripper, then the compressor. Returns
+    // the files left by the policy, the paths to upload them from, and the copies.
+    fn prepare(&self, paths: &[&str]) -> Result<(Vec<String>, Vec<String>, TempCopies), WeTransferError> {
+        let originals = self.screen(paths)?;
+        let (paths, copies) = self.transform(&originals)?;
+        Ok((originals, paths, copies))
+    }
+
+    // Returns the files left by the upload policy.
+    fn screen(&self, paths: &[&str]) -> Result<Vec<String>, WeTransferError> {
+        match self.policy {
+            Some(ref policy) => policy.screen(paths),
+            None => Ok(paths.iter().map(|path| path.to_string()).collect()),
+        }
+    }
+
+    pub(crate) fn upload_policy(&self) -> Option<&UploadPolicy> {
+        self.policy.as_deref()
+    }
+
+    // Returns the paths to upload in place of `paths`: cleaned, then compressed copies.
+    pub(crate) fn transform(&self, paths: &[String]) -> Result<(Vec<String>, TempCopies), WeTransferError> {
+        let (paths, mut copies) = match self.stripper {
+            Some(ref stripper) => stripper.clean_all(paths)?,
+            None => (paths.to_vec(), TempCopies::default()),
+        };
+        match self.compressor {
+            Some(ref compressor) => {
+                let (paths, compressed) = compressor.compress_all(&paths)?;
+                copies.merge(compressed);
+                Ok((paths, copies))
+            },
+            None => Ok((paths, copies)),
+        }
+    }
+
+    fn request_transfer(&self, message: &str, paths: &[String]) -> Result<Transfer, WeTransferError> {
+        let files = paths.iter().map(|path_str: &String| {
             // Compiler suggested to put this expression under
             // a let variable.
-            let path = Path::new(*path_str);
-            self.extract_file_info(&path)
+            let path = Path::new(path_str);
+            self.extract_file_info(path)
         }).collect();
 
         match files {
@@ -73,12 +383,17 @@ impl TransferService {
                     files: file_requests
                 };
 
-                self.requester.post::<CreateTransferRequest, Transfer>("", payload)
+                self.create_from_request(payload)
             },
             Err(transfer_error) => Err(transfer_error)
         }
     }
 
+    /// Creates a transfer from a request built by hand, e.g. by `TransferBuilder::build_request`.
+    pub fn create_from_request(&self, payload: CreateTransferRequest) -> Result<Transfer, WeTransferError> {
+        self.requester.post::<CreateTransferRequest, Transfer>("", payload)
+    }
+
     pub fn mark_as_complete(&self, upload_id: &str, file_id: &str, part_numbers: u64) -> Result<CompleteFileUploadResponse, WeTransferError> {
         let payload = CompleteFileUploadRequest { part_numbers };
         let path = format!("/{}/files/{}/upload-complete", upload_id, file_id);
@@ -94,12 +409,31 @@ impl TransferService {
             }),
             Err(error) => Err(WeTransferError {
                 status: 0,
-                message: error.description().to_string(),
+                message: error.to_string(),
+                ..Default::default()
             })
         }
     }   
 }
 
+impl UploadTarget for TransferService {
+    fn upload_url_for_part(&self, progress: &UploadProgress, file: &FileProgress, part: u64) -> Result<String, WeTransferError> {
+        self.upload_url_for(&progress.upload_id, &file.id, part).map(|response| response.url)
+    }
+
+    fn complete_file(&self, progress: &UploadProgress, file: &FileProgress) -> Result<(), WeTransferError> {
+        self.mark_as_complete(&progress.upload_id, &file.id, file.part_numbers).map(|_| ())
+    }
+
+    fn requester(&self) -> &RequestService {
+        &self.requester
+    }
+
+    fn restore_copies(&self, progress: &mut UploadProgress) -> Result<(), WeTransferError> {
+        upload::restore_copies(progress, |paths| self.transform(paths))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -117,7 +451,7 @@ mod tests {
           .create();
 
         let service = TransferService::new("jwt-token".into(), "1234".into());
-        let transfer_request = service.create_transfer_request("foo", &vec!["Cargo.toml"]).unwrap();
+        let transfer_request = service.create_transfer_request("foo", &["Cargo.toml"]).unwrap();
         assert!(transfer_request.success);
         assert_eq!(transfer_request.id, "32a6ef6003f1429be0cf1674dd8fbdef20181019143517");
         assert_eq!(transfer_request.message, "foo");
@@ -188,6 +522,193 @@ mod tests {
         assert_eq!(transfer.state, "processing");
     }
 
+    #[test]
+    fn it_stops_cancelled_transfers() {
+        let body = fs::read_to_string(Path::new("src/support/create_transfer_request.json")).expect("Fixtures:");
+        let _m = mock("POST", "/")
+          .with_status(201)
+          .with_body(body)
+          .create();
+
+        let service = TransferService::new("jwt-token".into(), "1234".into());
+        let token = CancellationToken::new();
+        token.cancel();
+        let error = service.create_with_cancellation("foo", &["Cargo.toml"], &token).unwrap_err();
+        let progress = error.cancelled.unwrap();
+        assert_eq!(progress.upload_id, "32a6ef6003f1429be0cf1674dd8fbdef20181019143517");
+        assert_eq!(progress.files[0].path, "Cargo.toml");
+        assert!(progress.files[0].completed_parts.is_empty());
+        assert_eq!(progress.total_parts(), 1);
+    }
+
+    #[test]
+    fn it_resumes_uploads_skipping_finished_parts() {
+        let body = fs::read_to_string(Path::new("src/support/finalize_response.json")).expect("Fixtures:");
+        let completion = fs::read_to_string(Path::new("src/support/complete_file_upload.json")).expect("Fixtures:");
+        let upload_id = "resumed-transfer";
+        let complete_path = format!("/{}/files/{}/upload-complete", upload_id, "file-id");
+        let _complete = mock("PUT", complete_path.as_str())
+          .with_status(200)
+          .match_body("{\"part_numbers\":1}")
+          .with_body(completion)
+          .create();
+        let finalize_path = format!("/{}/finalize", upload_id);
+        let _finalize = mock("PUT", finalize_path.as_str())
+          .with_status(200)
+          .with_body(body)
+          .create();
+
+        let progress = UploadProgress {
+            upload_id: upload_id.to_string(),
+            files: vec![FileProgress {
+                id: String::from("file-id"),
+                name: String::from("Cargo.toml"),
+                path: String::from("Cargo.toml"),
+                part_numbers: 1,
+                chunk_size: 5242880,
+                completed_parts: vec![1],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let service = TransferService::new("jwt-token".into(), "1234".into());
+        let transfer = service.resume(progress, &CancellationToken::new()).unwrap();
+        assert_eq!(transfer.state, "processing");
+    }
+
+    #[test]
+    fn it_reuses_duplicate_transfers() {
+        let body = fs::read_to_string(Path::new("src/support/finalize_response.json")).expect("Fixtures:");
+        let _find = mock("GET", "/041bae61-adb4-4ba2-80eb-48719396f0e3")
+          .with_status(200)
+          .with_body(body)
+          .create();
+        let _create = mock("POST", "/").with_status(500).expect(0).create();
+
+        let cache_path = ::std::env::temp_dir().join("wetransfer-transfer-dedup.json");
+        let _ = fs::remove_file(&cache_path);
+        let cache = DedupCache::open(&cache_path, ::dedup::ReusePolicy::AnyMessage).unwrap();
+        let known = Transfer {
+            id: String::from("041bae61-adb4-4ba2-80eb-48719396f0e3"),
+            expires_at: String::from("2999-01-01T00:00:00Z"),
+            ..Default::default()
+        };
+        cache.store(&["Cargo.toml"], &known).unwrap();
+
+        let mut service = TransferService::new("jwt-token".into(), "1234".into());
+        service.set_dedup_cache(Arc::new(cache));
+        let transfer = service.create("Again", &["Cargo.toml"]).unwrap();
+        assert_eq!(transfer.url.unwrap(), "https://we.tl/t-12344657");
+        _create.assert();
+    }
+
+    #[test]
+    fn it_remembers_the_original_files_of_compressed_uploads() {
+        let finalized = r#"{"id": "compressed-transfer", "state": "processing", "expires_at": "2999-01-01T00:00:00Z"}"#;
+        let _finalize = mock("PUT", "/compressed-transfer/finalize").with_status(200).with_body(finalized).create();
+
+        let cache_path = ::std::env::temp_dir().join("wetransfer-compressed-dedup.json");
+        let _ = fs::remove_file(&cache_path);
+        let cache = Arc::new(DedupCache::open(&cache_path, ::dedup::ReusePolicy::AnyMessage).unwrap());
+        let mut service = TransferService::new("jwt-token".into(), "1234".into());
+        service.set_dedup_cache(cache.clone());
+        service.set_compressor(Arc::new(Compressor::new(::sync::compress::Codec::Gzip)));
+
+        let (originals, paths, copies) = service.prepare(&["README.md"]).unwrap();
+        assert_ne!(paths[0], "README.md");
+        let transfer = Transfer { id: String::from("compressed-transfer"), files: vec![Default::default()], ..Default::default() };
+        let progress = UploadProgress::from_transfer(&transfer, &paths).with_copies(&originals, copies);
+        service.complete_upload(&progress, IntegrityReport::default()).unwrap();
+        drop(progress);
+        assert!(!Path::new(&paths[0]).exists());
+        assert_eq!(cache.lookup("Again", &["README.md"]).unwrap().unwrap().id, "compressed-transfer");
+    }
+
+    fn uploading_transfer(id: &str, files: &[(&str, u64)]) -> String {
+        let files: Vec<String> = files.iter().enumerate().map(|(index, &(name, size))| format!(
+            "{{\"id\": \"file-{}\", \"name\": \"{}\", \"size\": {}, \"multipart\": {{\"part_numbers\": 1, \"chunk_size\": {}}}}}",
+            index, name, size, size)).collect();
+        format!("{{\"id\": \"{}\", \"message\": \"foo\", \"state\": \"uploading\", \"files\": [{}]}}", id, files.join(","))
+    }
+
+    #[test]
+    fn it_resumes_transfers_from_server_state() {
+        let cargo_size = fs::metadata("Cargo.toml").unwrap().len();
+        let readme_size = fs::metadata("README.md").unwrap().len();
+        let body = uploading_transfer("server-transfer", &[("README.md", readme_size), ("Cargo.toml", cargo_size)]);
+        let _find = mock("GET", "/server-transfer").with_status(200).with_body(body.as_str()).create();
+        let upload_url = format!("{{\"success\": true, \"url\": \"{}/server-s3\"}}", mockito::SERVER_URL);
+        let _url0 = mock("GET", "/server-transfer/files/file-0/upload-url/1").with_status(200).with_body(upload_url.as_str()).create();
+        let _url1 = mock("GET", "/server-transfer/files/file-1/upload-url/1").with_status(200).with_body(upload_url.as_str()).create();
+        let _s3 = mock("PUT", "/server-s3").with_status(200).expect(2).create();
+        let completion = fs::read_to_string(Path::new("src/support/complete_file_upload.json")).expect("Fixtures:");
+        let _complete0 = mock("PUT", "/server-transfer/files/file-0/upload-complete").with_status(200).with_body(completion.as_str()).create();
+        let _complete1 = mock("PUT", "/server-transfer/files/file-1/upload-complete").with_status(200).with_body(completion.as_str()).create();
+        let finalized = fs::read_to_string(Path::new("src/support/finalize_response.json")).expect("Fixtures:");
+        let _finalize = mock("PUT", "/server-transfer/finalize").with_status(200).with_body(finalized).create();
+
+        let service = TransferService::new("jwt-token".into(), "1234".into());
+        let transfer = service.resume_from_server("server-transfer", &["Cargo.toml", "README.md"]).unwrap();
+        assert_eq!(transfer.state, "processing");
+        let report = transfer.integrity.unwrap();
+        assert_eq!(report.files[0].path, "README.md");
+        assert_eq!(report.files[1].path, "Cargo.toml");
+        _s3.assert();
+    }
+
+    #[test]
+    fn it_skips_files_uploaded_according_to_the_server() {
+        let cargo_size = fs::metadata("Cargo.toml").unwrap().len();
+        let readme_size = fs::metadata("README.md").unwrap().len();
+        let body = uploading_transfer("half-transfer", &[("README.md", readme_size), ("Cargo.toml", cargo_size)])
+            .replacen("\"name\": \"README.md\"", "\"state\": \"uploaded\", \"name\": \"README.md\"", 1);
+        let _find = mock("GET", "/half-transfer").with_status(200).with_body(body.as_str()).create();
+        let upload_url = format!("{{\"success\": true, \"url\": \"{}/half-s3\"}}", mockito::SERVER_URL);
+        let _url = mock("GET", "/half-transfer/files/file-1/upload-url/1").with_status(200).with_body(upload_url.as_str()).create();
+        let _s3 = mock("PUT", "/half-s3").with_status(200).expect(1).create();
+        let completion = fs::read_to_string(Path::new("src/support/complete_file_upload.json")).expect("Fixtures:");
+        let _complete = mock("PUT", "/half-transfer/files/file-1/upload-complete").with_status(200).with_body(completion.as_str()).create();
+        let finalized = fs::read_to_string(Path::new("src/support/finalize_response.json")).expect("Fixtures:");
+        let _finalize = mock("PUT", "/half-transfer/finalize").with_status(200).with_body(finalized).create();
+
+        let service = TransferService::new("jwt-token".into(), "1234".into());
+        let transfer = service.resume_from_server("half-transfer", &["Cargo.toml", "README.md"]).unwrap();
+        assert_eq!(transfer.state, "processing");
+        _s3.assert();
+    }
+
+    #[test]
+    fn it_matches_compressed_files_with_server_state() {
+        let mut service = TransferService::new("jwt-token".into(), "1234".into());
+        service.set_compressor(Arc::new(Compressor::new(::sync::compress::Codec::Gzip)));
+        let (_, paths, _copies) = service.prepare(&["README.md"]).unwrap();
+        let compressed_size = fs::metadata(&paths[0]).unwrap().len();
+        let body = uploading_transfer("compressed-server-transfer", &[("README.md.wtz.gz", compressed_size)])
+            .replacen("\"name\"", "\"state\": \"uploaded\", \"name\"", 1);
+        let _find = mock("GET", "/compressed-server-transfer").with_status(200).with_body(body.as_str()).create();
+        let finalized = fs::read_to_string(Path::new("src/support/finalize_response.json")).expect("Fixtures:");
+        let finalize = mock("PUT", "/compressed-server-transfer/finalize").with_status(200).with_body(finalized).create();
+
+        let transfer = service.resume_from_server("compressed-server-transfer", &["README.md"]).unwrap();
+        assert_eq!(transfer.state, "processing");
+        finalize.assert();
+    }
+
+    #[test]
+    fn it_reports_mismatches_with_server_state() {
+        let cargo_size = fs::metadata("Cargo.toml").unwrap().len();
+        let body = uploading_transfer("mismatched-transfer", &[("Cargo.toml", cargo_size + 1), ("big-bobis.jpg", 195906)]);
+        let _find = mock("GET", "/mismatched-transfer").with_status(200).with_body(body.as_str()).create();
+
+        let service = TransferService::new("jwt-token".into(), "1234".into());
+        let error = service.resume_from_server("mismatched-transfer", &["Cargo.toml", "README.md"]).unwrap_err();
+        assert!(error.message.starts_with("Local files do not match transfer mismatched-transfer"));
+        assert!(error.message.contains(&format!("size differs: Cargo.toml ({} bytes in the transfer, {} bytes in Cargo.toml)", cargo_size + 1, cargo_size)));
+        assert!(error.message.contains("missing locally: big-bobis.jpg (195906 bytes)"));
+        assert!(error.message.contains("not part of the transfer: README.md"));
+        assert!(!error.message.contains("not part of the transfer: Cargo.toml"));
+    }
+
     #[test]
     fn it_finds_transfers_by_id() {
         let body = fs::read_to_string(Path::new("src/support/finalize_response.json")).expect("Fixtures:");
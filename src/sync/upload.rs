@@ -0,0 +1,462 @@
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use md5::Md5;
+use reqwest::header::ETAG;
+use sha2::{Digest, Sha256};
+
+use dedup::hex;
+use responses::*;
+use sync::cancel::CancellationToken;
+use sync::requester::RequestService;
+use sync::spool::TempCopies;
+
+// How many times a part is sent before giving up on a mismatching `ETag`.
+const MAX_PART_ATTEMPTS: u32 = 3;
+
+/// Per-call settings of an upload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UploadOptions {
+    /// How many files are uploaded at the same time.
+    pub concurrency: usize,
+    /// How many times a part is sent again after S3 rejected it, or the connection failed.
+    pub retries: u32,
+}
+
+impl Default for UploadOptions {
+    fn default() -> UploadOptions {
+        UploadOptions { concurrency: 1, retries: 0 }
+    }
+}
+
+/// The API calls that differ between transfer and board uploads.
+pub trait UploadTarget {
+    /// Returns the presigned S3 url for one part of a file.
+    fn upload_url_for_part(&self, progress: &UploadProgress, file: &FileProgress, part: u64) -> Result<String, WeTransferError>;
+    /// Tells the API that every part of a file was uploaded.
+    fn complete_file(&self, progress: &UploadProgress, file: &FileProgress) -> Result<(), WeTransferError>;
+    fn requester(&self) -> &RequestService;
+    /// Makes again the copies of files made for the upload, e.g. without
+    /// metadata, which are gone since it started. None by default.
+    fn restore_copies(&self, _progress: &mut UploadProgress) -> Result<(), WeTransferError> {
+        Ok(())
+    }
+}
+
+impl UploadProgress {
+    /// Starts tracking a freshly created transfer, whose files match `paths` by position.
+    pub fn from_transfer<S: ToString>(transfer: &Transfer, paths: &[S]) -> UploadProgress {
+        UploadProgress {
+            upload_id: transfer.id.clone(),
+            files: transfer.files.iter().zip(paths).map(|(file, path)| FileProgress {
+                id: file.id.clone(),
+                multipart_id: None,
+                name: file.name.clone(),
+                path: path.to_string(),
+                source: None,
+                size: file.size as u64,
+                part_numbers: file.multipart.part_numbers,
+                chunk_size: file.multipart.chunk_size,
+                completed_parts: Vec::new(),
+                completed: false,
+                checksums: Vec::new(),
+                sha256: None,
+            }).collect(),
+            copies: TempCopies::default(),
+        }
+    }
+
+    /// Starts tracking the files just added to a board, which match `paths` by position.
+    pub fn from_board_files<S: ToString>(board_id: &str, files: &[FileBoard], paths: &[S]) -> UploadProgress {
+        UploadProgress {
+            upload_id: board_id.to_string(),
+            files: files.iter().zip(paths).map(|(file, path)| FileProgress {
+                id: file.id.clone(),
+                multipart_id: Some(file.multipart.id.clone()),
+                name: file.name.clone(),
+                path: path.to_string(),
+                source: None,
+                size: file.size,
+                part_numbers: file.multipart.part_numbers,
+                chunk_size: file.multipart.chunk_size,
+                completed_parts: Vec::new(),
+                completed: false,
+                checksums: Vec::new(),
+                sha256: None,
+            }).collect(),
+            copies: TempCopies::default(),
+        }
+    }
+
+    /// Records that the files are read from copies of `originals`, which match
+    /// them by position, and keeps `copies` until the upload is over.
+    pub fn with_copies<S: AsRef<str>>(mut self, originals: &[S], copies: TempCopies) -> UploadProgress {
+        for (file, original) in self.files.iter_mut().zip(originals) {
+            if file.path != original.as_ref() {
+                file.source = Some(original.as_ref().to_string());
+            }
+        }
+        self.copies = copies;
+        self
+    }
+
+    /// Number of parts uploaded so far, across all files.
+    pub fn completed_parts(&self) -> u64 {
+        self.files.iter().map(|file| file.completed_parts.len() as u64).sum()
+    }
+
+    /// Number of parts of the whole upload.
+    pub fn total_parts(&self) -> u64 {
+        self.files.iter().map(|file| file.part_numbers).sum()
+    }
+
+    /// The checksums gathered so far, file by file.
+    pub fn integrity_report(&self) -> IntegrityReport {
+        IntegrityReport {
+            files: self.files.iter().map(|file| FileIntegrity {
+                name: file.name.clone(),
+                path: file.source.clone().unwrap_or_else(|| file.path.clone()),
+                size: file.size,
+                sha256: file.sha256.clone(),
+                parts: file.checksums.clone(),
+                verified: file.checksums.len() as u64 == file.part_numbers && file.checksums.iter().all(|part| part.verified),
+            }).collect(),
+        }
+    }
+}
+
+/// Uploads every part not yet recorded in `progress`, and marks each file as complete.
+/// `progress` is kept up to date, so it can be used to resume after a failure.
+/// Parts whose `ETag` does not match their MD5 are sent again, and fail the upload
+/// if they keep mismatching.
+pub fn upload_files<T: UploadTarget>(target: &T, progress: &mut UploadProgress, cancel: &CancellationToken) -> Result<IntegrityReport, WeTransferError> {
+    upload_files_observed(target, progress, cancel, |_| {})
+}
+
+/// Same as `upload_files`, calling `on_part` after every uploaded part.
+pub fn upload_files_observed<T, F>(target: &T, progress: &mut UploadProgress, cancel: &CancellationToken, on_part: F) -> Result<IntegrityReport, WeTransferError>
+    where T: UploadTarget, F: FnMut(&UploadProgress) {
+    target.restore_copies(progress)?;
+    let report = upload_sequentially(target, progress, cancel, 0, on_part)?;
+    // The copies are not needed anymore once every file is uploaded.
+    progress.copies = TempCopies::default();
+    Ok(report)
+}
+
+/// Same as `upload_files_observed`, following `options`. With a concurrency above
+/// one, files are spread over that many threads; once a file fails, the files
+/// not started yet are left alone and the first error is returned.
+pub fn upload_files_with<T, F>(target: &T, progress: &mut UploadProgress, cancel: &CancellationToken, options: &UploadOptions, on_part: F) -> Result<IntegrityReport, WeTransferError>
+    where T: UploadTarget + Sync, F: FnMut(&UploadProgress) + Send {
+    target.restore_copies(progress)?;
+    let workers = options.concurrency.min(progress.files.len());
+    let report = if workers <= 1 {
+        upload_sequentially(target, progress, cancel, options.retries, on_part)?
+    } else {
+        upload_concurrently(target, progress, cancel, options, workers, on_part)?
+    };
+    progress.copies = TempCopies::default();
+    Ok(report)
+}
+
+fn upload_concurrently<T, F>(target: &T, progress: &mut UploadProgress, cancel: &CancellationToken, options: &UploadOptions, workers: usize, on_part: F) -> Result<IntegrityReport, WeTransferError>
+    where T: UploadTarget + Sync, F: FnMut(&UploadProgress) + Send {
+    let next = AtomicUsize::new(0);
+    let shared = Mutex::new((progress, on_part, None));
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::SeqCst);
+                // Each file is uploaded on its own, and merged back after every part.
+                let mut single = {
+                    let state = shared.lock().unwrap();
+                    if index >= state.0.files.len() || state.2.is_some() {
+                        break;
+                    }
+                    if state.0.files[index].completed {
+                        continue;
+                    }
+                    UploadProgress { upload_id: state.0.upload_id.clone(), files: vec![state.0.files[index].clone()], copies: TempCopies::default() }
+                };
+                let result = upload_sequentially(target, &mut single, cancel, options.retries, |file_progress| {
+                    let mut state = shared.lock().unwrap();
+                    let (ref mut progress, ref mut on_part, _) = *state;
+                    progress.files[index] = file_progress.files[0].clone();
+                    on_part(progress);
+                });
+                let mut state = shared.lock().unwrap();
+                state.0.files[index] = single.files.remove(0);
+                if let Err(error) = result {
+                    state.2.get_or_insert(error);
+                }
+            });
+        }
+    });
+    let (progress, _, error) = shared.into_inner().unwrap();
+    match error {
+        Some(_) if cancel.is_cancelled() => Err(cancelled(progress)),
+        Some(error) => Err(error),
+        None => Ok(progress.integrity_report()),
+    }
+}
+
+/// Makes again with `transform` the copies which are gone, e.g. after a restart.
+/// They must still have the size declared to the API.
+pub(crate) fn restore_copies<F>(progress: &mut UploadProgress, transform: F) -> Result<(), WeTransferError>
+    where F: Fn(&[String]) -> Result<(Vec<String>, TempCopies), WeTransferError> {
+    let missing: Vec<usize> = (0..progress.files.len()).filter(|&index| {
+        let file = &progress.files[index];
+        !file.completed && file.source.is_some() && !Path::new(&file.path).exists()
+    }).collect();
+    if missing.is_empty() {
+        return Ok(());
+    }
+    let sources: Vec<String> = missing.iter().filter_map(|&index| progress.files[index].source.clone()).collect();
+    let (paths, copies) = transform(&sources)?;
+    for ((&index, source), path) in missing.iter().zip(&sources).zip(paths) {
+        let size = fs::metadata(&path).map(|metadata| metadata.len()).unwrap_or_default();
+        if size != progress.files[index].size {
+            return Err(WeTransferError {
+                status: 0,
+                message: format!("{} changed since its upload started", source),
+                ..Default::default()
+            });
+        }
+        progress.files[index].path = path;
+    }
+    progress.copies.merge(copies);
+    Ok(())
+}
+
+fn upload_sequentially<T, F>(target: &T, progress: &mut UploadProgress, cancel: &CancellationToken, retries: u32, mut on_part: F) -> Result<IntegrityReport, WeTransferError>
+    where T: UploadTarget, F: FnMut(&UploadProgress) {
+    for index in 0..progress.files.len() {
+        if progress.files[index].completed {
+            continue;
+        }
+        let mut file_io = open(&progress.files[index].path)?;
+        // Parts uploaded by a previous run are read anyway, to hash the whole file.
+        let mut file_hasher = Sha256::new();
+        for part in 1..=progress.files[index].part_numbers {
+            let buffer = read_part(&mut file_io, progress.files[index].chunk_size, part)?;
+            file_hasher.update(&buffer);
+            if progress.files[index].completed_parts.contains(&part) {
+                continue;
+            }
+            if cancel.is_cancelled() {
+                return Err(cancelled(progress));
+            }
+            let checksum = upload_part(target, progress, index, part, &buffer, cancel, retries)?;
+            progress.files[index].completed_parts.push(part);
+            progress.files[index].checksums.push(checksum);
+            on_part(progress);
+        }
+        progress.files[index].sha256 = Some(hex(&file_hasher.finalize()));
+        target.complete_file(progress, &progress.files[index])?;
+        progress.files[index].completed = true;
+    }
+    Ok(progress.integrity_report())
+}
+
+fn upload_part<T: UploadTarget>(target: &T, progress: &UploadProgress, index: usize, part: u64, buffer: &[u8], cancel: &CancellationToken, retries: u32) -> Result<PartChecksum, WeTransferError> {
+    let md5 = hex(&Md5::digest(buffer));
+    let mut attempts = 0;
+    let mut failures = 0;
+    loop {
+        attempts += 1;
+        let s3_url = target.upload_url_for_part(progress, &progress.files[index], part)?;
+        let response = match target.requester().file_upload_cancellable(s3_url, buffer, cancel) {
+            Ok(response) => response,
+            Err(_) if cancel.is_cancelled() => return Err(cancelled(progress)),
+            Err(_) if failures < retries => {
+                failures += 1;
+                continue;
+            },
+            Err(error) => return Err(error),
+        };
+        let etag = response.headers().get(ETAG)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.trim_matches('"').to_lowercase());
+        // Multipart and KMS-encrypted objects have ETags which are not an MD5.
+        let comparable = etag.as_ref().is_some_and(|etag| etag.len() == 32 && etag.chars().all(|c| c.is_ascii_hexdigit()));
+        if comparable && etag.as_ref() != Some(&md5) {
+            if attempts < MAX_PART_ATTEMPTS {
+                continue;
+            }
+            return Err(WeTransferError {
+                status: 0,
+                message: format!("Integrity check failed for part {} of {}: S3 stored {}, expected {}",
+                                 part, progress.files[index].name, etag.unwrap_or_default(), md5),
+                ..Default::default()
+            });
+        }
+        return Ok(PartChecksum {
+            part,
+            size: buffer.len() as u64,
+            md5,
+            sha256: hex(&Sha256::digest(buffer)),
+            etag,
+            verified: comparable,
+            attempts,
+        });
+    }
+}
+
+/// Reads the `part`-th chunk (1-based) of a file. The last one may be shorter.
+pub fn read_part(file_io: &mut File, chunk_size: u64, part: u64) -> Result<Vec<u8>, WeTransferError> {
+    let mut buffer = Vec::with_capacity(chunk_size as usize);
+    file_io.seek(SeekFrom::Start(chunk_size * (part - 1)))
+        .and_then(|_| file_io.take(chunk_size).read_to_end(&mut buffer))
+        .map_err(|error| WeTransferError {
+            status: 0,
+            message: format!("Error while reading part {}: {}", part, error),
+            ..Default::default()
+        })?;
+    Ok(buffer)
+}
+
+fn open(path: &str) -> Result<File, WeTransferError> {
+    File::open(path).map_err(|error| WeTransferError {
+        status: 0,
+        message: format!("Error while opening {}: {}", path, error),
+        ..Default::default()
+    })
+}
+
+fn cancelled(progress: &UploadProgress) -> WeTransferError {
+    WeTransferError {
+        status: 0,
+        message: format!("Upload cancelled after {} of {} parts", progress.completed_parts(), progress.total_parts()),
+        cancelled: Some(Box::new(progress.clone())),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::mock;
+
+    struct FakeTarget {
+        requester: RequestService,
+        s3_path: &'static str,
+    }
+
+    impl UploadTarget for FakeTarget {
+        fn upload_url_for_part(&self, _: &UploadProgress, _: &FileProgress, _: u64) -> Result<String, WeTransferError> {
+            Ok(format!("{}{}", mockito::SERVER_URL, self.s3_path))
+        }
+
+        fn complete_file(&self, _: &UploadProgress, _: &FileProgress) -> Result<(), WeTransferError> {
+            Ok(())
+        }
+
+        fn requester(&self) -> &RequestService {
+            &self.requester
+        }
+    }
+
+    fn progress(chunk_size: u64) -> UploadProgress {
+        let size = ::std::fs::metadata("Cargo.toml").unwrap().len();
+        UploadProgress {
+            upload_id: String::from("upload-id"),
+            files: vec![FileProgress {
+                id: String::from("file-id"),
+                name: String::from("Cargo.toml"),
+                path: String::from("Cargo.toml"),
+                size,
+                part_numbers: size.div_ceil(chunk_size),
+                chunk_size,
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn target(s3_path: &'static str) -> FakeTarget {
+        FakeTarget {
+            requester: RequestService::new("jwt-token".into(), "1234".into(), mockito::SERVER_URL.to_string()),
+            s3_path,
+        }
+    }
+
+    #[test]
+    fn it_verifies_parts_against_etags() {
+        let contents = ::std::fs::read("Cargo.toml").unwrap();
+        let etag = format!("\"{}\"", hex(&Md5::digest(&contents)));
+        let _m = mock("PUT", "/verified-part").with_status(200).with_header("ETag", &etag).create();
+
+        let mut progress = progress(contents.len() as u64);
+        let report = upload_files(&target("/verified-part"), &mut progress, &CancellationToken::new()).unwrap();
+        let file = &report.files[0];
+        assert!(file.verified);
+        assert_eq!(file.sha256, Some(hex(&Sha256::digest(&contents))));
+        assert_eq!(file.parts[0].attempts, 1);
+        assert_eq!(file.parts[0].size, contents.len() as u64);
+    }
+
+    #[test]
+    fn it_hashes_whole_files_across_parts() {
+        let contents = ::std::fs::read("Cargo.toml").unwrap();
+        let _m = mock("PUT", "/unverifiable-part").with_status(200).with_header("ETag", "\"multipart-etag-2\"").create();
+
+        let mut progress = progress(100);
+        progress.files[0].completed_parts.push(1);
+        let report = upload_files(&target("/unverifiable-part"), &mut progress, &CancellationToken::new()).unwrap();
+        let file = &report.files[0];
+        assert!(!file.verified);
+        assert_eq!(file.sha256, Some(hex(&Sha256::digest(&contents))));
+        assert_eq!(file.parts.len() as u64, progress.files[0].part_numbers - 1);
+        assert!(progress.files[0].completed);
+    }
+
+    #[test]
+    fn it_retries_then_fails_on_mismatching_etags() {
+        let m = mock("PUT", "/corrupted-part")
+          .with_status(200)
+          .with_header("ETag", "\"00000000000000000000000000000000\"")
+          .expect(3)
+          .create();
+
+        let mut progress = progress(5242880);
+        let error = upload_files(&target("/corrupted-part"), &mut progress, &CancellationToken::new()).unwrap_err();
+        assert!(error.message.starts_with("Integrity check failed for part 1 of Cargo.toml"));
+        assert!(progress.files[0].completed_parts.is_empty());
+        m.assert();
+    }
+
+    #[test]
+    fn it_retries_rejected_parts() {
+        let m = mock("PUT", "/rejected-part").with_status(500).expect(3).create();
+
+        let mut progress = progress(5242880);
+        let options = UploadOptions { retries: 2, ..Default::default() };
+        let error = upload_files_with(&target("/rejected-part"), &mut progress, &CancellationToken::new(), &options, |_| {}).unwrap_err();
+        assert_eq!(error.status, 500);
+        m.assert();
+    }
+
+    #[test]
+    fn it_uploads_files_concurrently() {
+        let _m = mock("PUT", "/concurrent-part").with_status(200).expect(6).create();
+
+        let mut progress = progress(5242880);
+        let mut readme = progress.files[0].clone();
+        readme.path = String::from("README.md");
+        readme.size = ::std::fs::metadata("README.md").unwrap().len();
+        for _ in 0..5 {
+            progress.files.push(readme.clone());
+        }
+        let mut calls = 0;
+        let options = UploadOptions { concurrency: 3, ..Default::default() };
+        let report = upload_files_with(&target("/concurrent-part"), &mut progress, &CancellationToken::new(), &options, |_| calls += 1).unwrap();
+        assert_eq!(calls, 6);
+        assert_eq!(report.files.len(), 6);
+        assert!(progress.files.iter().all(|file| file.completed));
+        assert_eq!(report.files[1].sha256, report.files[5].sha256);
+    }
+}
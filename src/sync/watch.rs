@@ -0,0 +1,456 @@
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use chrono::{DateTime, Utc};
+use serde_json;
+
+use responses::{UploadProgress, WeTransferError};
+use fsutil::io_error;
+use sync::board::BoardService;
+use sync::cancel::CancellationToken;
+use sync::upload::UploadOptions;
+
+/// Settings of a `FolderWatcher`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WatchOptions {
+    /// Time between two scans of the directory.
+    pub interval: Duration,
+    /// How long a file's size and modification time must stay the same before it is uploaded.
+    pub settle_time: Duration,
+    /// File name patterns never uploaded, where `*` matches any run of characters and `?` one.
+    pub ignore: Vec<String>,
+    /// How many times the parts of a file are sent again within the same scan,
+    /// after a network or server error.
+    pub retries: u32,
+    /// Wait before the first retry, doubled before each of the next ones, and
+    /// before the next scans try a file again.
+    pub retry_delay: Duration,
+}
+
+impl Default for WatchOptions {
+    fn default() -> WatchOptions {
+        WatchOptions {
+            interval: Duration::from_secs(2),
+            settle_time: Duration::from_secs(5),
+            ignore: [".*", "*~", "*.tmp", "*.part", "*.partial", "*.crdownload", "*.download", "*.swp"]
+                .iter().map(|pattern| pattern.to_string()).collect(),
+            retries: 3,
+            retry_delay: Duration::from_secs(1),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+/// represents a file already published by a `FolderWatcher`.
+pub struct LedgerEntry {
+    pub board_id: String,
+    pub name: String,
+    pub size: u64,
+    /// modification time, in seconds since the epoch.
+    pub modified: u64,
+    /// when the entry was recorded.
+    pub uploaded_at: DateTime<Utc>,
+    /// the upload of the file, recorded once its board file was created and
+    /// until all of its parts are sent; `None` once the file is uploaded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upload: Option<UploadProgress>,
+}
+
+impl LedgerEntry {
+    fn is_for(&self, board_id: &str, name: &str, size: u64, modified: u64) -> bool {
+        self.board_id == board_id && self.name == name && self.size == size && self.modified == modified
+    }
+}
+
+/// A JSON-lines record of the files uploaded from a watched folder, so that a
+/// restarted watcher does not upload them again. A file changed since its
+/// upload, in size or modification time, is uploaded again. A file whose
+/// upload was interrupted is resumed, without adding it to the board again.
+#[derive(Debug)]
+pub struct Ledger {
+    path: PathBuf,
+    entries: Mutex<Vec<LedgerEntry>>,
+}
+
+impl Ledger {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Ledger, WeTransferError> {
+        let path = path.as_ref().to_path_buf();
+        let entries = if path.exists() {
+            let contents = fs::read_to_string(&path).map_err(|error| io_error(&path, error))?;
+            // A line cut short by a crash is skipped rather than failing the whole ledger.
+            let mut entries = Vec::new();
+            for entry in contents.lines().filter_map(|line| serde_json::from_str(line).ok()) {
+                replace(&mut entries, entry);
+            }
+            entries
+        } else {
+            Vec::new()
+        };
+        Ok(Ledger { path, entries: Mutex::new(entries) })
+    }
+
+    /// Whether the file was fully uploaded.
+    pub fn contains(&self, board_id: &str, name: &str, size: u64, modified: u64) -> bool {
+        self.entries.lock().unwrap().iter()
+            .any(|entry| entry.is_for(board_id, name, size, modified) && entry.upload.is_none())
+    }
+
+    /// The upload of the file left unfinished, if any.
+    pub fn pending(&self, board_id: &str, name: &str, size: u64, modified: u64) -> Option<UploadProgress> {
+        self.entries.lock().unwrap().iter()
+            .find(|entry| entry.is_for(board_id, name, size, modified))
+            .and_then(|entry| entry.upload.clone())
+    }
+
+    pub fn entries(&self) -> Vec<LedgerEntry> {
+        self.entries.lock().unwrap().clone()
+    }
+
+    /// Replaces the entry of the same file, if any. The entry is kept in memory
+    /// even when it cannot be written, so that this watcher does not upload the file again.
+    pub fn record(&self, entry: LedgerEntry) -> Result<(), WeTransferError> {
+        let mut entries = self.entries.lock().unwrap();
+        let line = serde_json::to_string(&entry).unwrap();
+        replace(&mut entries, entry);
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path).map_err(|error| io_error(&self.path, error))?;
+        writeln!(file, "{}", line).map_err(|error| io_error(&self.path, error))
+    }
+}
+
+// Later lines of the ledger win over earlier ones for the same file.
+fn replace(entries: &mut Vec<LedgerEntry>, entry: LedgerEntry) {
+    entries.retain(|existing| !existing.is_for(&entry.board_id, &entry.name, entry.size, entry.modified));
+    entries.push(entry);
+}
+
+/// What happened to a file during a scan.
+#[derive(Debug, Clone)]
+pub enum WatchEvent {
+    Uploaded(PathBuf),
+    /// The upload failed after every retry. After a network or server error, a
+    /// later scan tries again; otherwise the file is left alone until it changes.
+    Failed(PathBuf, WeTransferError),
+}
+
+// A file seen during a scan, waiting for its size to settle.
+struct Observation {
+    size: u64,
+    modified: u64,
+    since: Instant,
+    failures: u32,
+    // When the file may be uploaded; `None` once it failed for good.
+    retry_at: Option<Instant>,
+}
+
+/// Publishes every new file of a directory to a board, by polling it. Files are
+/// uploaded once they stopped changing for `settle_time`; sub-directories and
+/// ignored names are left alone.
+pub struct FolderWatcher<'a> {
+    service: &'a BoardService,
+    board_id: String,
+    directory: PathBuf,
+    ledger: Ledger,
+    options: WatchOptions,
+    observations: HashMap<PathBuf, Observation>,
+}
+
+impl<'a> FolderWatcher<'a> {
+    /// Constructor.
+    pub fn new<S: ToString, P: AsRef<Path>>(service: &'a BoardService, board_id: S, directory: P, ledger: Ledger, options: WatchOptions) -> FolderWatcher<'a> {
+        FolderWatcher {
+            service,
+            board_id: board_id.to_string(),
+            directory: directory.as_ref().to_path_buf(),
+            ledger,
+            options,
+            observations: HashMap::new(),
+        }
+    }
+
+    /// Scans the directory once, uploading the files which settled since the previous scan.
+    pub fn scan(&mut self) -> Result<Vec<WatchEvent>, WeTransferError> {
+        let now = Instant::now();
+        let mut ready = Vec::new();
+        let mut seen = Vec::new();
+        for entry in fs::read_dir(&self.directory).map_err(|error| io_error(&self.directory, error))? {
+            let entry = entry.map_err(|error| io_error(&self.directory, error))?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let metadata = match entry.metadata() {
+                Ok(ref metadata) if metadata.is_file() => metadata.clone(),
+                // Gone since listed, or not a file.
+                _ => continue,
+            };
+            if self.options.ignore.iter().any(|pattern| matches_pattern(pattern, &name)) {
+                continue;
+            }
+            let size = metadata.len();
+            let modified = metadata.modified().ok()
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map(|modified| modified.as_secs())
+                .unwrap_or_default();
+            if self.ledger.contains(&self.board_id, &name, size, modified) {
+                continue;
+            }
+            let path = entry.path();
+            seen.push(path.clone());
+            let settled = match self.observations.get(&path) {
+                Some(observation) if observation.size == size && observation.modified == modified => {
+                    now.duration_since(observation.since) >= self.options.settle_time
+                        && observation.retry_at.is_some_and(|retry_at| now >= retry_at)
+                },
+                _ => {
+                    self.observations.insert(path.clone(), Observation { size, modified, since: now, failures: 0, retry_at: Some(now) });
+                    false
+                },
+            };
+            if settled {
+                ready.push((path, name, size, modified));
+            }
+        }
+        self.observations.retain(|path, _| seen.contains(path));
+        ready.sort();
+
+        let mut events = Vec::new();
+        for (path, name, size, modified) in ready {
+            let result = match self.rejection(&path) {
+                Some(error) => Err((error, false)),
+                None => self.upload(&path, name, size, modified).map_err(|error| {
+                    let transient = is_transient(&error);
+                    (error, transient)
+                }),
+            };
+            match result {
+                Ok(()) => {
+                    self.observations.remove(&path);
+                    events.push(WatchEvent::Uploaded(path));
+                },
+                Err((error, transient)) => {
+                    if let Some(observation) = self.observations.get_mut(&path) {
+                        observation.failures += 1;
+                        observation.retry_at = if transient {
+                            Some(Instant::now() + self.options.retry_delay * 2u32.pow(observation.failures.min(10)))
+                        } else {
+                            None
+                        };
+                    }
+                    events.push(WatchEvent::Failed(path, error));
+                },
+            }
+        }
+        Ok(events)
+    }
+
+    /// Scans the directory every `interval` until `cancel` is cancelled, passing every event to `on_event`.
+    pub fn run<F: FnMut(WatchEvent)>(&mut self, cancel: &CancellationToken, mut on_event: F) -> Result<(), WeTransferError> {
+        while !cancel.is_cancelled() {
+            for event in self.scan()? {
+                on_event(event);
+            }
+            let deadline = Instant::now() + self.options.interval;
+            while !cancel.is_cancelled() && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(50).min(self.options.interval));
+            }
+        }
+        Ok(())
+    }
+
+    // Runs the upload policy first, as its errors cannot be told apart from network errors.
+    fn rejection(&self, path: &Path) -> Option<WeTransferError> {
+        let policy = self.service.upload_policy()?;
+        let report = match policy.evaluate(&[path.to_string_lossy()]) {
+            Ok(report) => report,
+            Err(error) => return Some(error),
+        };
+        policy.refusal(&report).or_else(|| if report.allowed.is_empty() {
+            Some(WeTransferError { status: 0, message: String::from("Upload blocked by policy: every file was dropped"), ..Default::default() })
+        } else {
+            None
+        })
+    }
+
+    fn entry(&self, name: String, size: u64, modified: u64) -> LedgerEntry {
+        LedgerEntry { board_id: self.board_id.clone(), name, size, modified, uploaded_at: Utc::now(), upload: None }
+    }
+
+    // Adds the file to the board, unless an earlier attempt did, then sends its
+    // parts. Only the parts are sent again, and only after a network or server error.
+    fn upload(&self, path: &Path, name: String, size: u64, modified: u64) -> Result<(), WeTransferError> {
+        let mut progress = match self.ledger.pending(&self.board_id, &name, size, modified) {
+            Some(progress) => progress,
+            None => {
+                let paths = [path.to_string_lossy().into_owned()];
+                let progress = self.service.start_upload(&self.board_id, &paths)?;
+                self.ledger.record(LedgerEntry { upload: Some(progress.clone()), ..self.entry(name.clone(), size, modified) })?;
+                progress
+            },
+        };
+        let options = UploadOptions { retries: self.options.retries, ..Default::default() };
+        let mut attempts = 0;
+        let mut delay = self.options.retry_delay;
+        loop {
+            match self.service.resume_file_uploads_with(&mut progress, &CancellationToken::new(), &options, |_| {}) {
+                Ok(_) => break,
+                Err(ref error) if is_transient(error) && attempts < self.options.retries => {
+                    attempts += 1;
+                    thread::sleep(delay);
+                    delay *= 2;
+                },
+                Err(error) => {
+                    // Keeps the parts already sent for the next attempt.
+                    let _ = self.ledger.record(LedgerEntry { upload: Some(progress), ..self.entry(name, size, modified) });
+                    return Err(error);
+                },
+            }
+        }
+        self.ledger.record(self.entry(name, size, modified))
+    }
+}
+
+// Network errors, server errors and rate limits are worth trying again.
+fn is_transient(error: &WeTransferError) -> bool {
+    error.status == 0 || error.status == 429 || error.status >= 500
+}
+
+/// Matches a file name against a pattern where `*` matches any run of characters and `?` one.
+pub fn matches_pattern(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, n));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            n = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::mock;
+    use std::env;
+
+    fn directory(name: &str) -> PathBuf {
+        let directory = env::temp_dir().join(format!("wetransfer-watch-{}", name));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    #[test]
+    fn it_matches_ignore_patterns() {
+        assert!(matches_pattern("*.part", "render.mov.part"));
+        assert!(matches_pattern(".*", ".DS_Store"));
+        assert!(matches_pattern("IMG_????.jpg", "IMG_0042.jpg"));
+        assert!(matches_pattern("*a*b", "xaab"));
+        assert!(!matches_pattern("*.part", "render.mov"));
+        assert!(!matches_pattern("IMG_????.jpg", "IMG_42.jpg"));
+    }
+
+    #[test]
+    fn it_uploads_settled_files_once() {
+        let directory = directory("settled");
+        fs::write(directory.join("select-01.jpg"), "hello").unwrap();
+        fs::write(directory.join("select-02.jpg.part"), "partial").unwrap();
+        fs::create_dir_all(directory.join("nested")).unwrap();
+
+        let files = r#"[{"id": "watched-file", "name": "select-01.jpg", "size": 5, "type": "file",
+            "multipart": {"id": "multipart-id", "part_numbers": 1, "chunk_size": 5}}]"#;
+        let add = mock("POST", "/watched-board/files").with_status(201).with_body(files).expect(1).create();
+        let upload_url = format!("{{\"success\": true, \"url\": \"{}/watched-s3\"}}", mockito::SERVER_URL);
+        let _url = mock("GET", "/watched-board/files/watched-file/upload-url/1/multipart-id").with_status(200).with_body(upload_url.as_str()).create();
+        let _s3 = mock("PUT", "/watched-s3").with_status(200).create();
+        let _complete = mock("PUT", "/watched-board/files/watched-file/upload-complete")
+          .with_status(200)
+          .with_body(r#"{"success": true, "message": "File is marked as complete."}"#)
+          .create();
+
+        let service = BoardService::new("jwt-token".into(), "1234".into());
+        let ledger_path = directory.join(".ledger.jsonl");
+        let options = WatchOptions { settle_time: Duration::from_millis(0), ..Default::default() };
+        let mut watcher = FolderWatcher::new(&service, "watched-board", &directory, Ledger::open(&ledger_path).unwrap(), options.clone());
+        assert!(watcher.scan().unwrap().is_empty());
+        let events = watcher.scan().unwrap();
+        assert!(matches!(events[..], [WatchEvent::Uploaded(ref path)] if path.ends_with("select-01.jpg")));
+        assert!(watcher.scan().unwrap().is_empty());
+
+        let mut restarted = FolderWatcher::new(&service, "watched-board", &directory, Ledger::open(&ledger_path).unwrap(), options);
+        assert!(restarted.scan().unwrap().is_empty());
+        assert!(restarted.scan().unwrap().is_empty());
+        assert_eq!(restarted.ledger.entries().len(), 1);
+        add.assert();
+    }
+
+    #[test]
+    fn it_retries_parts_without_adding_files_again() {
+        let directory = directory("failed");
+        fs::write(directory.join("select.jpg"), "hello").unwrap();
+        let files = r#"[{"id": "retried-file", "name": "select.jpg", "size": 5, "type": "file",
+            "multipart": {"id": "multipart-id", "part_numbers": 1, "chunk_size": 5}}]"#;
+        let add = mock("POST", "/failing-board/files").with_status(201).with_body(files).expect(1).create();
+        let url = mock("GET", "/failing-board/files/retried-file/upload-url/1/multipart-id")
+          .with_status(500)
+          .with_body(r#"{"message": "Boom"}"#)
+          .expect(6)
+          .create();
+
+        let service = BoardService::new("jwt-token".into(), "1234".into());
+        let options = WatchOptions { settle_time: Duration::from_millis(0), retries: 2, retry_delay: Duration::from_millis(20), ..Default::default() };
+        let ledger_path = directory.join(".ledger.jsonl");
+        let mut watcher = FolderWatcher::new(&service, "failing-board", &directory, Ledger::open(&ledger_path).unwrap(), options.clone());
+        watcher.scan().unwrap();
+        let start = Instant::now();
+        let events = watcher.scan().unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(60));
+        assert!(matches!(events[..], [WatchEvent::Failed(_, ref error)] if error.status == 500));
+        // Waits for the next attempt, which resumes the same board file.
+        assert!(watcher.scan().unwrap().is_empty());
+
+        let mut restarted = FolderWatcher::new(&service, "failing-board", &directory, Ledger::open(&ledger_path).unwrap(), options);
+        let entries = restarted.ledger.entries();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].upload.as_ref().unwrap().files[0].id, "retried-file");
+        restarted.scan().unwrap();
+        assert!(matches!(restarted.scan().unwrap()[..], [WatchEvent::Failed(_, ref error)] if error.status == 500));
+        add.assert();
+        url.assert();
+    }
+
+    #[test]
+    fn it_leaves_rejected_files_alone_until_they_change() {
+        let directory = directory("rejected");
+        fs::write(directory.join("select.jpg"), "hello").unwrap();
+        let add = mock("POST", "/rejecting-board/files").with_status(422).with_body(r#"{"message": "Invalid"}"#).expect(2).create();
+
+        let service = BoardService::new("jwt-token".into(), "1234".into());
+        let options = WatchOptions { settle_time: Duration::from_millis(0), retry_delay: Duration::from_millis(0), ..Default::default() };
+        let ledger = Ledger::open(directory.join(".ledger.jsonl")).unwrap();
+        let mut watcher = FolderWatcher::new(&service, "rejecting-board", &directory, ledger, options);
+        watcher.scan().unwrap();
+        assert!(matches!(watcher.scan().unwrap()[..], [WatchEvent::Failed(_, ref error)] if error.status == 422));
+        assert!(watcher.scan().unwrap().is_empty());
+        assert!(watcher.scan().unwrap().is_empty());
+        assert!(watcher.ledger.entries().is_empty());
+
+        fs::write(directory.join("select.jpg"), "hello again").unwrap();
+        watcher.scan().unwrap();
+        assert_eq!(watcher.scan().unwrap().len(), 1);
+        add.assert();
+    }
+}
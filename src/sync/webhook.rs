@@ -0,0 +1,440 @@
+//! Outgoing webhooks. Unless a chat format is chosen, every delivery is a
+//! `POST` of this JSON document:
+//!
+//! ```json
+//! {
+//!   "schema_version": 1,
+//!   "event": "transfer_finalized",
+//!   "sent_at": "2019-01-26T15:14:45Z",
+//!   "data": { ... }
+//! }
+//! ```
+//!
+//! where `data` is, depending on `event`:
+//!
+//! - `transfer_finalized`, `transfer_downloadable`: a `responses::Transfer`
+//! - `board_created`: a `responses::Board`
+//! - `board_files_added`: `{"board_id": "...", "files": [responses::FileBoard, ...]}`
+//! - `upload_failed`: `{"kind": "transfer" | "board", "id": "...", "error": responses::WeTransferError}`
+//!
+//! When the webhook has a secret, the `X-WeTransfer-Signature` header holds
+//! `sha256=` followed by the hex-encoded HMAC-SHA256 of the body.
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::mpsc::{self, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use reqwest;
+use reqwest::header::CONTENT_TYPE;
+use serde_json;
+use sha2::Sha256;
+
+use dedup::hex;
+use export::human_size;
+use responses::*;
+use secret::Secret;
+
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    TransferFinalized,
+    TransferDownloadable,
+    BoardCreated,
+    BoardFilesAdded,
+    UploadFailed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", content = "data", rename_all = "snake_case")]
+pub enum WebhookEvent {
+    TransferFinalized(Transfer),
+    TransferDownloadable(Transfer),
+    BoardCreated(Board),
+    BoardFilesAdded { board_id: String, files: Vec<FileBoard> },
+    /// `kind` is `transfer` or `board`, `id` the transfer or board id.
+    UploadFailed { kind: String, id: String, error: WeTransferError },
+}
+
+impl WebhookEvent {
+    pub fn kind(&self) -> EventKind {
+        match *self {
+            WebhookEvent::TransferFinalized(_) => EventKind::TransferFinalized,
+            WebhookEvent::TransferDownloadable(_) => EventKind::TransferDownloadable,
+            WebhookEvent::BoardCreated(_) => EventKind::BoardCreated,
+            WebhookEvent::BoardFilesAdded { .. } => EventKind::BoardFilesAdded,
+            WebhookEvent::UploadFailed { .. } => EventKind::UploadFailed,
+        }
+    }
+
+    /// The files added to a board, as listed in `progress`.
+    pub fn board_files_added(progress: &UploadProgress) -> WebhookEvent {
+        let files = progress.files.iter().map(|file| FileBoard {
+            id: file.id.clone(),
+            name: file.name.clone(),
+            size: file.size,
+            multipart: MultipartFileBoard {
+                id: file.multipart_id.clone().unwrap_or_default(),
+                part_numbers: file.part_numbers,
+                chunk_size: file.chunk_size,
+            },
+            kind: String::from("file"),
+        }).collect();
+        WebhookEvent::BoardFilesAdded { board_id: progress.upload_id.clone(), files }
+    }
+
+    // One line for chat messages, `link` rendering a link from its text and url.
+    fn summary<F: Fn(&str, &str) -> String>(&self, link: F) -> String {
+        let transfer_summary = |transfer: &Transfer| {
+            let size: i64 = transfer.files.iter().map(|file| file.size).sum();
+            let title = if transfer.message.is_empty() { transfer.id.as_str() } else { transfer.message.as_str() };
+            let title = match transfer.url {
+                Some(ref url) => link(title, url),
+                None => title.to_string(),
+            };
+            format!("{} ({} files, {})", title, transfer.files.len(), human_size(size as u64))
+        };
+        match *self {
+            WebhookEvent::TransferFinalized(ref transfer) => format!("Transfer sent: {}", transfer_summary(transfer)),
+            WebhookEvent::TransferDownloadable(ref transfer) => format!("Transfer ready to download: {}", transfer_summary(transfer)),
+            WebhookEvent::BoardCreated(ref board) => format!("Board created: {}", link(&board.name, &board.url)),
+            WebhookEvent::BoardFilesAdded { ref board_id, ref files } => {
+                let names: Vec<&str> = files.iter().map(|file| file.name.as_str()).collect();
+                format!("{} files added to board {}: {}", files.len(), board_id, names.join(", "))
+            },
+            WebhookEvent::UploadFailed { ref kind, ref id, ref error } => format!("Upload to {} {} failed: {}", kind, id, error.message),
+        }
+    }
+}
+
+/// The shape of the body sent to a webhook.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WebhookFormat {
+    /// The documented JSON schema, see the module documentation.
+    Json,
+    /// `{"text": "..."}` with `<url|text>` links, for Slack incoming webhooks.
+    Slack,
+    /// `{"text": "...", "username": "WeTransfer"}` with Markdown links, for Mattermost incoming webhooks.
+    Mattermost,
+}
+
+#[derive(Serialize)]
+struct Envelope<'a> {
+    schema_version: u32,
+    sent_at: DateTime<Utc>,
+    #[serde(flatten)]
+    event: &'a WebhookEvent,
+}
+
+#[derive(Serialize)]
+struct ChatMessage<'a> {
+    text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    username: Option<&'a str>,
+}
+
+impl WebhookFormat {
+    /// The body sent for `event`.
+    pub fn format(self, event: &WebhookEvent) -> String {
+        match self {
+            WebhookFormat::Json => serde_json::to_string(&Envelope { schema_version: SCHEMA_VERSION, sent_at: Utc::now(), event }).unwrap(),
+            WebhookFormat::Slack => serde_json::to_string(&ChatMessage {
+                text: event.summary(|text, url| format!("<{}|{}>", url, text)),
+                username: None,
+            }).unwrap(),
+            WebhookFormat::Mattermost => serde_json::to_string(&ChatMessage {
+                text: event.summary(|text, url| format!("[{}]({})", text, url)),
+                username: Some("WeTransfer"),
+            }).unwrap(),
+        }
+    }
+}
+
+/// An endpoint receiving events.
+#[derive(Debug, Clone)]
+pub struct Webhook {
+    pub url: String,
+    /// events sent to this webhook; every event when empty.
+    pub events: Vec<EventKind>,
+    pub format: WebhookFormat,
+    /// key of the `X-WeTransfer-Signature` HMAC.
+    pub secret: Option<Secret>,
+    /// How many times a failed delivery is tried again.
+    pub retries: u32,
+    /// Wait before the first retry, doubled after each one.
+    pub retry_delay: Duration,
+}
+
+impl Webhook {
+    /// A webhook receiving every event as JSON, unsigned, retried 3 times.
+    pub fn new<S: Into<String>>(url: S) -> Webhook {
+        Webhook {
+            url: url.into(),
+            events: Vec::new(),
+            format: WebhookFormat::Json,
+            secret: None,
+            retries: 3,
+            retry_delay: Duration::from_secs(1),
+        }
+    }
+
+    pub fn accepts(&self, kind: EventKind) -> bool {
+        self.events.is_empty() || self.events.contains(&kind)
+    }
+}
+
+/// Delivers events to a list of webhooks. `queue` hands events to a
+/// background thread, so the calls triggering them never wait for slow or
+/// failing webhooks; events still queued are delivered before the dispatcher is
+/// dropped.
+#[derive(Debug)]
+pub struct WebhookDispatcher {
+    deliveries: Arc<Deliveries>,
+    sender: Mutex<Option<Sender<WebhookEvent>>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+#[derive(Debug)]
+struct Deliveries {
+    webhooks: Vec<Webhook>,
+    http_client: reqwest::Client,
+    // Events queued but not delivered yet.
+    pending: Mutex<usize>,
+    idle: Condvar,
+}
+
+impl WebhookDispatcher {
+    /// Constructor.
+    pub fn new(webhooks: Vec<Webhook>) -> WebhookDispatcher {
+        let deliveries = Arc::new(Deliveries { webhooks, http_client: reqwest::Client::new(), pending: Mutex::new(0), idle: Condvar::new() });
+        let (sender, receiver) = mpsc::channel::<WebhookEvent>();
+        let worker = {
+            let deliveries = deliveries.clone();
+            thread::spawn(move || {
+                for event in receiver {
+                    // Best effort: nobody is left waiting for the outcome.
+                    let _ = deliveries.dispatch(&event);
+                    *deliveries.pending.lock().unwrap() -= 1;
+                    deliveries.idle.notify_all();
+                }
+            })
+        };
+        WebhookDispatcher { deliveries, sender: Mutex::new(Some(sender)), worker: Some(worker) }
+    }
+
+    /// Sends `event` to every webhook subscribed to it, returning the deliveries
+    /// which still failed after their retries.
+    pub fn dispatch(&self, event: &WebhookEvent) -> Vec<WeTransferError> {
+        self.deliveries.dispatch(event)
+    }
+
+    /// Delivers `event` in the background and returns at once.
+    pub fn queue(&self, event: WebhookEvent) {
+        if !self.deliveries.webhooks.iter().any(|webhook| webhook.accepts(event.kind())) {
+            return;
+        }
+        *self.deliveries.pending.lock().unwrap() += 1;
+        if let Some(ref sender) = *self.sender.lock().unwrap() {
+            if sender.send(event).is_ok() {
+                return;
+            }
+        }
+        *self.deliveries.pending.lock().unwrap() -= 1;
+    }
+
+    /// Waits until every queued event was delivered or gave up.
+    pub fn flush(&self) {
+        let mut pending = self.deliveries.pending.lock().unwrap();
+        while *pending > 0 {
+            pending = self.deliveries.idle.wait(pending).unwrap();
+        }
+    }
+}
+
+impl Drop for WebhookDispatcher {
+    fn drop(&mut self) {
+        self.sender.lock().unwrap().take();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Deliveries {
+    fn dispatch(&self, event: &WebhookEvent) -> Vec<WeTransferError> {
+        self.webhooks.iter()
+            .filter(|webhook| webhook.accepts(event.kind()))
+            .filter_map(|webhook| self.deliver(webhook, event).err())
+            .collect()
+    }
+
+    fn deliver(&self, webhook: &Webhook, event: &WebhookEvent) -> Result<(), WeTransferError> {
+        let body = webhook.format.format(event);
+        let mut delay = webhook.retry_delay;
+        let mut attempt = 0;
+        loop {
+            let mut request = self.http_client.post(webhook.url.as_str())
+                .header(CONTENT_TYPE, "application/json")
+                .header("X-WeTransfer-Event", serde_json::to_value(event.kind()).unwrap().as_str().unwrap_or_default())
+                .body(body.clone());
+            if let Some(ref secret) = webhook.secret {
+                request = request.header("X-WeTransfer-Signature", sign(secret, &body));
+            }
+            let error = match request.send() {
+                Ok(ref response) if response.status().is_success() => return Ok(()),
+                Ok(ref response) => {
+                    let status = response.status();
+                    let retryable = status.is_server_error() || status.as_u16() == 429;
+                    let error = WeTransferError {
+                        status: status.as_u16(),
+                        message: format!("Webhook {} answered {}", webhook.url, status),
+                        ..Default::default()
+                    };
+                    if !retryable {
+                        return Err(error);
+                    }
+                    error
+                },
+                Err(error) => WeTransferError {
+                    status: 0,
+                    message: format!("Webhook {} could not be reached: {}", webhook.url, error),
+                    ..Default::default()
+                },
+            };
+            if attempt >= webhook.retries {
+                return Err(error);
+            }
+            attempt += 1;
+            thread::sleep(delay);
+            delay *= 2;
+        }
+    }
+}
+
+/// `sha256=` followed by the hex-encoded HMAC-SHA256 of `body`, keyed with `secret`.
+pub fn sign(secret: &Secret, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.expose().as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body.as_bytes());
+    format!("sha256={}", hex(&mac.finalize().into_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::{mock, Matcher};
+    use std::fs;
+    use std::path::Path;
+    use std::sync::Arc;
+    use sync::board::BoardService;
+
+    fn transfer() -> Transfer {
+        Transfer {
+            id: String::from("hook-transfer"),
+            message: String::from("Renders"),
+            state: String::from("processing"),
+            url: Some(String::from("https://we.tl/t-hook")),
+            files: vec![File { name: String::from("render.mov"), size: 2048, ..Default::default() }],
+            ..Default::default()
+        }
+    }
+
+    fn webhook(path: &str) -> Webhook {
+        Webhook { retry_delay: Duration::from_millis(1), ..Webhook::new(format!("{}{}", mockito::SERVER_URL, path)) }
+    }
+
+    #[test]
+    fn it_signs_payloads() {
+        // RFC 4231, test case 2.
+        assert_eq!(sign(&Secret::new("Jefe"), "what do ya want for nothing?"),
+                   "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+    }
+
+    #[test]
+    fn it_formats_payloads() {
+        let event = WebhookEvent::TransferFinalized(transfer());
+        let json: serde_json::Value = serde_json::from_str(&WebhookFormat::Json.format(&event)).unwrap();
+        assert_eq!(json["schema_version"], 1);
+        assert_eq!(json["event"], "transfer_finalized");
+        assert_eq!(json["data"]["url"], "https://we.tl/t-hook");
+        assert!(json["sent_at"].as_str().unwrap().ends_with('Z'));
+
+        assert_eq!(WebhookFormat::Slack.format(&event), r#"{"text":"Transfer sent: <https://we.tl/t-hook|Renders> (1 files, 2.0 KB)"}"#);
+        assert_eq!(WebhookFormat::Mattermost.format(&event),
+                   r#"{"text":"Transfer sent: [Renders](https://we.tl/t-hook) (1 files, 2.0 KB)","username":"WeTransfer"}"#);
+
+        let progress = UploadProgress { upload_id: String::from("board-id"), files: vec![FileProgress { id: String::from("f"), name: String::from("a.png"), ..Default::default() }], ..Default::default() };
+        let added = WebhookEvent::board_files_added(&progress);
+        assert_eq!(added.kind(), EventKind::BoardFilesAdded);
+        assert_eq!(WebhookFormat::Slack.format(&added), r#"{"text":"1 files added to board board-id: a.png"}"#);
+    }
+
+    #[test]
+    fn it_delivers_signed_events_to_subscribed_webhooks() {
+        let delivered = mock("POST", "/hooks/tracker")
+          .match_header("x-wetransfer-event", "transfer_finalized")
+          .match_header("x-wetransfer-signature", Matcher::Regex(String::from("^sha256=[0-9a-f]{64}$")))
+          .match_body(Matcher::Regex(String::from(r#""event":"transfer_finalized""#)))
+          .with_status(204)
+          .expect(1)
+          .create();
+        let tracker = Webhook { secret: Some(Secret::new("s3cret")), ..webhook("/hooks/tracker") };
+        let boards_only = Webhook { events: vec![EventKind::BoardCreated], ..webhook("/hooks/boards") };
+
+        let dispatcher = WebhookDispatcher::new(vec![tracker, boards_only]);
+        assert!(dispatcher.dispatch(&WebhookEvent::TransferFinalized(transfer())).is_empty());
+        delivered.assert();
+    }
+
+    #[test]
+    fn it_retries_failed_deliveries() {
+        let failing = mock("POST", "/hooks/failing").with_status(503).expect(3).create();
+        let rejected = mock("POST", "/hooks/rejected").with_status(400).expect(1).create();
+        let hooks = vec![Webhook { retries: 2, ..webhook("/hooks/failing") }, webhook("/hooks/rejected")];
+
+        let errors = WebhookDispatcher::new(hooks).dispatch(&WebhookEvent::TransferDownloadable(transfer()));
+        let statuses: Vec<u16> = errors.iter().map(|error| error.status).collect();
+        assert_eq!(statuses, vec![503, 400]);
+        failing.assert();
+        rejected.assert();
+    }
+
+    #[test]
+    fn it_reports_created_boards() {
+        let body = fs::read_to_string(Path::new("src/support/create_board.json")).expect("Fixtures:");
+        let _create = mock("POST", "/").with_status(201).with_body(body).create();
+        let delivered = mock("POST", "/hooks/chat")
+          .match_body(Matcher::Regex(String::from(r#"^\{"text":"Board created: \[.+\]\(https://"#)))
+          .with_status(200)
+          .expect(1)
+          .create();
+
+        let mut service = BoardService::new("jwt-token".into(), "1234".into());
+        let chat = Webhook { format: WebhookFormat::Mattermost, events: vec![EventKind::BoardCreated], ..webhook("/hooks/chat") };
+        let dispatcher = Arc::new(WebhookDispatcher::new(vec![chat]));
+        service.set_webhooks(dispatcher.clone());
+        service.create("Hooks", None).unwrap();
+        dispatcher.flush();
+        delivered.assert();
+    }
+
+    #[test]
+    fn it_queues_deliveries_in_the_background() {
+        let failing = mock("POST", "/hooks/slow").with_status(503).expect(3).create();
+        let slow = Webhook { retries: 2, retry_delay: Duration::from_millis(200), ..webhook("/hooks/slow") };
+        let dispatcher = WebhookDispatcher::new(vec![slow]);
+
+        let started = ::std::time::Instant::now();
+        dispatcher.queue(WebhookEvent::TransferFinalized(transfer()));
+        assert!(started.elapsed() < Duration::from_millis(200));
+        dispatcher.flush();
+        assert!(started.elapsed() >= Duration::from_millis(600));
+        failing.assert();
+
+        dispatcher.queue(WebhookEvent::TransferFinalized(transfer()));
+        drop(dispatcher);
+        failing.expect(6).assert();
+    }
+}